@@ -1,344 +1,245 @@
-#[cfg(feature = "debug")]
-use solana_program::msg;
-
-use crate::TEN_THOUSAND;
-
 use super::{
-    number::{CheckedCeilDiv, U128},
+    error::CalculatorError,
+    number::{U128, U256},
+    rounding::{CheckedMulDiv, RoundDirection},
+    swap_context::SwapContext,
+    swap_curve::SwapCurve,
     swap_direction::SwapDirection,
-    taxation_mode::TaxationMode,
-    ConstantProduct, ProtocolSwapFeeDirection, RoundDirection, SwapInCalculationResult,
-    SwapOutCalculationResult, TokenSwapCalculator,
+    ConstantProduct, SwapInCalculationResult, SwapOutCalculationResult, TokenSwapCalculator,
 };
 
 use anyhow::Result;
 
-impl TokenSwapCalculator<ConstantProduct> {
-    pub fn swap_in(
-        amount_in: u64,
+impl SwapCurve for ConstantProduct {
+    fn swap_amount_out(
+        &self,
+        amount_in: U128,
+        base_reserve: U128,
+        quote_reserve: U128,
         swap_direction: &SwapDirection,
-        protocol_swap_fee_direction: &ProtocolSwapFeeDirection,
-        taxation_mode: &TaxationMode,
-        base_token_amount: u64,
-        quote_token_amount: u64,
-        swap_fee_numerator: u64,
-        swap_fee_denominator: u64,
-        protocol_swap_fee_numerator: u64,
-        protocol_swap_fee_denominator: u64,
-        buy_tax: u64,
-        sell_tax: u64,
-    ) -> Result<SwapInCalculationResult> {
-        let swap_amount_in_before_fees = U128::from(amount_in);
-
-        let swap_fee = swap_amount_in_before_fees
-            .checked_mul(swap_fee_numerator.into())
-            .ok_or_else(|| anyhow::anyhow!("Swap fee calculation overflow"))?
-            .checked_ceil_div(swap_fee_denominator.into())
-            .ok_or_else(|| anyhow::anyhow!("Swap fee calculation overflow"))?
-            .0;
-        let mut swap_tax_on_input_amount = U128::zero();
-        let mut swap_tax_on_output_amount = U128::zero();
-        let mut protocol_swap_fee_on_input_amount = U128::zero();
-        let mut protocol_swap_fee_on_output_amount = U128::zero();
-
-        match (taxation_mode, swap_direction) {
-            // If `Base` is the stable/native coin and `Quote` is the custom coin, 
-            // then swapping base for quote is considered buying
-            (TaxationMode::Base, SwapDirection::Base2Quote)
-            // If `Quote` is the stable/native coin and `Base` is the custom coin, 
-            // then swapping `Quote` for `Base` is considered buying
-            | (TaxationMode::Quote, SwapDirection::Quote2Base) => {
-                swap_tax_on_input_amount = swap_amount_in_before_fees
-                    .checked_mul(buy_tax.into())
-                    .ok_or_else(|| anyhow::anyhow!("Swap tax calculation overflow"))?
-                    .checked_ceil_div(TEN_THOUSAND.into())
-                    .ok_or_else(|| anyhow::anyhow!("Swap tax calculation overflow"))?
-                    .0;
-            }
-            _ => {}
-        };
-
-        match (protocol_swap_fee_direction, swap_direction) {
-            (ProtocolSwapFeeDirection::Base, SwapDirection::Base2Quote)
-            | (ProtocolSwapFeeDirection::Quote, SwapDirection::Quote2Base) => {
-                protocol_swap_fee_on_input_amount = swap_amount_in_before_fees
-                    .checked_mul(protocol_swap_fee_numerator.into())
-                    .ok_or_else(|| anyhow::anyhow!("Protocol swap fee calculation overflow"))?
-                    .checked_ceil_div(protocol_swap_fee_denominator.into())
-                    .ok_or_else(|| anyhow::anyhow!("Protocol swap fee calculation overflow"))?
-                    .0;
-            }
-            _ => {}
+    ) -> Result<U128> {
+        let (source_reserve, destination_reserve) = match swap_direction {
+            SwapDirection::Base2Quote => (base_reserve, quote_reserve),
+            SwapDirection::Quote2Base => (quote_reserve, base_reserve),
         };
-
-        let swap_amount_in_after_fees = swap_amount_in_before_fees
-            .checked_sub(swap_fee)
-            .ok_or_else(|| anyhow::anyhow!("Swap fee deduction overflow"))?
-            .checked_sub(swap_tax_on_input_amount)
-            .ok_or_else(|| anyhow::anyhow!("Swap tax deduction overflow"))?
-            .checked_sub(protocol_swap_fee_on_input_amount)
-            .ok_or_else(|| anyhow::anyhow!("Protocol swap fee deduction overflow"))?;
-
-        let swap_amount_out_before_fees = match swap_direction {
-            SwapDirection::Base2Quote => {
-                let denominator = U128::from(base_token_amount)
-                    .checked_add(swap_amount_in_after_fees)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount out calculation overflow"))?;
-                U128::from(quote_token_amount)
-                    .checked_mul(swap_amount_in_after_fees)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount out calculation overflow"))?
-                    .checked_div(denominator)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount out calculation overflow"))?
-            }
-            SwapDirection::Quote2Base => {
-                let denominator = U128::from(quote_token_amount)
-                    .checked_add(swap_amount_in_after_fees)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount out calculation overflow"))?;
-                U128::from(base_token_amount)
-                    .checked_mul(swap_amount_in_after_fees)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount out calculation overflow"))?
-                    .checked_div(denominator)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount out calculation overflow"))?
-            }
-        };
-
-        match (taxation_mode, swap_direction) {
-            // If `Base` is the stable/native coin and `Quote` is the custom coin, 
-            // then swapping `Quote` for `Base` is considered selling
-            (TaxationMode::Base, SwapDirection::Quote2Base)
-            // If `Quote` is the stable/native coin and `Base` is the custom coin, 
-            // then swapping `Base` for `Quote` is considered selling
-            | (TaxationMode::Quote, SwapDirection::Base2Quote) => {
-                swap_tax_on_output_amount = swap_amount_out_before_fees
-                    .checked_mul(sell_tax.into())
-                    .ok_or_else(|| anyhow::anyhow!("Swap tax calculation overflow"))?
-                    .checked_ceil_div(TEN_THOUSAND.into())
-                    .ok_or_else(|| anyhow::anyhow!("Swap tax calculation overflow"))?
-                    .0;
-            }
-            _ => {}
-        };
-
-        match (protocol_swap_fee_direction, swap_direction) {
-            (ProtocolSwapFeeDirection::Base, SwapDirection::Quote2Base)
-            | (ProtocolSwapFeeDirection::Quote, SwapDirection::Base2Quote) => {
-                protocol_swap_fee_on_output_amount = swap_amount_out_before_fees
-                    .checked_mul(protocol_swap_fee_numerator.into())
-                    .ok_or_else(|| anyhow::anyhow!("Protocol swap fee calculation overflow"))?
-                    .checked_ceil_div(protocol_swap_fee_denominator.into())
-                    .ok_or_else(|| anyhow::anyhow!("Protocol swap fee calculation overflow"))?
-                    .0;
-            }
-            _ => {}
-        };
-
-        let swap_amount_out_after_fees = swap_amount_out_before_fees
-            .checked_sub(swap_tax_on_output_amount)
-            .ok_or_else(|| anyhow::anyhow!("Swap tax deduction overflow"))?
-            .checked_sub(protocol_swap_fee_on_output_amount)
-            .ok_or_else(|| anyhow::anyhow!("Protocol swap fee deduction overflow"))?;
-
-        Ok(SwapInCalculationResult {
-            swap_amount_in_before_fees,
-            swap_amount_in_after_fees,
-            swap_amount_out_after_fees,
-            swap_amount_out_before_fees,
-            swap_fee,
-            swap_tax_on_input_amount,
-            swap_tax_on_output_amount,
-            protocol_swap_fee_on_input_amount,
-            protocol_swap_fee_on_output_amount,
-        })
+        let denominator = source_reserve
+            .checked_add(amount_in)
+            .ok_or(CalculatorError::Overflow)?;
+        // Rounded down, in the pool's favor
+        Ok(destination_reserve
+            .checked_mul_div(amount_in, denominator, RoundDirection::Floor)
+            .ok_or(CalculatorError::Overflow)?)
     }
 
-    pub fn swap_out(
-        amount_out: u64,
+    fn swap_amount_in(
+        &self,
+        amount_out: U128,
+        base_reserve: U128,
+        quote_reserve: U128,
         swap_direction: &SwapDirection,
-        external_fee_direction: &ProtocolSwapFeeDirection,
-        taxation_mode: &TaxationMode,
-        base_token_amount: u64,
-        quote_token_amount: u64,
-        swap_fee_numerator: u64,
-        swap_fee_denominator: u64,
-        protocol_swap_fee_numerator: u64,
-        protocol_swap_fee_denominator: u64,
-        buy_tax: u64,
-        sell_tax: u64,
-    ) -> Result<SwapOutCalculationResult> {
-        let swap_amount_out_before_fees = U128::from(amount_out);
-        let total_base_token_amount = U128::from(base_token_amount);
-        let total_quote_token_amount = U128::from(quote_token_amount);
-
-        #[cfg(feature = "debug")]
-        msg!(
-            "swap_amount_out_before_fees: {}, total_base_token_amount: {}, total_quote_token_amount: {}",
-            swap_amount_out_before_fees,
-            total_base_token_amount,
-            total_quote_token_amount
-        );
-
-        let protocol_swap_fee_on_output_amount;
-
-        // Base on the directions apply external fee on the amount out
-        match (external_fee_direction, swap_direction) {
-            (ProtocolSwapFeeDirection::Quote, SwapDirection::Base2Quote)
-            | (ProtocolSwapFeeDirection::Base, SwapDirection::Quote2Base) => {
-                protocol_swap_fee_on_output_amount = swap_amount_out_before_fees
-                    .checked_mul(protocol_swap_fee_numerator.into())
-                    .ok_or_else(|| anyhow::anyhow!("Protocol swap fee calculation overflow"))?
-                    .checked_ceil_div(protocol_swap_fee_denominator.into())
-                    .ok_or_else(|| anyhow::anyhow!("Protocol swap fee calculation overflow"))?
-                    .0;
-            }
-            _ => {
-                protocol_swap_fee_on_output_amount = U128::zero();
-            }
+    ) -> Result<U128> {
+        let (source_reserve, destination_reserve) = match swap_direction {
+            SwapDirection::Base2Quote => (base_reserve, quote_reserve),
+            SwapDirection::Quote2Base => (quote_reserve, base_reserve),
+        };
+        // The pool can never be drained of its last destination token
+        if amount_out >= destination_reserve {
+            return Err(CalculatorError::InsufficientLiquidity.into());
         }
+        let denominator = destination_reserve - amount_out;
+        // Rounded up, in the pool's favor
+        Ok(source_reserve
+            .checked_mul_div(amount_out, denominator, RoundDirection::Ceiling)
+            .ok_or(CalculatorError::Overflow)?)
+    }
 
-        #[cfg(feature = "debug")]
-        msg!(
-            "protocol_swap_fee_on_output_amount: {}",
-            protocol_swap_fee_on_output_amount
-        );
-
-        let swap_tax_on_output_amount;
-        match (taxation_mode, swap_direction) {
-            // If `Base` is the stable/native coin and `Quote` is the custom coin,
-            // then swapping `Quote` for `Base` is considered selling
-            (TaxationMode::Base, SwapDirection::Quote2Base)
-            // If `Quote` is the stable/native coin and `Base` is the custom coin,
-            // then swapping `Base` for `Quote` is considered selling
-            | (TaxationMode::Quote, SwapDirection::Base2Quote) => {
-                swap_tax_on_output_amount = swap_amount_out_before_fees
-                    .checked_mul(sell_tax.into())
-                    .ok_or_else(|| anyhow::anyhow!("Swap tax calculation overflow"))?
-                    .checked_ceil_div(TEN_THOUSAND.into())
-                    .ok_or_else(|| anyhow::anyhow!("Swap tax calculation overflow"))?
-                    .0;
-            }
-            _ => {
-                swap_tax_on_output_amount = U128::zero();
-            }
+    fn spot_price(&self, base_reserve: U128, quote_reserve: U128) -> Result<f64> {
+        if base_reserve.is_zero() {
+            return Err(CalculatorError::InsufficientLiquidity.into());
         }
+        Ok(quote_reserve.as_u128() as f64 / base_reserve.as_u128() as f64)
+    }
 
-        #[cfg(feature = "debug")]
-        msg!("swap_tax_on_output_amount: {}", swap_tax_on_output_amount);
+    fn invariant(&self, base_reserve: U128, quote_reserve: U128) -> Result<U256> {
+        U256::from(base_reserve.as_u128())
+            .checked_mul(U256::from(quote_reserve.as_u128()))
+            .ok_or_else(|| CalculatorError::Overflow.into())
+    }
+}
 
-        let swap_amount_out_after_fees = swap_amount_out_before_fees
-            .checked_add(protocol_swap_fee_on_output_amount)
-            .ok_or_else(|| anyhow::anyhow!("Protocol swap fee addition overflow"))?
-            .checked_add(swap_tax_on_output_amount)
-            .ok_or_else(|| anyhow::anyhow!("Swap tax addition overflow"))?;
+impl TokenSwapCalculator<ConstantProduct> {
+    pub fn swap_in(amount_in: u64, context: &SwapContext) -> Result<SwapInCalculationResult> {
+        Self::swap_in_with_curve(&ConstantProduct, amount_in, context)
+    }
 
-        #[cfg(feature = "debug")]
-        msg!("swap_amount_out_after_fees: {}", swap_amount_out_after_fees);
+    pub fn swap_out(amount_out: u64, context: &SwapContext) -> Result<SwapOutCalculationResult> {
+        Self::swap_out_with_curve(&ConstantProduct, amount_out, context)
+    }
+}
 
-        let swap_amount_in_before_fees;
-        match swap_direction {
-            SwapDirection::Base2Quote => {
-                let denominator = total_quote_token_amount
-                    .checked_sub(swap_amount_out_after_fees)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount in calculation overflow"))?;
-                swap_amount_in_before_fees = total_base_token_amount
-                    .checked_mul(swap_amount_out_after_fees)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount in calculation overflow"))?
-                    .checked_ceil_div(denominator)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount in calculation overflow"))?
-                    .0;
-            }
-            SwapDirection::Quote2Base => {
-                let denominator = total_base_token_amount
-                    .checked_sub(swap_amount_out_after_fees)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount in calculation overflow"))?;
-                swap_amount_in_before_fees = total_quote_token_amount
-                    .checked_mul(swap_amount_out_after_fees)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount in calculation overflow"))?
-                    .checked_ceil_div(denominator)
-                    .ok_or_else(|| anyhow::anyhow!("Swap amount in calculation overflow"))?
-                    .0;
-            }
-        };
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calculator::{
+        fee_mode::{FeeMode, FeeTier, FeeTiers},
+        swap_context::{FeeSchedule, Reserves, TaxSchedule},
+        taxation_mode::TaxationMode,
+        ProtocolSwapFeeDirection,
+    };
+
+    fn context() -> SwapContext {
+        SwapContext {
+            swap_direction: SwapDirection::Quote2Base,
+            protocol_swap_fee_direction: ProtocolSwapFeeDirection::None,
+            taxation_mode: TaxationMode::None,
+            reserves: Reserves::new(1_000_000_000, 100_000_000),
+            fee_schedule: FeeSchedule::new(25, 10_000, 0, 1).unwrap(),
+            tax_schedule: TaxSchedule::default(),
+            ..Default::default()
+        }
+    }
 
-        #[cfg(feature = "debug")]
-        msg!("swap_amount_in_before_fees: {}", swap_amount_in_before_fees);
+    #[test]
+    fn test_swap_in_matches_constant_product_formula() {
+        let result =
+            TokenSwapCalculator::<ConstantProduct>::swap_in(1_000_000, &context()).unwrap();
 
-        let swap_fee = swap_amount_in_before_fees
-            .checked_mul(swap_fee_numerator.into())
-            .ok_or_else(|| anyhow::anyhow!("Swap fee calculation overflow"))?
-            .checked_ceil_div(swap_fee_denominator.into())
-            .ok_or_else(|| anyhow::anyhow!("Swap fee calculation overflow"))?
-            .0;
+        // 1_000_000 - 0.25% swap fee
+        assert_eq!(result.swap_amount_in_after_fees, U128::from(997_500));
+        // 1_000_000_000 * 997_500 / (100_000_000 + 997_500), rounded down
+        assert_eq!(result.swap_amount_out_before_fees, U128::from(9_876_482));
+        assert_eq!(result.swap_amount_out_after_fees, U128::from(9_876_482));
+    }
 
-        #[cfg(feature = "debug")]
-        msg!("swap_fee: {}", swap_fee);
+    #[test]
+    fn test_swap_out_does_not_decrease_invariant() {
+        let result =
+            TokenSwapCalculator::<ConstantProduct>::swap_out(9_876_482, &context()).unwrap();
+
+        ConstantProduct
+            .check_invariant(
+                U128::from(1_000_000_000u64),
+                U128::from(100_000_000u64),
+                U128::from(1_000_000_000u64 - 9_876_482),
+                U128::from(100_000_000u64) + result.swap_amount_in_before_fees,
+            )
+            .unwrap();
+        assert!(result.swap_amount_in_after_fees > result.swap_amount_in_before_fees);
+    }
 
-        let protocol_swap_fee_on_input_amount;
-        // Base on the directions apply external fee on the amount in
-        match (external_fee_direction, swap_direction) {
-            (ProtocolSwapFeeDirection::Base, SwapDirection::Base2Quote)
-            | (ProtocolSwapFeeDirection::Quote, SwapDirection::Quote2Base) => {
-                protocol_swap_fee_on_input_amount = swap_amount_in_before_fees
-                    .checked_mul(protocol_swap_fee_numerator.into())
-                    .ok_or_else(|| anyhow::anyhow!("Protocol swap fee calculation overflow"))?
-                    .checked_ceil_div(protocol_swap_fee_denominator.into())
-                    .ok_or_else(|| anyhow::anyhow!("Protocol swap fee calculation overflow"))?
-                    .0;
-            }
-            _ => {
-                protocol_swap_fee_on_input_amount = U128::zero();
-            }
-        }
+    #[test]
+    fn test_errors_are_typed() {
+        let error = TokenSwapCalculator::<ConstantProduct>::swap_out(1_000_000_000, &context())
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CalculatorError>(),
+            Some(&CalculatorError::InsufficientLiquidity)
+        );
 
-        #[cfg(feature = "debug")]
-        msg!(
-            "protocol_swap_fee_on_input_amount: {}",
-            protocol_swap_fee_on_input_amount
+        let sell_context = SwapContext {
+            swap_direction: SwapDirection::Base2Quote,
+            ..context()
+        };
+        let error = TokenSwapCalculator::<ConstantProduct>::swap_in(1, &sell_context).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CalculatorError>(),
+            Some(&CalculatorError::ZeroOutput)
         );
 
-        let swap_tax_on_input_amount;
-        match (taxation_mode, swap_direction) {
-            // If `Quote` is the stable/native coin and `Base` is the custom coin,
-            // then swapping `Quote` for `Base` is considered buying
-            (TaxationMode::Quote, SwapDirection::Quote2Base)
-            // If `Base` is the stable/native coin and `Quote` is the custom coin,
-            // then swapping `Base` for `Quote` is considered buying
-            | (TaxationMode::Base, SwapDirection::Base2Quote) => {
-                swap_tax_on_input_amount = swap_amount_in_before_fees
-                    .checked_mul(buy_tax.into())
-                    .ok_or_else(|| anyhow::anyhow!("Swap tax calculation overflow"))?
-                    .checked_ceil_div(TEN_THOUSAND.into())
-                    .ok_or_else(|| anyhow::anyhow!("Swap tax calculation overflow"))?
-                    .0;
-            }
-            _ => {
-                swap_tax_on_input_amount = U128::zero();
-            }
-        }
+        let invalid_context = SwapContext {
+            fee_schedule: FeeSchedule {
+                swap_fee_denominator: 0,
+                ..context().fee_schedule
+            },
+            ..context()
+        };
+        let error = TokenSwapCalculator::<ConstantProduct>::swap_in(1_000_000, &invalid_context)
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CalculatorError>(),
+            Some(&CalculatorError::InvalidFeeConfig)
+        );
+    }
 
-        #[cfg(feature = "debug")]
-        msg!("swap_tax_on_input_amount: {}", swap_tax_on_input_amount);
+    #[test]
+    fn test_protocol_tax_is_a_cut_of_swap_tax() {
+        let taxed_context = SwapContext {
+            taxation_mode: TaxationMode::Quote,
+            tax_schedule: TaxSchedule::new(300, 500)
+                .unwrap()
+                .with_protocol_tax(1, 10)
+                .unwrap(),
+            ..context()
+        };
 
-        let swap_amount_in_after_fees = swap_amount_in_before_fees
-            .checked_add(protocol_swap_fee_on_input_amount)
-            .ok_or_else(|| anyhow::anyhow!("Protocol swap fee addition overflow"))?
-            .checked_add(swap_tax_on_input_amount)
-            .ok_or_else(|| anyhow::anyhow!("Swap tax addition overflow"))?
-            .checked_add(swap_fee)
-            .ok_or_else(|| anyhow::anyhow!("Swap fee addition overflow"))?;
+        // Buying: the tax is taken from the quote input, the protocol's cut
+        // out of it
+        let result =
+            TokenSwapCalculator::<ConstantProduct>::swap_in(1_000_000, &taxed_context).unwrap();
+        assert_eq!(result.swap_tax_on_input_amount, U128::from(30_000));
+        assert_eq!(result.protocol_tax_on_input_amount, U128::from(3_000));
+        assert_eq!(
+            result.swap_amount_in_after_fees,
+            U128::from(1_000_000 - 2_500 - 30_000)
+        );
 
-        #[cfg(feature = "debug")]
-        msg!("swap_amount_in_after_fees: {}", swap_amount_in_after_fees);
+        // Selling: the tax is taken from the quote output
+        let sell_context = SwapContext {
+            swap_direction: SwapDirection::Base2Quote,
+            ..taxed_context
+        };
+        let result =
+            TokenSwapCalculator::<ConstantProduct>::swap_out(1_000_000, &sell_context).unwrap();
+        assert_eq!(result.swap_tax_on_output_amount, U128::from(50_000));
+        assert_eq!(result.protocol_tax_on_output_amount, U128::from(5_000));
+        assert_eq!(
+            result.swap_amount_out_after_fees,
+            U128::from(1_000_000 + 50_000)
+        );
+    }
 
-        Ok(SwapOutCalculationResult {
-            swap_amount_in_after_fees,
-            swap_amount_out_after_fees,
-            swap_amount_in_before_fees,
-            swap_amount_out_before_fees,
-            swap_fee,
-            swap_tax_on_input_amount,
-            swap_tax_on_output_amount,
-            protocol_swap_fee_on_input_amount,
-            protocol_swap_fee_on_output_amount,
-        })
+    #[test]
+    fn test_swap_out_across_a_tier_boundary_charges_the_largest_input() {
+        let tiered_context = |below: u64, above: u64| SwapContext {
+            reserves: Reserves::new(1_000_000_000, 1_000_000_000),
+            fee_schedule: FeeSchedule::new(100, 10_000, 0, 1).unwrap(),
+            fee_mode: FeeMode::Tiered(
+                FeeTiers::new(&[
+                    FeeTier {
+                        minimum_amount_in: 0,
+                        swap_fee_numerator: below,
+                    },
+                    FeeTier {
+                        minimum_amount_in: 1_000_000,
+                        swap_fee_numerator: above,
+                    },
+                ])
+                .unwrap(),
+            ),
+            ..context()
+        };
+        let static_amount_in = |swap_fee_numerator| {
+            let context = SwapContext {
+                fee_schedule: FeeSchedule::new(swap_fee_numerator, 10_000, 0, 1).unwrap(),
+                ..tiered_context(swap_fee_numerator, swap_fee_numerator)
+            };
+            TokenSwapCalculator::<ConstantProduct>::swap_out(995_000, &context)
+                .unwrap()
+                .swap_amount_in_after_fees
+        };
+        // At a 1% fee the input crosses the boundary, at 0.1% it falls short
+        assert!(static_amount_in(100) >= U128::from(1_000_000));
+        assert!(static_amount_in(10) < U128::from(1_000_000));
+
+        // Cheaper above the boundary: neither tier applies to its own input
+        let result =
+            TokenSwapCalculator::<ConstantProduct>::swap_out(995_000, &tiered_context(100, 10))
+                .unwrap();
+        assert_eq!(result.swap_amount_in_after_fees, static_amount_in(100));
+        // Dearer above the boundary: both tiers apply to their own input
+        let result =
+            TokenSwapCalculator::<ConstantProduct>::swap_out(995_000, &tiered_context(10, 100))
+                .unwrap();
+        assert_eq!(result.swap_amount_in_after_fees, static_amount_in(100));
     }
 }
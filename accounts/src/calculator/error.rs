@@ -0,0 +1,114 @@
+use std::fmt;
+
+use crate::utils::error::AmmErrorCode;
+
+/// Failures of the swap calculator.
+///
+/// Calculator functions return `anyhow::Result`; callers that need to tell
+/// these cases apart can use `anyhow::Error::downcast_ref::<CalculatorError>()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CalculatorError {
+    /// The pool reserves cannot cover the requested swap
+    InsufficientLiquidity,
+    /// The swap would pay out nothing once fees and taxes are taken
+    ZeroOutput,
+    /// Fees and taxes take more than the amount they are charged on
+    FeeExceedsInput,
+    /// Zero fee denominator or fees of 100% and more
+    InvalidFeeConfig,
+    /// Swap tax above 100%, or a tax decay without a duration
+    InvalidTaxConfig,
+    /// Decaying taxes that were not resolved at a time with `SwapContext::at`
+    UnresolvedTaxDecay,
+    /// Fee tiers out of order or an adaptive fee without a sensitivity
+    InvalidFeeMode,
+    /// Token-2022 transfer fee above the maximum basis points
+    InvalidTransferFeeConfig,
+    /// Curve parameters the curve cannot price with
+    InvalidCurveConfig,
+    /// An iterative curve solver did not converge
+    DidNotConverge,
+    /// A swap would decrease the curve invariant
+    InvariantViolation,
+    /// Arithmetic overflow or underflow
+    Overflow,
+}
+
+impl CalculatorError {
+    /// Whether the error only means that the pool cannot fill this swap,
+    /// as opposed to a broken pool configuration or a bug.
+    pub fn is_liquidity_error(&self) -> bool {
+        matches!(
+            self,
+            CalculatorError::InsufficientLiquidity | CalculatorError::ZeroOutput
+        )
+    }
+}
+
+impl fmt::Display for CalculatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            CalculatorError::InsufficientLiquidity => "Insufficient liquidity for swap",
+            CalculatorError::ZeroOutput => "Swap amount out is zero",
+            CalculatorError::FeeExceedsInput => "Swap fees exceed the swapped amount",
+            CalculatorError::InvalidFeeConfig => "Invalid swap fee configuration",
+            CalculatorError::InvalidTaxConfig => "Invalid swap tax configuration",
+            CalculatorError::UnresolvedTaxDecay => "Swap taxes were not resolved at a time",
+            CalculatorError::InvalidFeeMode => "Invalid fee mode",
+            CalculatorError::InvalidTransferFeeConfig => "Invalid transfer fee configuration",
+            CalculatorError::InvalidCurveConfig => "Invalid curve configuration",
+            CalculatorError::DidNotConverge => "Curve calculation did not converge",
+            CalculatorError::InvariantViolation => "Swap decreases the curve invariant",
+            CalculatorError::Overflow => "Swap calculation overflow",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for CalculatorError {}
+
+impl TryFrom<CalculatorError> for AmmErrorCode {
+    type Error = CalculatorError;
+
+    fn try_from(error: CalculatorError) -> Result<Self, Self::Error> {
+        match error {
+            CalculatorError::InsufficientLiquidity => Ok(AmmErrorCode::InsufficientBalance),
+            CalculatorError::ZeroOutput => Ok(AmmErrorCode::ZeroAmount),
+            CalculatorError::FeeExceedsInput => Ok(AmmErrorCode::InvalidPostFeeAmount),
+            CalculatorError::InvalidTaxConfig => Ok(AmmErrorCode::InvalidSwapTax),
+            CalculatorError::InvalidFeeMode => Ok(AmmErrorCode::InvalidFeeMode),
+            _ => Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_survives_anyhow() {
+        let error: anyhow::Error = CalculatorError::InsufficientLiquidity.into();
+
+        assert_eq!(
+            error.downcast_ref::<CalculatorError>(),
+            Some(&CalculatorError::InsufficientLiquidity)
+        );
+        assert!(error
+            .downcast_ref::<CalculatorError>()
+            .unwrap()
+            .is_liquidity_error());
+    }
+
+    #[test]
+    fn test_amm_error_code_conversion() {
+        assert!(matches!(
+            AmmErrorCode::try_from(CalculatorError::ZeroOutput),
+            Ok(AmmErrorCode::ZeroAmount)
+        ));
+        assert!(matches!(
+            AmmErrorCode::try_from(CalculatorError::Overflow),
+            Err(CalculatorError::Overflow)
+        ));
+    }
+}
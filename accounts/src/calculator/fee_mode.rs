@@ -0,0 +1,342 @@
+//! How the LP swap fee is set: a static fee, a fee by swap size, or a fee
+//! that rises with recent price volatility.
+//!
+//! Every mode resolves to a [`FeeSchedule`] for a given swap. The protocol
+//! swap fee is left as is, only the LP fee changes.
+
+use std::{collections::VecDeque, ops::RangeInclusive};
+
+use anyhow::Result;
+
+use super::{
+    error::CalculatorError,
+    number::{CheckedAsU64, U128},
+    rounding::{CheckedMulDiv, RoundDirection},
+    swap_context::FeeSchedule,
+};
+use crate::{instructions::LiquidityPoolState, TEN_THOUSAND};
+
+/// Most tiers a [`FeeTiers`] holds, so that fee modes stay `Copy`
+pub const MAX_FEE_TIERS: usize = 8;
+
+/// LP swap fee for swaps of at least `minimum_amount_in`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeeTier {
+    pub minimum_amount_in: u64,
+    /// Over the pool's `swap_fee_denominator`
+    pub swap_fee_numerator: u64,
+}
+
+/// Fee tiers by increasing input amount, the first one starting at zero
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeeTiers {
+    tiers: [FeeTier; MAX_FEE_TIERS],
+    len: usize,
+}
+
+impl FeeTiers {
+    pub fn new(tiers: &[FeeTier]) -> Result<Self> {
+        let starts_at_zero = tiers
+            .first()
+            .is_some_and(|tier| tier.minimum_amount_in == 0);
+        let is_increasing = tiers
+            .windows(2)
+            .all(|pair| pair[0].minimum_amount_in < pair[1].minimum_amount_in);
+        if tiers.len() > MAX_FEE_TIERS || !starts_at_zero || !is_increasing {
+            return Err(CalculatorError::InvalidFeeMode.into());
+        }
+        let mut fee_tiers = Self {
+            len: tiers.len(),
+            ..Default::default()
+        };
+        fee_tiers.tiers[..tiers.len()].copy_from_slice(tiers);
+        Ok(fee_tiers)
+    }
+
+    pub fn as_slice(&self) -> &[FeeTier] {
+        &self.tiers[..self.len]
+    }
+
+    /// Tiers with the input amounts each one applies to
+    fn ranges(&self) -> impl Iterator<Item = (RangeInclusive<u64>, &FeeTier)> {
+        let tiers = self.as_slice();
+        tiers.iter().enumerate().map(|(index, tier)| {
+            let end = tiers
+                .get(index + 1)
+                .map_or(u64::MAX, |next| next.minimum_amount_in - 1);
+            (tier.minimum_amount_in..=end, tier)
+        })
+    }
+}
+
+/// LP fee raised in proportion to the recent price volatility
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AdaptiveFee {
+    /// Fee added per unit of volatility: `1 / 10` adds 10 basis points of
+    /// fee for 100 basis points of volatility
+    pub sensitivity_numerator: u64,
+    pub sensitivity_denominator: u64,
+    /// Cap on the raised LP fee, over the pool's `swap_fee_denominator`
+    pub max_swap_fee_numerator: u64,
+    /// Recent volatility, in basis points, e.g. from a [`VolatilityTracker`]
+    pub volatility_bps: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FeeMode {
+    /// The pool's `swap_fee_numerator / swap_fee_denominator`
+    #[default]
+    Static,
+    /// Fee of the tier the input amount falls in
+    Tiered(FeeTiers),
+    /// Pool fee plus a volatility premium, up to a cap
+    Adaptive(AdaptiveFee),
+}
+
+impl FeeMode {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            FeeMode::Static | FeeMode::Tiered(_) => Ok(()),
+            FeeMode::Adaptive(adaptive_fee) => {
+                if adaptive_fee.sensitivity_denominator == 0 {
+                    return Err(CalculatorError::InvalidFeeMode.into());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The same mode with the volatility of an adaptive fee set to
+    /// `volatility_bps`. Other modes do not depend on volatility.
+    pub fn with_volatility(self, volatility_bps: u64) -> Self {
+        match self {
+            FeeMode::Adaptive(adaptive_fee) => FeeMode::Adaptive(AdaptiveFee {
+                volatility_bps,
+                ..adaptive_fee
+            }),
+            fee_mode => fee_mode,
+        }
+    }
+
+    /// Every fee schedule the mode can charge on top of `fee_schedule`, with
+    /// the input amounts, before fees, each one applies to.
+    pub fn fee_schedules(
+        &self,
+        fee_schedule: &FeeSchedule,
+    ) -> Result<Vec<(RangeInclusive<u64>, FeeSchedule)>> {
+        self.validate()?;
+        let with_swap_fee = |swap_fee_numerator: u64| {
+            FeeSchedule::new(
+                swap_fee_numerator,
+                fee_schedule.swap_fee_denominator,
+                fee_schedule.protocol_swap_fee_numerator,
+                fee_schedule.protocol_swap_fee_denominator,
+            )
+        };
+        match self {
+            FeeMode::Static => Ok(vec![(0..=u64::MAX, *fee_schedule)]),
+            FeeMode::Tiered(fee_tiers) => fee_tiers
+                .ranges()
+                .map(|(amounts_in, tier)| Ok((amounts_in, with_swap_fee(tier.swap_fee_numerator)?)))
+                .collect(),
+            FeeMode::Adaptive(adaptive_fee) => {
+                // volatility * sensitivity, from basis points to the fee
+                // denominator, rounded up in the pool's favor
+                let premium = U128::from(adaptive_fee.volatility_bps)
+                    .checked_mul(adaptive_fee.sensitivity_numerator.into())
+                    .and_then(|premium| {
+                        premium.checked_mul_div(
+                            fee_schedule.swap_fee_denominator.into(),
+                            U128::from(TEN_THOUSAND)
+                                .checked_mul(adaptive_fee.sensitivity_denominator.into())?,
+                            RoundDirection::Ceiling,
+                        )
+                    })
+                    .ok_or(CalculatorError::Overflow)?;
+                let swap_fee_numerator = U128::from(fee_schedule.swap_fee_numerator)
+                    .checked_add(premium)
+                    .ok_or(CalculatorError::Overflow)?
+                    .min(adaptive_fee.max_swap_fee_numerator.into())
+                    .max(fee_schedule.swap_fee_numerator.into())
+                    .checked_as_u64()
+                    .ok_or(CalculatorError::Overflow)?;
+                Ok(vec![(0..=u64::MAX, with_swap_fee(swap_fee_numerator)?)])
+            }
+        }
+    }
+
+    /// Fee schedule charged on a swap of `amount_in`, before fees
+    pub fn fee_schedule(&self, fee_schedule: &FeeSchedule, amount_in: u64) -> Result<FeeSchedule> {
+        self.fee_schedules(fee_schedule)?
+            .into_iter()
+            .find(|(amounts_in, _)| amounts_in.contains(&amount_in))
+            .map(|(_, fee_schedule)| fee_schedule)
+            .ok_or(CalculatorError::InvalidFeeMode.into())
+    }
+}
+
+/// Rolling window of the spot prices seen after swaps, kept across state
+/// updates to measure volatility for [`AdaptiveFee`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VolatilityTracker {
+    window: usize,
+    prices: VecDeque<f64>,
+    /// Swaps the pool had made at the last recorded state
+    swap_count: Option<u128>,
+}
+
+impl VolatilityTracker {
+    /// Tracks the prices after the last `window` swaps seen
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            prices: VecDeque::with_capacity(window),
+            swap_count: None,
+        }
+    }
+
+    /// Records the spot price of `state` when it has seen swaps since the
+    /// last recorded state. Updates without new swaps leave the window as is.
+    pub fn record(&mut self, state: &LiquidityPoolState) {
+        let swap_count = state.swap_in_count.saturating_add(state.swap_out_count);
+        if self.swap_count == Some(swap_count)
+            || self.window == 0
+            || state.base_token_vault_balance == 0
+        {
+            return;
+        }
+        self.swap_count = Some(swap_count);
+        if self.prices.len() == self.window {
+            self.prices.pop_front();
+        }
+        self.prices.push_back(
+            state.quote_token_vault_balance as f64 / state.base_token_vault_balance as f64,
+        );
+    }
+
+    /// Spread between the highest and lowest recorded prices, over the
+    /// lowest, in basis points
+    pub fn volatility_bps(&self) -> u64 {
+        let lowest = self.prices.iter().copied().fold(f64::INFINITY, f64::min);
+        let highest = self.prices.iter().copied().fold(0.0, f64::max);
+        if self.prices.len() < 2 || lowest <= 0.0 {
+            return 0;
+        }
+        ((highest - lowest) / lowest * TEN_THOUSAND as f64).round() as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fee_schedule() -> FeeSchedule {
+        FeeSchedule::new(25, 10_000, 5, 10_000).unwrap()
+    }
+
+    #[test]
+    fn test_tiered_fee_by_size() {
+        let fee_mode = FeeMode::Tiered(
+            FeeTiers::new(&[
+                FeeTier {
+                    minimum_amount_in: 0,
+                    swap_fee_numerator: 30,
+                },
+                FeeTier {
+                    minimum_amount_in: 1_000_000,
+                    swap_fee_numerator: 20,
+                },
+            ])
+            .unwrap(),
+        );
+
+        let swap_fee = |amount_in| {
+            fee_mode
+                .fee_schedule(&fee_schedule(), amount_in)
+                .unwrap()
+                .swap_fee_numerator
+        };
+        assert_eq!(swap_fee(0), 30);
+        assert_eq!(swap_fee(999_999), 30);
+        assert_eq!(swap_fee(1_000_000), 20);
+        assert_eq!(swap_fee(u64::MAX), 20);
+        // The protocol swap fee is kept
+        assert_eq!(
+            fee_mode
+                .fee_schedule(&fee_schedule(), 0)
+                .unwrap()
+                .protocol_swap_fee_numerator,
+            5
+        );
+    }
+
+    #[test]
+    fn test_fee_tiers_validation() {
+        let tier = |minimum_amount_in| FeeTier {
+            minimum_amount_in,
+            swap_fee_numerator: 30,
+        };
+        assert!(FeeTiers::new(&[]).is_err());
+        assert!(FeeTiers::new(&[tier(1)]).is_err());
+        assert!(FeeTiers::new(&[tier(0), tier(10), tier(10)]).is_err());
+        let tiers = (0..=MAX_FEE_TIERS as u64).map(tier).collect::<Vec<_>>();
+        assert!(FeeTiers::new(&tiers[..MAX_FEE_TIERS]).is_ok());
+        assert!(FeeTiers::new(&tiers).is_err());
+        assert!(FeeTiers::new(&[tier(0), tier(10)]).is_ok());
+        // Tiers are still checked against the protocol swap fee
+        let fee_mode = FeeMode::Tiered(
+            FeeTiers::new(&[FeeTier {
+                minimum_amount_in: 0,
+                swap_fee_numerator: 10_000,
+            }])
+            .unwrap(),
+        );
+        assert!(fee_mode.fee_schedule(&fee_schedule(), 1).is_err());
+    }
+
+    #[test]
+    fn test_adaptive_fee_follows_volatility_up_to_cap() {
+        let fee_mode = FeeMode::Adaptive(AdaptiveFee {
+            sensitivity_numerator: 1,
+            sensitivity_denominator: 10,
+            max_swap_fee_numerator: 100,
+            volatility_bps: 0,
+        });
+        let swap_fee = |volatility_bps| {
+            fee_mode
+                .with_volatility(volatility_bps)
+                .fee_schedule(&fee_schedule(), 1_000)
+                .unwrap()
+                .swap_fee_numerator
+        };
+
+        assert_eq!(swap_fee(0), 25);
+        // 500 basis points of volatility add 50 basis points of fee
+        assert_eq!(swap_fee(500), 75);
+        assert_eq!(swap_fee(5_000), 100);
+        assert_eq!(swap_fee(u64::MAX), 100);
+    }
+
+    #[test]
+    fn test_volatility_tracker_window() {
+        let state = |quote, swap_in_count| LiquidityPoolState {
+            base_token_vault_balance: 1_000,
+            quote_token_vault_balance: quote,
+            swap_in_count,
+            ..Default::default()
+        };
+        let mut tracker = VolatilityTracker::new(3);
+
+        tracker.record(&state(1_000, 0));
+        assert_eq!(tracker.volatility_bps(), 0);
+        tracker.record(&state(1_100, 1));
+        assert_eq!(tracker.volatility_bps(), 1_000);
+        // No new swap, the update is not a new sample
+        tracker.record(&state(2_000, 1));
+        assert_eq!(tracker.volatility_bps(), 1_000);
+        tracker.record(&state(1_050, 2));
+        tracker.record(&state(1_100, 3));
+        // The first price has left the window
+        assert_eq!(tracker.volatility_bps(), 476);
+    }
+}
@@ -0,0 +1,508 @@
+use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::TransferFee;
+use anyhow::Result;
+
+use super::{
+    error::CalculatorError,
+    number::{CheckedAsU64, U128},
+    rounding::{CheckedMulDiv, RoundDirection},
+    swap_context::TransferFees,
+    LiquidityCalculator,
+};
+use crate::{instructions::LiquidityPoolState, utils::error::AmmErrorCode, TEN_THOUSAND};
+
+/// Tokens a depositor sends for `lp_amount` LP tokens. Amounts sent include
+/// the Token-2022 transfer fee; the vaults receive them net of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DepositResult {
+    /// LP tokens minted to the depositor
+    pub lp_amount: u64,
+    /// LP tokens the first deposit of a pool locks instead of minting them
+    /// to the depositor
+    pub locked_lp_amount: u64,
+    pub base_amount_in: u64,
+    pub quote_amount_in: u64,
+    pub base_transfer_fee: u64,
+    pub quote_transfer_fee: u64,
+    /// Slippage-bounded amounts to sign the deposit with
+    pub maximum_base_amount_in: u64,
+    pub maximum_quote_amount_in: u64,
+}
+
+/// Tokens a withdrawer receives for burning `lp_amount` LP tokens. Amounts
+/// received are net of the Token-2022 transfer fee.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WithdrawResult {
+    pub lp_amount: u64,
+    pub base_amount_out: u64,
+    pub quote_amount_out: u64,
+    pub base_transfer_fee: u64,
+    pub quote_transfer_fee: u64,
+    /// Slippage-bounded amounts to sign the withdrawal with
+    pub minimum_base_amount_out: u64,
+    pub minimum_quote_amount_out: u64,
+}
+
+fn amm_error(error_code: AmmErrorCode) -> anyhow::Error {
+    anchor_lang::error::Error::from(error_code).into()
+}
+
+/// Amount to send so that `vault_amount` reaches the vault, and its transfer fee
+fn gross_up(transfer_fee: &TransferFee, vault_amount: u64) -> Result<(u64, u64)> {
+    let fee = transfer_fee
+        .calculate_inverse_fee(vault_amount)
+        .ok_or(CalculatorError::Overflow)?;
+    let amount = vault_amount
+        .checked_add(fee)
+        .ok_or(CalculatorError::Overflow)?;
+    Ok((amount, fee))
+}
+
+/// Amount received out of `amount` sent, and its transfer fee
+fn net_of(transfer_fee: &TransferFee, amount: u64) -> Result<(u64, u64)> {
+    let fee = transfer_fee
+        .calculate_fee(amount)
+        .ok_or(CalculatorError::Overflow)?;
+    let amount = amount
+        .checked_sub(fee)
+        .ok_or(CalculatorError::FeeExceedsInput)?;
+    Ok((amount, fee))
+}
+
+fn slippage(amount: u64, slippage_numerator: u64) -> Result<u64> {
+    Ok(U128::from(amount)
+        .checked_mul_div(
+            slippage_numerator.into(),
+            TEN_THOUSAND.into(),
+            RoundDirection::Ceiling,
+        )
+        .and_then(|slippage| slippage.checked_as_u64())
+        .ok_or(CalculatorError::Overflow)?)
+}
+
+/// `amount * numerator / denominator`, rounded in `round_direction`
+fn mul_div(
+    amount: u64,
+    numerator: u64,
+    denominator: u64,
+    round_direction: RoundDirection,
+) -> Result<u64> {
+    Ok(U128::from(amount)
+        .checked_mul_div(numerator.into(), denominator.into(), round_direction)
+        .and_then(|amount| amount.checked_as_u64())
+        .ok_or(CalculatorError::Overflow)?)
+}
+
+impl LiquidityCalculator {
+    /// LP tokens minted by the first deposit of a pool: the geometric mean of
+    /// the amounts reaching the vaults, rounded down. Pool creation locks one
+    /// whole base token worth of it, `10^base_decimals`, and mints the rest
+    /// to the depositor.
+    pub fn initial_deposit(
+        base_amount_in: u64,
+        quote_amount_in: u64,
+        base_decimals: u8,
+        transfer_fees: &TransferFees,
+    ) -> Result<DepositResult> {
+        transfer_fees.validate()?;
+        let (base_vault_amount, base_transfer_fee) = net_of(&transfer_fees.base, base_amount_in)?;
+        let (quote_vault_amount, quote_transfer_fee) =
+            net_of(&transfer_fees.quote, quote_amount_in)?;
+
+        let liquidity = U128::from(base_vault_amount)
+            .checked_mul(quote_vault_amount.into())
+            .ok_or(CalculatorError::Overflow)?
+            .integer_sqrt()
+            .checked_as_u64()
+            .ok_or(CalculatorError::Overflow)?;
+        let locked_lp_amount = 10u64
+            .checked_pow(base_decimals.into())
+            .ok_or(CalculatorError::Overflow)?;
+        if liquidity <= locked_lp_amount {
+            return Err(amm_error(AmmErrorCode::InvalidAddLiquidityInput));
+        }
+
+        Ok(DepositResult {
+            lp_amount: liquidity - locked_lp_amount,
+            locked_lp_amount,
+            base_amount_in,
+            quote_amount_in,
+            base_transfer_fee,
+            quote_transfer_fee,
+            maximum_base_amount_in: base_amount_in,
+            maximum_quote_amount_in: quote_amount_in,
+        })
+    }
+
+    /// Tokens to deposit into an existing pool for `lp_amount` LP tokens. The
+    /// vault amounts are proportional to the reserves and rounded up.
+    pub fn deposit(
+        state: &LiquidityPoolState,
+        lp_amount: u64,
+        transfer_fees: &TransferFees,
+        slippage_numerator: u64,
+    ) -> Result<DepositResult> {
+        state.checked_allow_add_liquidity()?;
+        transfer_fees.validate()?;
+        let lp_supply = state.lp_token_current_supply;
+        if lp_amount == 0 || lp_supply == 0 {
+            return Err(amm_error(AmmErrorCode::InvalidAddLiquidityInput));
+        }
+
+        let base_vault_amount = mul_div(
+            state.base_token_vault_balance,
+            lp_amount,
+            lp_supply,
+            RoundDirection::Ceiling,
+        )?;
+        let quote_vault_amount = mul_div(
+            state.quote_token_vault_balance,
+            lp_amount,
+            lp_supply,
+            RoundDirection::Ceiling,
+        )?;
+        let (base_amount_in, base_transfer_fee) = gross_up(&transfer_fees.base, base_vault_amount)?;
+        let (quote_amount_in, quote_transfer_fee) =
+            gross_up(&transfer_fees.quote, quote_vault_amount)?;
+
+        Ok(DepositResult {
+            lp_amount,
+            locked_lp_amount: 0,
+            base_amount_in,
+            quote_amount_in,
+            base_transfer_fee,
+            quote_transfer_fee,
+            maximum_base_amount_in: base_amount_in
+                .checked_add(slippage(base_amount_in, slippage_numerator)?)
+                .ok_or(CalculatorError::Overflow)?,
+            maximum_quote_amount_in: quote_amount_in
+                .checked_add(slippage(quote_amount_in, slippage_numerator)?)
+                .ok_or(CalculatorError::Overflow)?,
+        })
+    }
+
+    /// Largest LP amount that `base_amount_in` and `quote_amount_in`, sent by
+    /// the depositor, can pay for.
+    pub fn lp_amount_for_deposit(
+        state: &LiquidityPoolState,
+        base_amount_in: u64,
+        quote_amount_in: u64,
+        transfer_fees: &TransferFees,
+    ) -> Result<u64> {
+        transfer_fees.validate()?;
+        let lp_supply = state.lp_token_current_supply;
+        if state.base_token_vault_balance == 0 || state.quote_token_vault_balance == 0 {
+            return Err(amm_error(AmmErrorCode::InvalidAddLiquidityInput));
+        }
+        let (mut base_vault_amount, _) = net_of(&transfer_fees.base, base_amount_in)?;
+        let (mut quote_vault_amount, _) = net_of(&transfer_fees.quote, quote_amount_in)?;
+
+        // Grossing the vault amounts back up for the transfer fee can cost a
+        // unit more than was sent; shrink the budget by that much and retry
+        for _ in 0..8 {
+            let lp_amount = mul_div(
+                base_vault_amount,
+                lp_supply,
+                state.base_token_vault_balance,
+                RoundDirection::Floor,
+            )?
+            .min(mul_div(
+                quote_vault_amount,
+                lp_supply,
+                state.quote_token_vault_balance,
+                RoundDirection::Floor,
+            )?);
+            if lp_amount == 0 {
+                return Ok(0);
+            }
+            let deposit = Self::deposit(state, lp_amount, transfer_fees, 0)?;
+            if deposit.base_amount_in <= base_amount_in
+                && deposit.quote_amount_in <= quote_amount_in
+            {
+                return Ok(lp_amount);
+            }
+            base_vault_amount = base_vault_amount
+                .saturating_sub(deposit.base_amount_in.saturating_sub(base_amount_in));
+            quote_vault_amount = quote_vault_amount
+                .saturating_sub(deposit.quote_amount_in.saturating_sub(quote_amount_in));
+        }
+        Err(CalculatorError::DidNotConverge.into())
+    }
+
+    /// Tokens received for burning `lp_amount` LP tokens, rounded down.
+    ///
+    /// Until `lock_until`, the `locked_lp` part of the supply cannot be
+    /// withdrawn. `now` is in the same unit as `lock_until`.
+    pub fn withdraw(
+        state: &LiquidityPoolState,
+        lp_amount: u64,
+        transfer_fees: &TransferFees,
+        slippage_numerator: u64,
+        now: u64,
+    ) -> Result<WithdrawResult> {
+        state.checked_allow_remove_liquidity()?;
+        let redeemable = Self::redeemable(state, lp_amount, transfer_fees)?;
+        let unlocked_lp = state
+            .lp_token_current_supply
+            .saturating_sub(state.locked_lp);
+        if state.lock_until > now && lp_amount > unlocked_lp {
+            return Err(amm_error(AmmErrorCode::LpTokensLocked));
+        }
+
+        Ok(WithdrawResult {
+            minimum_base_amount_out: redeemable
+                .base_amount_out
+                .saturating_sub(slippage(redeemable.base_amount_out, slippage_numerator)?),
+            minimum_quote_amount_out: redeemable
+                .quote_amount_out
+                .saturating_sub(slippage(redeemable.quote_amount_out, slippage_numerator)?),
+            ..redeemable
+        })
+    }
+
+    /// Share of the vaults `lp_amount` LP tokens are worth, net of transfer
+    /// fees and rounded down like [`LiquidityCalculator::withdraw`], but
+    /// regardless of whether the pool allows withdrawals or the tokens are
+    /// locked. The minimum amounts are the amounts themselves.
+    pub fn redeemable(
+        state: &LiquidityPoolState,
+        lp_amount: u64,
+        transfer_fees: &TransferFees,
+    ) -> Result<WithdrawResult> {
+        transfer_fees.validate()?;
+        let lp_supply = state.lp_token_current_supply;
+        if lp_amount == 0 || lp_amount > lp_supply {
+            return Err(amm_error(AmmErrorCode::InvalidRemoveLiquidityInput));
+        }
+
+        let base_vault_amount = mul_div(
+            state.base_token_vault_balance,
+            lp_amount,
+            lp_supply,
+            RoundDirection::Floor,
+        )?;
+        let quote_vault_amount = mul_div(
+            state.quote_token_vault_balance,
+            lp_amount,
+            lp_supply,
+            RoundDirection::Floor,
+        )?;
+        let (base_amount_out, base_transfer_fee) = net_of(&transfer_fees.base, base_vault_amount)?;
+        let (quote_amount_out, quote_transfer_fee) =
+            net_of(&transfer_fees.quote, quote_vault_amount)?;
+
+        Ok(WithdrawResult {
+            lp_amount,
+            base_amount_out,
+            quote_amount_out,
+            base_transfer_fee,
+            quote_transfer_fee,
+            minimum_base_amount_out: base_amount_out,
+            minimum_quote_amount_out: quote_amount_out,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn state() -> LiquidityPoolState {
+        LiquidityPoolState {
+            base_token_vault_balance: 1_000_000_000,
+            quote_token_vault_balance: 4_000_000_000,
+            lp_token_current_supply: 2_000_000_000,
+            allow_add_liquidity: true,
+            allow_remove_liquidity: true,
+            ..Default::default()
+        }
+    }
+
+    fn transfer_fees() -> TransferFees {
+        TransferFees {
+            base: TransferFee {
+                epoch: 0.into(),
+                maximum_fee: u64::MAX.into(),
+                transfer_fee_basis_points: 100.into(),
+            },
+            ..Default::default()
+        }
+    }
+
+    fn error_code(error: &anyhow::Error) -> Option<u32> {
+        match error.downcast_ref::<anchor_lang::error::Error>() {
+            Some(anchor_lang::error::Error::AnchorError(error)) => Some(error.error_code_number),
+            _ => None,
+        }
+    }
+
+    fn code(error_code: AmmErrorCode) -> Option<u32> {
+        Some(anchor_lang::error::ERROR_CODE_OFFSET + error_code as u32)
+    }
+
+    #[test]
+    fn test_initial_deposit_mints_geometric_mean() {
+        let result =
+            LiquidityCalculator::initial_deposit(1_010_101_011, 4_000_000_000, 6, &transfer_fees())
+                .unwrap();
+
+        // 1% of the base is taken by the transfer fee, one base token worth
+        // of the 2_000_000_000 LP is locked
+        assert_eq!(result.base_transfer_fee, 10_101_011);
+        assert_eq!(result.locked_lp_amount, 1_000_000);
+        assert_eq!(result.lp_amount, 1_999_000_000);
+    }
+
+    #[test]
+    fn test_initial_deposit_locks_lp_like_pool_creation() {
+        // The amounts of `create_liquidity_pool::test::test_create_liquidity_pool`
+        let result = LiquidityCalculator::initial_deposit(
+            10u64.pow(18),
+            10u64.pow(9),
+            9,
+            &TransferFees::default(),
+        )
+        .unwrap();
+
+        assert_eq!(result.locked_lp_amount, 1_000_000_000);
+        assert_eq!(result.lp_amount, 31_622_776_601_683 - 1_000_000_000);
+
+        // Liquidity of no more than the locked amount mints nothing
+        let error = LiquidityCalculator::initial_deposit(
+            1_000_000_000,
+            1_000_000_000,
+            9,
+            &TransferFees::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error_code(&error),
+            code(AmmErrorCode::InvalidAddLiquidityInput)
+        );
+    }
+
+    #[test]
+    fn test_deposit_rounds_up_and_grosses_up_transfer_fee() {
+        let result = LiquidityCalculator::deposit(&state(), 3, &transfer_fees(), 100).unwrap();
+
+        // 1.5 base and 6 quote reach the vaults
+        assert_eq!(result.base_amount_in - result.base_transfer_fee, 2);
+        assert_eq!(result.quote_amount_in, 6);
+        assert_eq!(result.maximum_quote_amount_in, 7);
+
+        let lp_amount = LiquidityCalculator::lp_amount_for_deposit(
+            &state(),
+            10_101_011,
+            40_000_000,
+            &transfer_fees(),
+        )
+        .unwrap();
+        assert_eq!(lp_amount, 20_000_000);
+        let deposit =
+            LiquidityCalculator::deposit(&state(), lp_amount, &transfer_fees(), 0).unwrap();
+        assert!(deposit.base_amount_in <= 10_101_011);
+        assert!(deposit.quote_amount_in <= 40_000_000);
+    }
+
+    #[test]
+    fn test_withdraw_rounds_down_and_nets_transfer_fee() {
+        let result = LiquidityCalculator::withdraw(&state(), 3, &transfer_fees(), 100, 0).unwrap();
+
+        // 1.5 base and 6 quote leave the vaults
+        assert_eq!(result.base_amount_out + result.base_transfer_fee, 1);
+        assert_eq!(result.quote_amount_out, 6);
+        assert_eq!(result.minimum_quote_amount_out, 5);
+    }
+
+    #[test]
+    fn test_liquidity_gates() {
+        let closed = LiquidityPoolState {
+            allow_add_liquidity: false,
+            allow_remove_liquidity: false,
+            ..state()
+        };
+        let error = LiquidityCalculator::deposit(&closed, 1, &transfer_fees(), 0).unwrap_err();
+        assert_eq!(error_code(&error), code(AmmErrorCode::AddLiquidityDisabled));
+        let error = LiquidityCalculator::withdraw(&closed, 1, &transfer_fees(), 0, 0).unwrap_err();
+        assert_eq!(
+            error_code(&error),
+            code(AmmErrorCode::RemoveLiquidityDisabled)
+        );
+
+        let locked = LiquidityPoolState {
+            locked_lp: 1_000_000_000,
+            lock_until: 100,
+            ..state()
+        };
+        let error = LiquidityCalculator::withdraw(&locked, 1_500_000_000, &transfer_fees(), 0, 99)
+            .unwrap_err();
+        assert_eq!(error_code(&error), code(AmmErrorCode::LpTokensLocked));
+        assert!(
+            LiquidityCalculator::withdraw(&locked, 1_000_000_000, &transfer_fees(), 0, 99).is_ok()
+        );
+        assert!(
+            LiquidityCalculator::withdraw(&locked, 1_500_000_000, &transfer_fees(), 0, 100).is_ok()
+        );
+
+        let error = LiquidityCalculator::deposit(&state(), 0, &transfer_fees(), 0).unwrap_err();
+        assert_eq!(
+            error_code(&error),
+            code(AmmErrorCode::InvalidAddLiquidityInput)
+        );
+        let error = LiquidityCalculator::withdraw(&state(), 2_000_000_001, &transfer_fees(), 0, 0)
+            .unwrap_err();
+        assert_eq!(
+            error_code(&error),
+            code(AmmErrorCode::InvalidRemoveLiquidityInput)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_lp_amount_for_deposit_fits_the_amounts_sent(
+            reserves in (1..=u64::MAX / 2, 1..=u64::MAX / 2),
+            lp_supply in 1..=u64::MAX / 2,
+            amounts_in in (0..=u64::MAX / 4, 0..=u64::MAX / 4),
+            transfer_fee_basis_points in (0..=10_000u16, 0..=10_000u16),
+        ) {
+            let state = LiquidityPoolState {
+                base_token_vault_balance: reserves.0,
+                quote_token_vault_balance: reserves.1,
+                lp_token_current_supply: lp_supply,
+                ..state()
+            };
+            let transfer_fee = |transfer_fee_basis_points: u16| TransferFee {
+                epoch: 0.into(),
+                maximum_fee: u64::MAX.into(),
+                transfer_fee_basis_points: transfer_fee_basis_points.into(),
+            };
+            let transfer_fees = TransferFees::new(
+                transfer_fee(transfer_fee_basis_points.0),
+                transfer_fee(transfer_fee_basis_points.1),
+            )
+            .unwrap();
+
+            // More LP tokens than a `u64` can hold
+            let lp_amount = match LiquidityCalculator::lp_amount_for_deposit(
+                &state,
+                amounts_in.0,
+                amounts_in.1,
+                &transfer_fees,
+            ) {
+                Err(error)
+                    if error.downcast_ref::<CalculatorError>()
+                        == Some(&CalculatorError::Overflow) =>
+                {
+                    return Ok(());
+                }
+                result => result.unwrap(),
+            };
+            if lp_amount > 0 {
+                let deposit =
+                    LiquidityCalculator::deposit(&state, lp_amount, &transfer_fees, 0).unwrap();
+                prop_assert!(deposit.base_amount_in <= amounts_in.0);
+                prop_assert!(deposit.quote_amount_in <= amounts_in.1);
+            }
+        }
+    }
+}
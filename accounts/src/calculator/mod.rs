@@ -1,13 +1,25 @@
 use anchor_lang::prelude::*;
 
-use self::{number::U128, swap_direction::SwapDirection};
+use self::{number::U128, swap_direction::SwapDirection, taxation_mode::TaxationMode};
 
 use super::stable_coin;
 
 pub mod constant_product_curve;
+pub mod error;
+pub mod fee_mode;
+pub mod liquidity;
 pub mod number;
+pub mod price;
+pub mod rounding;
+pub mod shared_constant_product_curve;
+pub mod stable_swap_curve;
+pub mod swap_context;
+pub mod swap_curve;
 pub mod swap_direction;
+pub mod tax_decay;
 pub mod taxation_mode;
+pub mod token_swap_calculator;
+pub mod virtual_constant_product_curve;
 
 #[derive(PartialEq, Eq)]
 pub enum StableCoin {
@@ -16,13 +28,6 @@ pub enum StableCoin {
     USDT,
 }
 
-#[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum RoundDirection {
-    Floor,
-    Ceiling,
-}
-
 impl StableCoin {
     pub fn to_u8(&self) -> u8 {
         match self {
@@ -50,11 +55,37 @@ impl StableCoin {
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct ConstantProduct;
-pub struct SharedConstantProduct;
+/// Constant product priced against the reserves of every pool on the same
+/// pair. The reserves handed to the curve are the quoted pool's own vault
+/// balances; the fields hold the combined balances of the other pools.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SharedConstantProduct {
+    pub other_pools_base_reserve: u64,
+    pub other_pools_quote_reserve: u64,
+}
+/// Curve-style StableSwap invariant for pairs of pegged assets with the same
+/// number of decimals, e.g. USDC/USDT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StableSwap {
+    pub amplification_coefficient: u64,
+}
+/// Constant product priced against real plus virtual reserves, so launch
+/// pools can trade before real quote liquidity exists. The reserves handed to
+/// the curve are the real vault balances.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VirtualConstantProduct {
+    pub virtual_base_reserve: u64,
+    pub virtual_quote_reserve: u64,
+    /// Real quote reserve at which the launch curve is considered completed
+    pub completion_quote_reserve: u64,
+}
 pub struct TokenSwapCalculator<Curve> {
     _marker: std::marker::PhantomData<Curve>,
 }
+/// Deposits into and withdrawals from the constant-product reserves
+pub struct LiquidityCalculator;
 
 #[derive(PartialEq, Eq, Copy, Clone, Default, Debug)]
 pub enum ProtocolSwapFeeDirection {
@@ -65,6 +96,15 @@ pub enum ProtocolSwapFeeDirection {
 }
 
 impl ProtocolSwapFeeDirection {
+    /// The protocol swap fee is charged on the same side as the swap tax
+    pub fn from_taxation_mode(taxation_mode: &TaxationMode) -> Self {
+        match taxation_mode {
+            TaxationMode::Base => ProtocolSwapFeeDirection::Base,
+            TaxationMode::Quote => ProtocolSwapFeeDirection::Quote,
+            TaxationMode::None => ProtocolSwapFeeDirection::None,
+        }
+    }
+
     pub fn from(
         base_mint: &Pubkey,
         quote_mint: &Pubkey,
@@ -98,6 +138,10 @@ pub struct SwapInCalculationResult {
     pub swap_tax_on_output_amount: U128,
     pub protocol_swap_fee_on_input_amount: U128,
     pub protocol_swap_fee_on_output_amount: U128,
+    // Protocol's cut of the swap tax, part of `swap_tax_on_*_amount` and
+    // accounted in `base_protocol_tax` / `quote_protocol_tax`
+    pub protocol_tax_on_input_amount: U128,
+    pub protocol_tax_on_output_amount: U128,
 }
 
 #[derive(Debug)]
@@ -111,4 +155,8 @@ pub struct SwapOutCalculationResult {
     pub swap_tax_on_output_amount: U128,
     pub protocol_swap_fee_on_input_amount: U128,
     pub protocol_swap_fee_on_output_amount: U128,
+    // Protocol's cut of the swap tax, part of `swap_tax_on_*_amount` and
+    // accounted in `base_protocol_tax` / `quote_protocol_tax`
+    pub protocol_tax_on_input_amount: U128,
+    pub protocol_tax_on_output_amount: U128,
 }
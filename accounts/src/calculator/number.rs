@@ -10,71 +10,17 @@ construct_uint! {
     pub struct U256(4);
 }
 
-pub trait CheckedCeilDiv: Sized {
-    /// Perform ceiling division
-    fn checked_ceil_div(&self, rhs: Self) -> Option<(Self, Self)>;
+pub trait CheckedAsU64 {
+    /// Narrow to `u64`, returning `None` instead of panicking when the value
+    /// does not fit
+    fn checked_as_u64(&self) -> Option<u64>;
 }
 
-impl CheckedCeilDiv for u128 {
-    fn checked_ceil_div(&self, mut rhs: Self) -> Option<(Self, Self)> {
-        let mut quotient = self.checked_div(rhs)?;
-        // Avoid dividing a small number by a big one and returning 1, and instead
-        // fail.
-        if quotient == 0 {
-            // return None;
-            if self.checked_mul(2 as u128)? >= rhs {
-                return Some((1, 0));
-            } else {
-                return Some((0, 0));
-            }
+impl CheckedAsU64 for U128 {
+    fn checked_as_u64(&self) -> Option<u64> {
+        if *self > U128::from(u64::MAX) {
+            return None;
         }
-
-        // Ceiling the destination amount if there's any remainder, which will
-        // almost always be the case.
-        let remainder = self.checked_rem(rhs)?;
-        if remainder > 0 {
-            quotient = quotient.checked_add(1)?;
-            // calculate the minimum amount needed to get the dividend amount to
-            // avoid truncating too much
-            rhs = self.checked_div(quotient)?;
-            let remainder = self.checked_rem(quotient)?;
-            if remainder > 0 {
-                rhs = rhs.checked_add(1)?;
-            }
-        }
-        Some((quotient, rhs))
-    }
-}
-
-impl CheckedCeilDiv for U128 {
-    fn checked_ceil_div(&self, mut rhs: Self) -> Option<(Self, Self)> {
-        let mut quotient = self.checked_div(rhs)?;
-        // Avoid dividing a small number by a big one and returning 1, and instead
-        // fail.
-        let zero = U128::from(0);
-        let one = U128::from(1);
-        if quotient.is_zero() {
-            // return None;
-            if self.checked_mul(U128::from(2))? >= rhs {
-                return Some((one, zero));
-            } else {
-                return Some((zero, zero));
-            }
-        }
-
-        // Ceiling the destination amount if there's any remainder, which will
-        // almost always be the case.
-        let remainder = self.checked_rem(rhs)?;
-        if remainder > zero {
-            quotient = quotient.checked_add(one)?;
-            // calculate the minimum amount needed to get the dividend amount to
-            // avoid truncating too much
-            rhs = self.checked_div(quotient)?;
-            let remainder = self.checked_rem(quotient)?;
-            if remainder > zero {
-                rhs = rhs.checked_add(one)?;
-            }
-        }
-        Some((quotient, rhs))
+        Some(self.low_u64())
     }
 }
@@ -0,0 +1,148 @@
+use anyhow::Result;
+
+use super::{
+    number::U128, swap_context::Reserves, swap_curve::SwapCurve, swap_direction::SwapDirection,
+};
+use crate::TEN_THOUSAND;
+
+/// Prices around a swap, in quote tokens per base token.
+///
+/// Built from raw token units by [`SwapPrices::new`], then converted to UI
+/// units with [`SwapPrices::to_ui`]. The price impact does not depend on the
+/// units.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SwapPrices {
+    /// Marginal price before the swap
+    pub spot_price_before: f64,
+    /// Average price the curve fills the swap at, fees excluded
+    pub execution_price: f64,
+    /// Marginal price once the swap has moved the reserves
+    pub spot_price_after: f64,
+    /// How much worse the execution price is than the spot price before the
+    /// swap, in basis points
+    pub price_impact_bps: f64,
+}
+
+impl SwapPrices {
+    /// `curve_amount_in` and `curve_amount_out` are the amounts going through
+    /// the curve, i.e. net of swap fees and taxes.
+    pub fn new<Curve: SwapCurve>(
+        curve: &Curve,
+        swap_direction: SwapDirection,
+        reserves_before: &Reserves,
+        reserves_after: &Reserves,
+        curve_amount_in: U128,
+        curve_amount_out: U128,
+    ) -> Result<Self> {
+        let spot_price_before =
+            curve.spot_price(reserves_before.base.into(), reserves_before.quote.into())?;
+        let spot_price_after =
+            curve.spot_price(reserves_after.base.into(), reserves_after.quote.into())?;
+
+        if curve_amount_in.is_zero() || curve_amount_out.is_zero() {
+            return Ok(Self {
+                spot_price_before,
+                execution_price: spot_price_before,
+                spot_price_after,
+                price_impact_bps: 0.0,
+            });
+        }
+
+        let amount_in = curve_amount_in.as_u128() as f64;
+        let amount_out = curve_amount_out.as_u128() as f64;
+        // Selling base lowers the price the user gets, buying base raises it
+        let (execution_price, price_impact) = match swap_direction {
+            SwapDirection::Base2Quote => {
+                let execution_price = amount_out / amount_in;
+                (execution_price, 1.0 - execution_price / spot_price_before)
+            }
+            SwapDirection::Quote2Base => {
+                let execution_price = amount_in / amount_out;
+                (execution_price, 1.0 - spot_price_before / execution_price)
+            }
+        };
+
+        Ok(Self {
+            spot_price_before,
+            execution_price,
+            spot_price_after,
+            price_impact_bps: price_impact.max(0.0) * TEN_THOUSAND as f64,
+        })
+    }
+
+    /// Converts prices in raw units to prices in UI units, given the mint
+    /// decimals.
+    pub fn to_ui(&self, base_decimals: u8, quote_decimals: u8) -> Self {
+        Self {
+            spot_price_before: ui_price(self.spot_price_before, base_decimals, quote_decimals),
+            execution_price: ui_price(self.execution_price, base_decimals, quote_decimals),
+            spot_price_after: ui_price(self.spot_price_after, base_decimals, quote_decimals),
+            price_impact_bps: self.price_impact_bps,
+        }
+    }
+}
+
+/// Converts a price of raw quote units per raw base unit into a price of
+/// whole quote tokens per whole base token.
+pub fn ui_price(raw_price: f64, base_decimals: u8, quote_decimals: u8) -> f64 {
+    raw_price * 10f64.powi(base_decimals as i32 - quote_decimals as i32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calculator::ConstantProduct;
+
+    #[test]
+    fn test_ui_price_adjusts_for_decimals() {
+        // 1 raw quote unit (6 decimals) per raw base unit (9 decimals)
+        assert_eq!(ui_price(1.0, 9, 6), 1_000.0);
+        assert_eq!(ui_price(1.0, 6, 9), 0.001);
+        assert_eq!(ui_price(2.5, 6, 6), 2.5);
+    }
+
+    #[test]
+    fn test_prices_around_a_buy() {
+        let reserves_before = Reserves::new(1_000_000_000, 1_000_000_000);
+        // Buy base with 1% of the quote reserve
+        let reserves_after = Reserves::new(990_099_010, 1_010_000_000);
+
+        let prices = SwapPrices::new(
+            &ConstantProduct,
+            SwapDirection::Quote2Base,
+            &reserves_before,
+            &reserves_after,
+            U128::from(10_000_000),
+            U128::from(9_900_990),
+        )
+        .unwrap()
+        .to_ui(9, 6);
+
+        assert_eq!(prices.spot_price_before, 1_000.0);
+        assert!(prices.execution_price > prices.spot_price_before);
+        assert!(prices.spot_price_after > prices.execution_price);
+        // Paying 1% of the reserve costs about 1% of price impact
+        assert!((prices.price_impact_bps - 99.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_prices_around_a_sell() {
+        let reserves_before = Reserves::new(1_000_000_000, 1_000_000_000);
+        let reserves_after = Reserves::new(1_010_000_000, 990_099_010);
+
+        let prices = SwapPrices::new(
+            &ConstantProduct,
+            SwapDirection::Base2Quote,
+            &reserves_before,
+            &reserves_after,
+            U128::from(10_000_000),
+            U128::from(9_900_990),
+        )
+        .unwrap();
+
+        assert_eq!(prices.spot_price_before, 1.0);
+        assert!(prices.execution_price < prices.spot_price_before);
+        assert!(prices.spot_price_after < prices.execution_price);
+        assert!((prices.price_impact_bps - 99.0).abs() < 0.1);
+    }
+}
@@ -0,0 +1,371 @@
+//! Rounding policy of the calculator.
+//!
+//! Every division done by the calculator states its [`RoundDirection`], and
+//! the direction is always the one that favors the pool:
+//!
+//! - swap fee, swap tax and protocol swap fee: [`RoundDirection::Ceiling`],
+//!   fees are never under-charged
+//! - curve amount out for an exact input: [`RoundDirection::Floor`], the
+//!   reserves never release more than the invariant allows
+//! - curve amount in for an exact output: [`RoundDirection::Ceiling`], the
+//!   reserves never receive less than the invariant requires
+//! - slippage tolerance: [`RoundDirection::Ceiling`], the tolerance is never
+//!   narrower than requested
+//! - liquidity: deposits [`RoundDirection::Ceiling`], withdrawals and LP
+//!   tokens minted [`RoundDirection::Floor`]
+
+use super::number::{U128, U256};
+
+/// Side a division rounds its quotient to
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoundDirection {
+    Floor,
+    Ceiling,
+}
+
+pub trait CheckedMulDiv: Sized {
+    /// `self * numerator / denominator`, rounded in `round_direction`.
+    /// The product is computed in 256 bits, so only a zero denominator or a
+    /// result that does not fit in `Self` fails.
+    fn checked_mul_div(
+        &self,
+        numerator: Self,
+        denominator: Self,
+        round_direction: RoundDirection,
+    ) -> Option<Self>;
+
+    /// `self / denominator`, rounded in `round_direction`.
+    fn checked_div_round(&self, denominator: Self, round_direction: RoundDirection)
+        -> Option<Self>;
+}
+
+impl CheckedMulDiv for U128 {
+    fn checked_mul_div(
+        &self,
+        numerator: Self,
+        denominator: Self,
+        round_direction: RoundDirection,
+    ) -> Option<Self> {
+        if denominator.is_zero() {
+            return None;
+        }
+        let product = U256::from(self.as_u128()) * U256::from(numerator.as_u128());
+        let denominator = U256::from(denominator.as_u128());
+        let (mut quotient, remainder) = product.div_mod(denominator);
+        if round_direction == RoundDirection::Ceiling && !remainder.is_zero() {
+            quotient += U256::one();
+        }
+        if quotient > U256::from(u128::MAX) {
+            return None;
+        }
+        Some(U128::from(quotient.as_u128()))
+    }
+
+    fn checked_div_round(
+        &self,
+        denominator: Self,
+        round_direction: RoundDirection,
+    ) -> Option<Self> {
+        self.checked_mul_div(U128::one(), denominator, round_direction)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        calculator::{
+            swap_context::{FeeSchedule, Reserves, SwapContext, TaxSchedule},
+            swap_curve::SwapCurve,
+            swap_direction::SwapDirection,
+            taxation_mode::TaxationMode,
+            ConstantProduct, ProtocolSwapFeeDirection, TokenSwapCalculator,
+        },
+        TEN_THOUSAND,
+    };
+    use num_bigint::BigInt;
+    use num_rational::BigRational;
+    use proptest::prelude::*;
+
+    /// Exact `value * numerator / denominator`, rounded in `round`
+    fn exact(value: u128, numerator: u128, denominator: u128, round: RoundDirection) -> u128 {
+        checked_exact(value, numerator, denominator, round).unwrap()
+    }
+
+    fn checked_exact(
+        value: u128,
+        numerator: u128,
+        denominator: u128,
+        round: RoundDirection,
+    ) -> Option<u128> {
+        let ratio = BigRational::new(
+            BigInt::from(value) * BigInt::from(numerator),
+            BigInt::from(denominator),
+        );
+        let rounded = match round {
+            RoundDirection::Floor => ratio.floor(),
+            RoundDirection::Ceiling => ratio.ceil(),
+        };
+        rounded.to_integer().try_into().ok()
+    }
+
+    fn swap_direction() -> impl Strategy<Value = SwapDirection> {
+        prop_oneof![
+            Just(SwapDirection::Base2Quote),
+            Just(SwapDirection::Quote2Base)
+        ]
+    }
+
+    fn context(
+        swap_direction: SwapDirection,
+        (base, quote): (u64, u64),
+        swap_fee_numerator: u64,
+        protocol_swap_fee_numerator: u64,
+        (buy_tax, sell_tax): (u64, u64),
+    ) -> SwapContext {
+        SwapContext {
+            swap_direction,
+            protocol_swap_fee_direction: ProtocolSwapFeeDirection::Quote,
+            taxation_mode: TaxationMode::Quote,
+            reserves: Reserves::new(base, quote),
+            fee_schedule: FeeSchedule {
+                swap_fee_numerator,
+                swap_fee_denominator: 10_000,
+                protocol_swap_fee_numerator,
+                protocol_swap_fee_denominator: 100_000,
+            },
+            tax_schedule: TaxSchedule {
+                buy_tax,
+                sell_tax,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rounding_direction() {
+        let seven = U128::from(7);
+        let two = U128::from(2);
+
+        assert_eq!(
+            seven.checked_div_round(two, RoundDirection::Floor),
+            Some(U128::from(3))
+        );
+        assert_eq!(
+            seven.checked_div_round(two, RoundDirection::Ceiling),
+            Some(U128::from(4))
+        );
+        // A tiny fee still costs one unit
+        assert_eq!(
+            U128::one().checked_mul_div(
+                U128::from(25),
+                U128::from(10_000),
+                RoundDirection::Ceiling
+            ),
+            Some(U128::one())
+        );
+        assert_eq!(
+            U128::one().checked_mul_div(U128::from(25), U128::from(10_000), RoundDirection::Floor),
+            Some(U128::zero())
+        );
+        assert_eq!(
+            seven.checked_div_round(U128::zero(), RoundDirection::Floor),
+            None
+        );
+        assert_eq!(
+            U128::MAX.checked_mul_div(two, U128::one(), RoundDirection::Floor),
+            None
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_mul_div_matches_exact_rational(
+            value in any::<u128>(),
+            numerator in any::<u64>(),
+            denominator in 1..=u64::MAX,
+        ) {
+            for round_direction in [RoundDirection::Floor, RoundDirection::Ceiling] {
+                let expected = checked_exact(
+                    value,
+                    numerator.into(),
+                    denominator.into(),
+                    round_direction,
+                );
+                let result = U128::from(value).checked_mul_div(
+                    numerator.into(),
+                    denominator.into(),
+                    round_direction,
+                );
+                prop_assert_eq!(result, expected.map(U128::from));
+            }
+        }
+
+        /// Fees and taxes round up, the curve output rounds down
+        #[test]
+        fn test_swap_in_rounds_in_pool_favor(
+            amount_in in 1..=1_000_000_000_000u64,
+            swap_direction in swap_direction(),
+            reserves in (1..=u64::MAX / 2, 1..=u64::MAX / 2),
+            swap_fee_numerator in 0..=1_000u64,
+            protocol_swap_fee_numerator in 0..=10_000u64,
+            taxes in (0..=3_000u64, 0..=3_000u64),
+        ) {
+            let context = context(
+                swap_direction,
+                reserves,
+                swap_fee_numerator,
+                protocol_swap_fee_numerator,
+                taxes,
+            );
+            let Ok(result) =
+                TokenSwapCalculator::<ConstantProduct>::swap_in(amount_in, &context)
+            else {
+                return Ok(());
+            };
+            let amount_in = amount_in as u128;
+            let ceiling = RoundDirection::Ceiling;
+
+            prop_assert_eq!(
+                result.swap_fee.as_u128(),
+                exact(amount_in, swap_fee_numerator.into(), 10_000, ceiling)
+            );
+            prop_assert_eq!(
+                result.protocol_swap_fee_on_input_amount.as_u128(),
+                match swap_direction {
+                    SwapDirection::Quote2Base => {
+                        exact(amount_in, protocol_swap_fee_numerator.into(), 100_000, ceiling)
+                    }
+                    SwapDirection::Base2Quote => 0,
+                }
+            );
+            prop_assert_eq!(
+                result.swap_tax_on_input_amount.as_u128(),
+                match swap_direction {
+                    SwapDirection::Quote2Base => {
+                        exact(amount_in, taxes.0.into(), TEN_THOUSAND.into(), ceiling)
+                    }
+                    SwapDirection::Base2Quote => 0,
+                }
+            );
+
+            let (source_reserve, destination_reserve) = match swap_direction {
+                SwapDirection::Base2Quote => reserves,
+                SwapDirection::Quote2Base => (reserves.1, reserves.0),
+            };
+            let curve_amount_in = result.swap_amount_in_after_fees.as_u128();
+            prop_assert_eq!(
+                result.swap_amount_out_before_fees.as_u128(),
+                exact(
+                    destination_reserve.into(),
+                    curve_amount_in,
+                    source_reserve as u128 + curve_amount_in,
+                    RoundDirection::Floor,
+                )
+            );
+
+            let amount_out = result.swap_amount_out_before_fees.as_u128();
+            prop_assert_eq!(
+                result.swap_tax_on_output_amount.as_u128(),
+                match swap_direction {
+                    SwapDirection::Base2Quote => {
+                        exact(amount_out, taxes.1.into(), TEN_THOUSAND.into(), ceiling)
+                    }
+                    SwapDirection::Quote2Base => 0,
+                }
+            );
+            prop_assert_eq!(
+                result.protocol_swap_fee_on_output_amount.as_u128(),
+                match swap_direction {
+                    SwapDirection::Base2Quote => {
+                        exact(amount_out, protocol_swap_fee_numerator.into(), 100_000, ceiling)
+                    }
+                    SwapDirection::Quote2Base => 0,
+                }
+            );
+        }
+
+        /// Fees and taxes round up, the curve input rounds up
+        #[test]
+        fn test_swap_out_rounds_in_pool_favor(
+            amount_out in 1..=1_000_000_000u64,
+            swap_direction in swap_direction(),
+            reserves in (1..=u64::MAX / 2, 1..=u64::MAX / 2),
+            swap_fee_numerator in 0..=1_000u64,
+            protocol_swap_fee_numerator in 0..=10_000u64,
+            taxes in (0..=3_000u64, 0..=3_000u64),
+        ) {
+            let context = context(
+                swap_direction,
+                reserves,
+                swap_fee_numerator,
+                protocol_swap_fee_numerator,
+                taxes,
+            );
+            let Ok(result) =
+                TokenSwapCalculator::<ConstantProduct>::swap_out(amount_out, &context)
+            else {
+                return Ok(());
+            };
+            let ceiling = RoundDirection::Ceiling;
+
+            let (source_reserve, destination_reserve) = match swap_direction {
+                SwapDirection::Base2Quote => reserves,
+                SwapDirection::Quote2Base => (reserves.1, reserves.0),
+            };
+            let curve_amount_out = result.swap_amount_out_after_fees.as_u128();
+            prop_assert_eq!(
+                result.swap_amount_in_before_fees.as_u128(),
+                exact(
+                    source_reserve.into(),
+                    curve_amount_out,
+                    destination_reserve as u128 - curve_amount_out,
+                    ceiling,
+                )
+            );
+
+            let curve_amount_in = result.swap_amount_in_before_fees.as_u128();
+            prop_assert_eq!(
+                result.swap_fee.as_u128(),
+                exact(curve_amount_in, swap_fee_numerator.into(), 10_000, ceiling)
+            );
+            prop_assert_eq!(
+                result.swap_tax_on_output_amount.as_u128(),
+                match swap_direction {
+                    SwapDirection::Base2Quote => {
+                        exact(amount_out.into(), taxes.1.into(), TEN_THOUSAND.into(), ceiling)
+                    }
+                    SwapDirection::Quote2Base => 0,
+                }
+            );
+            prop_assert_eq!(
+                result.swap_tax_on_input_amount.as_u128(),
+                match swap_direction {
+                    SwapDirection::Quote2Base => {
+                        exact(curve_amount_in, taxes.0.into(), TEN_THOUSAND.into(), ceiling)
+                    }
+                    SwapDirection::Base2Quote => 0,
+                }
+            );
+
+            // Rounding never lets the invariant decrease
+            let source_reserve_after = U128::from(source_reserve as u128 + curve_amount_in);
+            let destination_reserve_after =
+                U128::from(destination_reserve as u128 - curve_amount_out);
+            let (base_reserve_after, quote_reserve_after) = match swap_direction {
+                SwapDirection::Base2Quote => (source_reserve_after, destination_reserve_after),
+                SwapDirection::Quote2Base => (destination_reserve_after, source_reserve_after),
+            };
+            prop_assert!(ConstantProduct
+                .check_invariant(
+                    U128::from(reserves.0),
+                    U128::from(reserves.1),
+                    base_reserve_after,
+                    quote_reserve_after,
+                )
+                .is_ok());
+        }
+    }
+}
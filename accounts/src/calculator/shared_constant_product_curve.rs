@@ -0,0 +1,139 @@
+use super::{
+    error::CalculatorError,
+    number::{U128, U256},
+    swap_curve::SwapCurve,
+    swap_direction::SwapDirection,
+    ConstantProduct, SharedConstantProduct,
+};
+
+use anyhow::Result;
+
+impl SharedConstantProduct {
+    pub fn new(other_pools_base_reserve: u64, other_pools_quote_reserve: u64) -> Self {
+        Self {
+            other_pools_base_reserve,
+            other_pools_quote_reserve,
+        }
+    }
+
+    /// Reserves of the whole pool set, given the quoted pool's own reserves.
+    pub fn shared_reserves(&self, base_reserve: U128, quote_reserve: U128) -> Result<(U128, U128)> {
+        let shared_base_reserve = base_reserve
+            .checked_add(self.other_pools_base_reserve.into())
+            .ok_or(CalculatorError::Overflow)?;
+        let shared_quote_reserve = quote_reserve
+            .checked_add(self.other_pools_quote_reserve.into())
+            .ok_or(CalculatorError::Overflow)?;
+        Ok((shared_base_reserve, shared_quote_reserve))
+    }
+}
+
+impl SwapCurve for SharedConstantProduct {
+    fn swap_amount_out(
+        &self,
+        amount_in: U128,
+        base_reserve: U128,
+        quote_reserve: U128,
+        swap_direction: &SwapDirection,
+    ) -> Result<U128> {
+        let (shared_base_reserve, shared_quote_reserve) =
+            self.shared_reserves(base_reserve, quote_reserve)?;
+        let amount_out = ConstantProduct.swap_amount_out(
+            amount_in,
+            shared_base_reserve,
+            shared_quote_reserve,
+            swap_direction,
+        )?;
+
+        // The output is paid from the quoted pool's own vault
+        let destination_reserve = match swap_direction {
+            SwapDirection::Base2Quote => quote_reserve,
+            SwapDirection::Quote2Base => base_reserve,
+        };
+        if amount_out >= destination_reserve {
+            return Err(CalculatorError::InsufficientLiquidity.into());
+        }
+        Ok(amount_out)
+    }
+
+    fn swap_amount_in(
+        &self,
+        amount_out: U128,
+        base_reserve: U128,
+        quote_reserve: U128,
+        swap_direction: &SwapDirection,
+    ) -> Result<U128> {
+        let destination_reserve = match swap_direction {
+            SwapDirection::Base2Quote => quote_reserve,
+            SwapDirection::Quote2Base => base_reserve,
+        };
+        if amount_out >= destination_reserve {
+            return Err(CalculatorError::InsufficientLiquidity.into());
+        }
+
+        let (shared_base_reserve, shared_quote_reserve) =
+            self.shared_reserves(base_reserve, quote_reserve)?;
+        ConstantProduct.swap_amount_in(
+            amount_out,
+            shared_base_reserve,
+            shared_quote_reserve,
+            swap_direction,
+        )
+    }
+
+    fn spot_price(&self, base_reserve: U128, quote_reserve: U128) -> Result<f64> {
+        let (shared_base_reserve, shared_quote_reserve) =
+            self.shared_reserves(base_reserve, quote_reserve)?;
+        ConstantProduct.spot_price(shared_base_reserve, shared_quote_reserve)
+    }
+
+    fn invariant(&self, base_reserve: U128, quote_reserve: U128) -> Result<U256> {
+        let (shared_base_reserve, shared_quote_reserve) =
+            self.shared_reserves(base_reserve, quote_reserve)?;
+        ConstantProduct.invariant(shared_base_reserve, shared_quote_reserve)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shared_reserves_give_deeper_price() {
+        let amount_in = U128::from(1_000_000u64);
+        let base_reserve = U128::from(1_000_000_000u64);
+        let quote_reserve = U128::from(100_000_000u64);
+
+        let isolated = ConstantProduct
+            .swap_amount_out(
+                amount_in,
+                base_reserve,
+                quote_reserve,
+                &SwapDirection::Quote2Base,
+            )
+            .unwrap();
+        let shared = SharedConstantProduct::new(1_000_000_000, 100_000_000)
+            .swap_amount_out(
+                amount_in,
+                base_reserve,
+                quote_reserve,
+                &SwapDirection::Quote2Base,
+            )
+            .unwrap();
+
+        assert!(shared > isolated);
+    }
+
+    #[test]
+    fn test_output_is_capped_by_own_reserve() {
+        let curve = SharedConstantProduct::new(1_000_000_000, 1_000_000_000);
+        let result = curve.swap_amount_in(
+            U128::from(10_000u64),
+            U128::from(1_000_000u64),
+            U128::from(10_000u64),
+            &SwapDirection::Base2Quote,
+        );
+
+        assert!(result.is_err());
+    }
+}
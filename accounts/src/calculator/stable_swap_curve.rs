@@ -0,0 +1,320 @@
+use super::{
+    error::CalculatorError,
+    number::{U128, U256},
+    swap_curve::SwapCurve,
+    swap_direction::SwapDirection,
+    StableSwap,
+};
+
+use anyhow::Result;
+
+/// Number of tokens in a Heaven pool
+const N_COINS: u64 = 2;
+/// Newton's method iteration limit, same as the reference implementation
+const MAX_ITERATIONS: u16 = 255;
+
+impl StableSwap {
+    pub fn new(amplification_coefficient: u64) -> Self {
+        Self {
+            amplification_coefficient,
+        }
+    }
+
+    /// Amplification coefficient multiplied by n^n
+    fn ann(&self) -> Result<U256> {
+        if self.amplification_coefficient == 0 {
+            return Err(CalculatorError::InvalidCurveConfig.into());
+        }
+        U256::from(self.amplification_coefficient)
+            .checked_mul(U256::from(N_COINS * N_COINS))
+            .ok_or_else(|| CalculatorError::Overflow.into())
+    }
+
+    /// Solves the StableSwap invariant for `D` given both reserves.
+    pub fn compute_d(&self, base_reserve: U128, quote_reserve: U128) -> Result<U256> {
+        let ann = self.ann()?;
+        let x = to_u256(base_reserve);
+        let y = to_u256(quote_reserve);
+        let sum = x.checked_add(y).ok_or(CalculatorError::Overflow)?;
+        if sum.is_zero() {
+            return Ok(U256::zero());
+        }
+        if x.is_zero() || y.is_zero() {
+            return Err(CalculatorError::InsufficientLiquidity.into());
+        }
+
+        let n = U256::from(N_COINS);
+        let mut d = sum;
+        for _ in 0..MAX_ITERATIONS {
+            // d_p = D^(n+1) / (n^n * x * y)
+            let d_p = d
+                .checked_mul(d)
+                .and_then(|v| v.checked_div(x.checked_mul(n)?))
+                .and_then(|v| v.checked_mul(d))
+                .and_then(|v| v.checked_div(y.checked_mul(n)?))
+                .ok_or(CalculatorError::Overflow)?;
+            let d_previous = d;
+            // D = (Ann * S + D_P * n) * D / ((Ann - 1) * D + (n + 1) * D_P)
+            let numerator = ann
+                .checked_mul(sum)
+                .and_then(|v| v.checked_add(d_p.checked_mul(n)?))
+                .and_then(|v| v.checked_mul(d))
+                .ok_or(CalculatorError::Overflow)?;
+            let denominator = ann
+                .checked_sub(U256::one())
+                .and_then(|v| v.checked_mul(d))
+                .and_then(|v| v.checked_add(d_p.checked_mul(n.checked_add(U256::one())?)?))
+                .ok_or(CalculatorError::Overflow)?;
+            d = numerator
+                .checked_div(denominator)
+                .ok_or(CalculatorError::Overflow)?;
+            if abs_diff(d, d_previous) <= U256::one() {
+                return Ok(d);
+            }
+        }
+        Err(CalculatorError::DidNotConverge.into())
+    }
+
+    /// Solves the StableSwap invariant for the reserve on the other side of
+    /// `new_reserve`, keeping `d` constant.
+    fn compute_y(&self, new_reserve: U256, d: U256) -> Result<U256> {
+        if new_reserve.is_zero() {
+            return Err(CalculatorError::InsufficientLiquidity.into());
+        }
+        let ann = self.ann()?;
+        let n = U256::from(N_COINS);
+
+        // c = D^(n+1) / (n^n * x * Ann), b = x + D / Ann
+        let c = d
+            .checked_mul(d)
+            .and_then(|v| v.checked_div(new_reserve.checked_mul(n)?))
+            .and_then(|v| v.checked_mul(d))
+            .and_then(|v| v.checked_div(ann.checked_mul(n)?))
+            .ok_or(CalculatorError::Overflow)?;
+        let b = d
+            .checked_div(ann)
+            .and_then(|v| v.checked_add(new_reserve))
+            .ok_or(CalculatorError::Overflow)?;
+
+        let mut y = d;
+        for _ in 0..MAX_ITERATIONS {
+            let y_previous = y;
+            // y = (y^2 + c) / (2y + b - D)
+            let numerator = y
+                .checked_mul(y)
+                .and_then(|v| v.checked_add(c))
+                .ok_or(CalculatorError::Overflow)?;
+            let denominator = y
+                .checked_mul(n)
+                .and_then(|v| v.checked_add(b))
+                .and_then(|v| v.checked_sub(d))
+                .ok_or(CalculatorError::Overflow)?;
+            y = numerator
+                .checked_div(denominator)
+                .ok_or(CalculatorError::Overflow)?;
+            if abs_diff(y, y_previous) <= U256::one() {
+                return Ok(y);
+            }
+        }
+        Err(CalculatorError::DidNotConverge.into())
+    }
+}
+
+impl SwapCurve for StableSwap {
+    fn swap_amount_out(
+        &self,
+        amount_in: U128,
+        base_reserve: U128,
+        quote_reserve: U128,
+        swap_direction: &SwapDirection,
+    ) -> Result<U128> {
+        let (source_reserve, destination_reserve) = match swap_direction {
+            SwapDirection::Base2Quote => (base_reserve, quote_reserve),
+            SwapDirection::Quote2Base => (quote_reserve, base_reserve),
+        };
+        let d = self.compute_d(base_reserve, quote_reserve)?;
+        let new_source_reserve = to_u256(source_reserve)
+            .checked_add(to_u256(amount_in))
+            .ok_or(CalculatorError::Overflow)?;
+        let new_destination_reserve = self.compute_y(new_source_reserve, d)?;
+
+        // Keep one unit in the pool to absorb the rounding of `compute_y`, so
+        // the amount out is rounded down in the pool's favor
+        let amount_out = to_u256(destination_reserve)
+            .saturating_sub(new_destination_reserve)
+            .saturating_sub(U256::one());
+        to_u128(amount_out)
+    }
+
+    fn swap_amount_in(
+        &self,
+        amount_out: U128,
+        base_reserve: U128,
+        quote_reserve: U128,
+        swap_direction: &SwapDirection,
+    ) -> Result<U128> {
+        let (source_reserve, destination_reserve) = match swap_direction {
+            SwapDirection::Base2Quote => (base_reserve, quote_reserve),
+            SwapDirection::Quote2Base => (quote_reserve, base_reserve),
+        };
+        if amount_out >= destination_reserve {
+            return Err(CalculatorError::InsufficientLiquidity.into());
+        }
+        let d = self.compute_d(base_reserve, quote_reserve)?;
+        let new_destination_reserve = to_u256(destination_reserve - amount_out);
+        let new_source_reserve = self.compute_y(new_destination_reserve, d)?;
+
+        // Charge one more unit to absorb the rounding of `compute_y`, so the
+        // amount in is rounded up in the pool's favor
+        let amount_in = new_source_reserve
+            .saturating_sub(to_u256(source_reserve))
+            .checked_add(U256::one())
+            .ok_or(CalculatorError::Overflow)?;
+        to_u128(amount_in)
+    }
+
+    fn spot_price(&self, base_reserve: U128, quote_reserve: U128) -> Result<f64> {
+        if base_reserve.is_zero() || quote_reserve.is_zero() {
+            return Err(CalculatorError::InsufficientLiquidity.into());
+        }
+        let ann = to_u128(self.ann()?)?.as_u128() as f64;
+        let d = to_u128(self.compute_d(base_reserve, quote_reserve)?)?.as_u128() as f64;
+        let x = base_reserve.as_u128() as f64;
+        let y = quote_reserve.as_u128() as f64;
+
+        // Ratio of the invariant's partial derivatives
+        let k = d * d * d / (4.0 * x * y);
+        Ok((ann + k / x) / (ann + k / y))
+    }
+
+    fn invariant(&self, base_reserve: U128, quote_reserve: U128) -> Result<U256> {
+        self.compute_d(base_reserve, quote_reserve)
+    }
+}
+
+fn to_u256(value: U128) -> U256 {
+    U256::from(value.as_u128())
+}
+
+fn to_u128(value: U256) -> Result<U128> {
+    if value > U256::from(u128::MAX) {
+        return Err(CalculatorError::Overflow.into());
+    }
+    Ok(U128::from(value.as_u128()))
+}
+
+fn abs_diff(a: U256, b: U256) -> U256 {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calculator::{
+        swap_context::{FeeSchedule, Reserves, SwapContext, TaxSchedule},
+        taxation_mode::TaxationMode,
+        ConstantProduct, ProtocolSwapFeeDirection, TokenSwapCalculator,
+    };
+
+    #[test]
+    fn test_balanced_pool_has_less_slippage_than_constant_product() {
+        let reserve = U128::from(1_000_000_000_000u64);
+        let amount_in = U128::from(10_000_000_000u64);
+
+        let stable_out = StableSwap::new(100)
+            .swap_amount_out(amount_in, reserve, reserve, &SwapDirection::Base2Quote)
+            .unwrap();
+        let constant_product_out = ConstantProduct
+            .swap_amount_out(amount_in, reserve, reserve, &SwapDirection::Base2Quote)
+            .unwrap();
+
+        assert!(stable_out > constant_product_out);
+        assert!(stable_out < amount_in);
+    }
+
+    #[test]
+    fn test_swap_round_trip_favors_pool() {
+        let curve = StableSwap::new(200);
+        let base_reserve = U128::from(5_000_000_000u64);
+        let quote_reserve = U128::from(7_000_000_000u64);
+
+        for amount in [1u64, 1_000, 1_000_000, 1_000_000_000] {
+            for direction in [SwapDirection::Base2Quote, SwapDirection::Quote2Base] {
+                let amount_out = curve
+                    .swap_amount_out(amount.into(), base_reserve, quote_reserve, &direction)
+                    .unwrap();
+                if amount_out.is_zero() {
+                    continue;
+                }
+                let amount_in = curve
+                    .swap_amount_in(amount_out, base_reserve, quote_reserve, &direction)
+                    .unwrap();
+                assert!(amount_in <= U128::from(amount) + U128::from(2));
+            }
+        }
+    }
+
+    #[test]
+    fn test_spot_price_of_balanced_pool_is_one() {
+        let reserve = U128::from(1_000_000_000u64);
+        let price = StableSwap::new(100).spot_price(reserve, reserve).unwrap();
+
+        assert!((price - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_fee_pipeline_keeps_invariant() {
+        let curve = StableSwap::new(100);
+        for amount in [10u64, 12_345, 1_000_000, 999_999_999, 30_000_000_000] {
+            for swap_direction in [SwapDirection::Base2Quote, SwapDirection::Quote2Base] {
+                let context = SwapContext {
+                    swap_direction,
+                    protocol_swap_fee_direction: ProtocolSwapFeeDirection::Quote,
+                    taxation_mode: TaxationMode::Quote,
+                    reserves: Reserves::new(40_000_000_000, 60_000_000_000),
+                    fee_schedule: FeeSchedule::new(4, 10_000, 1, 10_000).unwrap(),
+                    tax_schedule: TaxSchedule::new(100, 100).unwrap(),
+                    ..Default::default()
+                };
+                TokenSwapCalculator::<StableSwap>::swap_in_with_curve(&curve, amount, &context)
+                    .unwrap();
+                TokenSwapCalculator::<StableSwap>::swap_out_with_curve(&curve, amount, &context)
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_quote_exact_in_with_stable_swap() {
+        use crate::{calculator::swap_context::TransferFees, quote::quote_exact_in_with_curve};
+
+        let context = SwapContext {
+            swap_direction: SwapDirection::Quote2Base,
+            protocol_swap_fee_direction: ProtocolSwapFeeDirection::Quote,
+            taxation_mode: TaxationMode::Quote,
+            reserves: Reserves::new(1_000_000_000_000, 1_000_000_000_000),
+            fee_schedule: FeeSchedule::new(4, 10_000, 1, 10_000).unwrap(),
+            tax_schedule: TaxSchedule::default(),
+            ..Default::default()
+        };
+        let result = quote_exact_in_with_curve(
+            &StableSwap::new(100),
+            1_000_000,
+            &context,
+            &TransferFees::default(),
+            50,
+        )
+        .unwrap();
+
+        // 0.04% swap fee + 0.01% protocol fee, almost no curve slippage
+        assert_eq!(result.swap_fee.amount, 400);
+        assert_eq!(result.protocol_swap_fee.amount, 100);
+        assert!((999_498..999_500).contains(&result.amount_out));
+        assert!(result.minimum_amount_out < result.amount_out);
+        assert!(result.prices.price_impact_bps < 0.1);
+    }
+}
@@ -0,0 +1,525 @@
+use anchor_lang::prelude::Pubkey;
+use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::{
+    TransferFee, TransferFeeConfig, MAX_FEE_BASIS_POINTS,
+};
+
+use crate::{instructions::LiquidityPoolState, TEN_THOUSAND};
+
+use super::{
+    error::CalculatorError,
+    fee_mode::FeeMode,
+    number::{CheckedAsU64, U128, U256},
+    rounding::{CheckedMulDiv, RoundDirection},
+    swap_direction::SwapDirection,
+    tax_decay::TaxDecaySchedule,
+    taxation_mode::TaxationMode,
+    ProtocolSwapFeeDirection,
+};
+
+use anyhow::Result;
+
+/// Token amounts held by the pool
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Reserves {
+    pub base: u64,
+    pub quote: u64,
+}
+
+impl Reserves {
+    pub fn new(base: u64, quote: u64) -> Self {
+        Self { base, quote }
+    }
+
+    /// Reserves once `amount_in` source tokens were added and `amount_out`
+    /// destination tokens were removed
+    pub fn after_swap(
+        &self,
+        swap_direction: SwapDirection,
+        amount_in: U128,
+        amount_out: U128,
+    ) -> Result<Self> {
+        let (source_reserve, destination_reserve) = match swap_direction {
+            SwapDirection::Base2Quote => (self.base, self.quote),
+            SwapDirection::Quote2Base => (self.quote, self.base),
+        };
+        let source_reserve = U128::from(source_reserve)
+            .checked_add(amount_in)
+            .and_then(|reserve| reserve.checked_as_u64())
+            .ok_or(CalculatorError::Overflow)?;
+        let destination_reserve = U128::from(destination_reserve)
+            .checked_sub(amount_out)
+            .and_then(|reserve| reserve.checked_as_u64())
+            .ok_or(CalculatorError::InsufficientLiquidity)?;
+        Ok(match swap_direction {
+            SwapDirection::Base2Quote => Self::new(source_reserve, destination_reserve),
+            SwapDirection::Quote2Base => Self::new(destination_reserve, source_reserve),
+        })
+    }
+}
+
+impl From<&LiquidityPoolState> for Reserves {
+    fn from(state: &LiquidityPoolState) -> Self {
+        Self {
+            base: state.base_token_vault_balance,
+            quote: state.quote_token_vault_balance,
+        }
+    }
+}
+
+/// LP swap fee and protocol swap fee, both charged as a fraction of the amount
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub swap_fee_numerator: u64,
+    pub swap_fee_denominator: u64,
+    pub protocol_swap_fee_numerator: u64,
+    pub protocol_swap_fee_denominator: u64,
+}
+
+impl FeeSchedule {
+    pub fn new(
+        swap_fee_numerator: u64,
+        swap_fee_denominator: u64,
+        protocol_swap_fee_numerator: u64,
+        protocol_swap_fee_denominator: u64,
+    ) -> Result<Self> {
+        let fee_schedule = Self {
+            swap_fee_numerator,
+            swap_fee_denominator,
+            protocol_swap_fee_numerator,
+            protocol_swap_fee_denominator,
+        };
+        fee_schedule.validate()?;
+        Ok(fee_schedule)
+    }
+
+    /// Rejects zero denominators and fees that, alone or combined, take 100%
+    /// or more of the amount.
+    pub fn validate(&self) -> Result<()> {
+        if self.swap_fee_denominator == 0 || self.protocol_swap_fee_denominator == 0 {
+            return Err(CalculatorError::InvalidFeeConfig.into());
+        }
+        // swap_fee_numerator / swap_fee_denominator
+        //   + protocol_swap_fee_numerator / protocol_swap_fee_denominator < 1
+        // (computed in 256 bits, the sum of two 128 bit products can overflow)
+        let combined_numerator = U256::from(self.swap_fee_numerator)
+            * U256::from(self.protocol_swap_fee_denominator)
+            + U256::from(self.protocol_swap_fee_numerator) * U256::from(self.swap_fee_denominator);
+        let combined_denominator =
+            U256::from(self.swap_fee_denominator) * U256::from(self.protocol_swap_fee_denominator);
+        if combined_numerator >= combined_denominator {
+            return Err(CalculatorError::InvalidFeeConfig.into());
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&LiquidityPoolState> for FeeSchedule {
+    type Error = anyhow::Error;
+
+    fn try_from(state: &LiquidityPoolState) -> Result<Self> {
+        Self::new(
+            state.swap_fee_numerator,
+            state.swap_fee_denominator,
+            state.protocol_swap_fee_numerator,
+            state.protocol_swap_fee_denominator,
+        )
+    }
+}
+
+/// Buy and sell taxes, in basis points of [`TEN_THOUSAND`], and the
+/// protocol's cut of them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TaxSchedule {
+    pub buy_tax: u64,
+    pub sell_tax: u64,
+    /// Protocol tax, as a fraction of the swap tax it is taken out of. A
+    /// zero denominator disables it, as on pools created before the protocol
+    /// tax existed.
+    pub protocol_tax_numerator: u64,
+    pub protocol_tax_denominator: u64,
+}
+
+impl TaxSchedule {
+    pub fn new(buy_tax: u64, sell_tax: u64) -> Result<Self> {
+        let tax_schedule = Self {
+            buy_tax,
+            sell_tax,
+            ..Default::default()
+        };
+        tax_schedule.validate()?;
+        Ok(tax_schedule)
+    }
+
+    pub fn with_protocol_tax(
+        self,
+        protocol_tax_numerator: u64,
+        protocol_tax_denominator: u64,
+    ) -> Result<Self> {
+        let tax_schedule = Self {
+            protocol_tax_numerator,
+            protocol_tax_denominator,
+            ..self
+        };
+        tax_schedule.validate()?;
+        Ok(tax_schedule)
+    }
+
+    /// Rejects taxes above 100% and a protocol tax larger than the swap tax
+    /// it is taken out of.
+    pub fn validate(&self) -> Result<()> {
+        if self.buy_tax > TEN_THOUSAND || self.sell_tax > TEN_THOUSAND {
+            return Err(CalculatorError::InvalidTaxConfig.into());
+        }
+        if self.protocol_tax_numerator > self.protocol_tax_denominator {
+            return Err(CalculatorError::InvalidTaxConfig.into());
+        }
+        Ok(())
+    }
+
+    /// Protocol's cut of `swap_tax`, rounded up in the protocol's favor. It
+    /// is part of the swap tax, the user pays no more for it.
+    pub fn protocol_tax(&self, swap_tax: U128) -> Result<U128> {
+        if self.protocol_tax_denominator == 0 {
+            return Ok(U128::zero());
+        }
+        Ok(swap_tax
+            .checked_mul_div(
+                self.protocol_tax_numerator.into(),
+                self.protocol_tax_denominator.into(),
+                RoundDirection::Ceiling,
+            )
+            .ok_or(CalculatorError::Overflow)?)
+    }
+}
+
+impl TryFrom<&LiquidityPoolState> for TaxSchedule {
+    type Error = anyhow::Error;
+
+    fn try_from(state: &LiquidityPoolState) -> Result<Self> {
+        Self::new(state.buy_tax, state.sell_tax)?
+            .with_protocol_tax(state.protocol_tax_numerator, state.protocol_tax_denominator)
+    }
+}
+
+/// Token-2022 transfer fees of the base and quote mints for the current epoch
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TransferFees {
+    pub base: TransferFee,
+    pub quote: TransferFee,
+}
+
+impl TransferFees {
+    pub fn new(base: TransferFee, quote: TransferFee) -> Result<Self> {
+        let transfer_fees = Self { base, quote };
+        transfer_fees.validate()?;
+        Ok(transfer_fees)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for transfer_fee in [&self.base, &self.quote] {
+            if u16::from(transfer_fee.transfer_fee_basis_points) > MAX_FEE_BASIS_POINTS {
+                return Err(CalculatorError::InvalidTransferFeeConfig.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the `(input, output)` transfer fees for a swap direction.
+    pub fn for_direction(&self, swap_direction: &SwapDirection) -> (TransferFee, TransferFee) {
+        match swap_direction {
+            SwapDirection::Base2Quote => (self.base, self.quote),
+            SwapDirection::Quote2Base => (self.quote, self.base),
+        }
+    }
+}
+
+/// Token-2022 transfer fee of a mint across epochs: the older fee, then the
+/// newer one from its epoch on
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EpochTransferFee {
+    pub older: TransferFee,
+    pub newer: TransferFee,
+}
+
+impl EpochTransferFee {
+    pub fn new(older: TransferFee, newer: TransferFee) -> Self {
+        Self { older, newer }
+    }
+
+    /// Transfer fee charged at `epoch`
+    pub fn at(&self, epoch: u64) -> TransferFee {
+        if epoch >= u64::from(self.newer.epoch) {
+            self.newer
+        } else {
+            self.older
+        }
+    }
+}
+
+impl From<&TransferFeeConfig> for EpochTransferFee {
+    fn from(config: &TransferFeeConfig) -> Self {
+        Self::new(config.older_transfer_fee, config.newer_transfer_fee)
+    }
+}
+
+/// Token-2022 transfer fees of the base and quote mints across epochs
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EpochTransferFees {
+    pub base: EpochTransferFee,
+    pub quote: EpochTransferFee,
+}
+
+impl EpochTransferFees {
+    pub fn new(base: EpochTransferFee, quote: EpochTransferFee) -> Self {
+        Self { base, quote }
+    }
+
+    /// Transfer fees charged at `epoch`
+    pub fn at(&self, epoch: u64) -> Result<TransferFees> {
+        TransferFees::new(self.base.at(epoch), self.quote.at(epoch))
+    }
+}
+
+/// Everything the calculator needs to know about a pool to price one swap
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SwapContext {
+    pub swap_direction: SwapDirection,
+    pub protocol_swap_fee_direction: ProtocolSwapFeeDirection,
+    pub taxation_mode: TaxationMode,
+    pub reserves: Reserves,
+    pub fee_schedule: FeeSchedule,
+    /// How the LP fee of `fee_schedule` is adjusted for each swap
+    pub fee_mode: FeeMode,
+    pub tax_schedule: TaxSchedule,
+    /// Pool mints, used to tag quoted amounts with their token
+    pub base_mint: Pubkey,
+    pub quote_mint: Pubkey,
+    /// Mint decimals, used to express prices in UI units
+    pub base_decimals: u8,
+    pub quote_decimals: u8,
+    /// Launch taxes replacing the flat ones of `tax_schedule`. The calculator
+    /// only charges `tax_schedule`, and rejects a context with a decay:
+    /// [`SwapContext::at`] resolves it into `tax_schedule` first.
+    pub tax_decay: Option<TaxDecaySchedule>,
+}
+
+impl SwapContext {
+    pub fn from_pool_state(
+        state: &LiquidityPoolState,
+        swap_direction: SwapDirection,
+    ) -> Result<Self> {
+        let taxation_mode = state.taxation_mode;
+        Ok(Self {
+            swap_direction,
+            protocol_swap_fee_direction: ProtocolSwapFeeDirection::from_taxation_mode(
+                &taxation_mode,
+            ),
+            taxation_mode,
+            reserves: Reserves::from(state),
+            fee_schedule: FeeSchedule::try_from(state)?,
+            fee_mode: FeeMode::Static,
+            tax_schedule: TaxSchedule::try_from(state)?,
+            base_mint: state.base_token_mint,
+            quote_mint: state.quote_token_mint,
+            base_decimals: state.base_token_mint_decimals,
+            quote_decimals: state.quote_token_mint_decimals,
+            tax_decay: None,
+        })
+    }
+
+    pub fn with_fee_mode(self, fee_mode: FeeMode) -> Result<Self> {
+        fee_mode.validate()?;
+        Ok(Self { fee_mode, ..self })
+    }
+
+    pub fn with_tax_decay(self, tax_decay: TaxDecaySchedule) -> Result<Self> {
+        tax_decay.validate()?;
+        Ok(Self {
+            tax_decay: Some(tax_decay),
+            ..self
+        })
+    }
+
+    /// Context of a swap at unix time `timestamp`, with the buy and sell taxes
+    /// of the decay schedule at that moment in `tax_schedule`.
+    pub fn at(&self, timestamp: u64) -> Result<Self> {
+        let Some(tax_decay) = self.tax_decay else {
+            return Ok(*self);
+        };
+        tax_decay.validate()?;
+        let (buy_tax, sell_tax) = tax_decay.taxes_at(timestamp)?;
+        let tax_schedule = TaxSchedule {
+            buy_tax,
+            sell_tax,
+            ..self.tax_schedule
+        };
+        tax_schedule.validate()?;
+        Ok(Self {
+            tax_schedule,
+            tax_decay: None,
+            ..*self
+        })
+    }
+
+    pub fn input_mint(&self) -> Pubkey {
+        match self.swap_direction {
+            SwapDirection::Base2Quote => self.base_mint,
+            SwapDirection::Quote2Base => self.quote_mint,
+        }
+    }
+
+    pub fn output_mint(&self) -> Pubkey {
+        match self.swap_direction {
+            SwapDirection::Base2Quote => self.quote_mint,
+            SwapDirection::Quote2Base => self.base_mint,
+        }
+    }
+
+    /// Mint the swap tax and protocol tax are charged in
+    pub fn tax_mint(&self) -> Pubkey {
+        match self.taxation_mode {
+            TaxationMode::Base => self.base_mint,
+            TaxationMode::Quote => self.quote_mint,
+            TaxationMode::None => self.input_mint(),
+        }
+    }
+
+    /// Mint the protocol swap fee is charged in
+    pub fn protocol_swap_fee_mint(&self) -> Pubkey {
+        match self.protocol_swap_fee_direction {
+            ProtocolSwapFeeDirection::Base => self.base_mint,
+            ProtocolSwapFeeDirection::Quote => self.quote_mint,
+            ProtocolSwapFeeDirection::None => self.input_mint(),
+        }
+    }
+
+    /// Rejects a context whose decaying taxes were not resolved with
+    /// [`Self::at`], which the calculator would otherwise leave uncharged.
+    pub fn checked_taxes_resolved(&self) -> Result<()> {
+        if self.tax_decay.is_some() {
+            return Err(CalculatorError::UnresolvedTaxDecay.into());
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.fee_schedule.validate()?;
+        self.fee_mode.validate()?;
+        self.tax_schedule.validate()?;
+        if let Some(tax_decay) = &self.tax_decay {
+            tax_decay.validate()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fee_schedule_validation() {
+        assert!(FeeSchedule::new(25, 10_000, 1, 1_000).is_ok());
+        assert!(FeeSchedule::new(25, 0, 1, 1_000).is_err());
+        assert!(FeeSchedule::new(25, 10_000, 1, 0).is_err());
+        assert!(FeeSchedule::new(10_000, 10_000, 0, 1).is_err());
+        assert!(FeeSchedule::new(1, 2, 1, 2).is_err());
+    }
+
+    #[test]
+    fn test_tax_schedule_validation() {
+        assert!(TaxSchedule::new(TEN_THOUSAND, 0).is_ok());
+        assert!(TaxSchedule::new(TEN_THOUSAND + 1, 0).is_err());
+        assert!(TaxSchedule::new(0, TEN_THOUSAND + 1).is_err());
+
+        let tax_schedule = TaxSchedule::new(300, 300).unwrap();
+        assert!(tax_schedule.with_protocol_tax(0, 0).is_ok());
+        assert!(tax_schedule.with_protocol_tax(1, 10).is_ok());
+        assert!(tax_schedule.with_protocol_tax(1, 0).is_err());
+        assert!(tax_schedule.with_protocol_tax(11, 10).is_err());
+    }
+
+    #[test]
+    fn test_protocol_tax() {
+        let tax_schedule = TaxSchedule::new(300, 300).unwrap();
+        assert_eq!(
+            tax_schedule.protocol_tax(U128::from(1_000)).unwrap(),
+            U128::zero()
+        );
+
+        let tax_schedule = tax_schedule.with_protocol_tax(1, 3).unwrap();
+        // 1_000 / 3, rounded up
+        assert_eq!(
+            tax_schedule.protocol_tax(U128::from(1_000)).unwrap(),
+            U128::from(334)
+        );
+    }
+
+    #[test]
+    fn test_swap_context_from_pool_state() {
+        let state = LiquidityPoolState {
+            base_token_vault_balance: 1_000,
+            quote_token_vault_balance: 2_000,
+            swap_fee_numerator: 25,
+            swap_fee_denominator: 10_000,
+            protocol_swap_fee_numerator: 1,
+            protocol_swap_fee_denominator: 1_000,
+            buy_tax: 300,
+            sell_tax: 500,
+            protocol_tax_numerator: 1,
+            protocol_tax_denominator: 10,
+            taxation_mode: TaxationMode::Quote,
+            ..Default::default()
+        };
+        let context = SwapContext::from_pool_state(&state, SwapDirection::Base2Quote).unwrap();
+
+        assert_eq!(context.reserves, Reserves::new(1_000, 2_000));
+        assert_eq!(
+            context.tax_schedule,
+            TaxSchedule {
+                buy_tax: 300,
+                sell_tax: 500,
+                protocol_tax_numerator: 1,
+                protocol_tax_denominator: 10,
+            }
+        );
+        assert_eq!(
+            context.protocol_swap_fee_direction,
+            ProtocolSwapFeeDirection::Quote
+        );
+
+        let state = LiquidityPoolState {
+            swap_fee_denominator: 0,
+            ..state
+        };
+        assert!(SwapContext::from_pool_state(&state, SwapDirection::Base2Quote).is_err());
+    }
+
+    #[test]
+    fn test_transfer_fees_at_epoch() {
+        let transfer_fee = |epoch: u64, transfer_fee_basis_points: u16| TransferFee {
+            epoch: epoch.into(),
+            maximum_fee: u64::MAX.into(),
+            transfer_fee_basis_points: transfer_fee_basis_points.into(),
+        };
+        let transfer_fees = EpochTransferFees::new(
+            EpochTransferFee::new(transfer_fee(0, 100), transfer_fee(10, 200)),
+            EpochTransferFee::default(),
+        );
+
+        assert_eq!(transfer_fees.at(9).unwrap().base, transfer_fee(0, 100));
+        assert_eq!(transfer_fees.at(10).unwrap().base, transfer_fee(10, 200));
+        assert_eq!(
+            transfer_fees.at(u64::MAX).unwrap().base,
+            transfer_fee(10, 200)
+        );
+        assert_eq!(transfer_fees.at(0).unwrap().quote, TransferFee::default());
+
+        let invalid = EpochTransferFees::new(
+            EpochTransferFee::new(transfer_fee(0, 100), transfer_fee(10, 10_001)),
+            EpochTransferFee::default(),
+        );
+        assert!(invalid.at(9).is_ok());
+        assert!(invalid.at(10).is_err());
+    }
+}
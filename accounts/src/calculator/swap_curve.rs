@@ -0,0 +1,57 @@
+use anyhow::Result;
+
+use super::{
+    error::CalculatorError,
+    number::{U128, U256},
+    swap_direction::SwapDirection,
+};
+
+/// Reserve math of a pricing curve.
+///
+/// Amounts passed to and returned from a curve are always net of swap fees,
+/// swap taxes and protocol fees; those are applied by
+/// [`TokenSwapCalculator`](super::TokenSwapCalculator) on top of any curve.
+pub trait SwapCurve {
+    /// Amount of destination tokens released by the reserves in exchange for
+    /// `amount_in` source tokens. Rounded down.
+    fn swap_amount_out(
+        &self,
+        amount_in: U128,
+        base_reserve: U128,
+        quote_reserve: U128,
+        swap_direction: &SwapDirection,
+    ) -> Result<U128>;
+
+    /// Amount of source tokens the reserves require in order to release
+    /// `amount_out` destination tokens. Rounded up.
+    fn swap_amount_in(
+        &self,
+        amount_out: U128,
+        base_reserve: U128,
+        quote_reserve: U128,
+        swap_direction: &SwapDirection,
+    ) -> Result<U128>;
+
+    /// Marginal price of one base token, expressed in quote tokens (raw units).
+    fn spot_price(&self, base_reserve: U128, quote_reserve: U128) -> Result<f64>;
+
+    /// Value of the curve invariant for the given reserves.
+    fn invariant(&self, base_reserve: U128, quote_reserve: U128) -> Result<U256>;
+
+    /// Ensures that moving from the reserves before a swap to the reserves
+    /// after it did not decrease the invariant, i.e. the pool was not drained.
+    fn check_invariant(
+        &self,
+        base_reserve_before: U128,
+        quote_reserve_before: U128,
+        base_reserve_after: U128,
+        quote_reserve_after: U128,
+    ) -> Result<()> {
+        let before = self.invariant(base_reserve_before, quote_reserve_before)?;
+        let after = self.invariant(base_reserve_after, quote_reserve_after)?;
+        if after < before {
+            return Err(CalculatorError::InvariantViolation.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+//! Launch taxes that start high when a pool opens and decay to a floor, to
+//! deter snipers. Taxes are in basis points of [`TEN_THOUSAND`], timestamps
+//! and durations in seconds.
+//!
+//! Decayed taxes are rounded up, in the pool's favor, like every other fee.
+
+use anyhow::Result;
+
+use super::error::CalculatorError;
+use crate::TEN_THOUSAND;
+
+/// Shape of the decay from the starting tax to the floor
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TaxDecay {
+    /// The tax stays at its starting value
+    #[default]
+    None,
+    /// Falls at a constant rate, reaching the floor after `duration`
+    Linear { duration: u64 },
+    /// Falls by equal steps every `step_duration`, reaching the floor after
+    /// `steps` of them
+    Stepwise { step_duration: u64, steps: u64 },
+    /// The part above the floor halves every `half_life`, linearly in
+    /// between. It reaches the floor once it falls below one basis point.
+    Exponential { half_life: u64 },
+}
+
+/// A buy or sell tax decaying from `start` to `floor`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecayingTax {
+    pub start: u64,
+    pub floor: u64,
+    pub decay: TaxDecay,
+}
+
+impl DecayingTax {
+    pub fn new(start: u64, floor: u64, decay: TaxDecay) -> Result<Self> {
+        let tax = Self {
+            start,
+            floor,
+            decay,
+        };
+        tax.validate()?;
+        Ok(tax)
+    }
+
+    /// A tax that does not decay
+    pub fn flat(tax: u64) -> Self {
+        Self {
+            start: tax,
+            floor: tax,
+            decay: TaxDecay::None,
+        }
+    }
+
+    /// Rejects taxes above 100%, a floor above the start and zero durations.
+    pub fn validate(&self) -> Result<()> {
+        if self.start > TEN_THOUSAND || self.floor > self.start {
+            return Err(CalculatorError::InvalidTaxConfig.into());
+        }
+        let has_zero_duration = match self.decay {
+            TaxDecay::None => false,
+            TaxDecay::Linear { duration } => duration == 0,
+            TaxDecay::Stepwise {
+                step_duration,
+                steps,
+            } => step_duration == 0 || steps == 0,
+            TaxDecay::Exponential { half_life } => half_life == 0,
+        };
+        if has_zero_duration {
+            return Err(CalculatorError::InvalidTaxConfig.into());
+        }
+        Ok(())
+    }
+
+    /// Tax `elapsed` seconds after the pool opened. Fails on a zero
+    /// duration, which [`Self::validate`] rejects.
+    pub fn at(&self, elapsed: u64) -> Result<u64> {
+        let range = self.start.saturating_sub(self.floor);
+        let remaining = match self.decay {
+            TaxDecay::None => range,
+            TaxDecay::Linear { duration } => {
+                mul_div_ceil(range, duration.saturating_sub(elapsed), duration)?
+            }
+            TaxDecay::Stepwise {
+                step_duration,
+                steps,
+            } => {
+                let steps_elapsed = elapsed
+                    .checked_div(step_duration)
+                    .ok_or(CalculatorError::InvalidTaxConfig)?;
+                mul_div_ceil(range, steps.saturating_sub(steps_elapsed), steps)?
+            }
+            TaxDecay::Exponential { half_life } => {
+                let halvings = elapsed
+                    .checked_div(half_life)
+                    .ok_or(CalculatorError::InvalidTaxConfig)?;
+                if halvings >= u64::BITS as u64 {
+                    0
+                } else {
+                    let high = range >> halvings;
+                    let low = high >> 1;
+                    let into_half_life = elapsed % half_life;
+                    low + mul_div_ceil(high - low, half_life - into_half_life, half_life)?
+                }
+            }
+        };
+        // `remaining` is at most `start - floor`
+        Ok(self.floor + remaining)
+    }
+}
+
+/// `value * numerator / denominator` rounded up, for `numerator <= denominator`
+fn mul_div_ceil(value: u64, numerator: u64, denominator: u64) -> Result<u64> {
+    if denominator == 0 {
+        return Err(CalculatorError::InvalidTaxConfig.into());
+    }
+    Ok((value as u128 * numerator as u128).div_ceil(denominator as u128) as u64)
+}
+
+/// Buy and sell taxes decaying from `open_at`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TaxDecaySchedule {
+    /// Unix timestamp the decay starts from, usually the pool's `open_at`
+    pub open_at: u64,
+    pub buy_tax: DecayingTax,
+    pub sell_tax: DecayingTax,
+}
+
+impl TaxDecaySchedule {
+    pub fn new(open_at: u64, buy_tax: DecayingTax, sell_tax: DecayingTax) -> Result<Self> {
+        let schedule = Self {
+            open_at,
+            buy_tax,
+            sell_tax,
+        };
+        schedule.validate()?;
+        Ok(schedule)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.buy_tax.validate()?;
+        self.sell_tax.validate()
+    }
+
+    /// `(buy_tax, sell_tax)` at unix time `timestamp`. Before `open_at` the
+    /// starting taxes apply.
+    pub fn taxes_at(&self, timestamp: u64) -> Result<(u64, u64)> {
+        let elapsed = timestamp.saturating_sub(self.open_at);
+        Ok((self.buy_tax.at(elapsed)?, self.sell_tax.at(elapsed)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_linear_decay() {
+        let tax = DecayingTax::new(3_000, 500, TaxDecay::Linear { duration: 600 }).unwrap();
+
+        assert_eq!(tax.at(0).unwrap(), 3_000);
+        assert_eq!(tax.at(300).unwrap(), 1_750);
+        // 2_500 * 599 / 600, rounded up
+        assert_eq!(tax.at(1).unwrap(), 2_996);
+        assert_eq!(tax.at(599).unwrap(), 505);
+        assert_eq!(tax.at(600).unwrap(), 500);
+        assert_eq!(tax.at(u64::MAX).unwrap(), 500);
+    }
+
+    #[test]
+    fn test_stepwise_decay() {
+        let tax = DecayingTax::new(
+            2_000,
+            0,
+            TaxDecay::Stepwise {
+                step_duration: 60,
+                steps: 4,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(tax.at(0).unwrap(), 2_000);
+        assert_eq!(tax.at(59).unwrap(), 2_000);
+        assert_eq!(tax.at(60).unwrap(), 1_500);
+        assert_eq!(tax.at(150).unwrap(), 1_000);
+        assert_eq!(tax.at(239).unwrap(), 500);
+        assert_eq!(tax.at(240).unwrap(), 0);
+        assert_eq!(tax.at(u64::MAX).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_exponential_decay() {
+        let tax = DecayingTax::new(4_100, 100, TaxDecay::Exponential { half_life: 100 }).unwrap();
+
+        assert_eq!(tax.at(0).unwrap(), 4_100);
+        assert_eq!(tax.at(50).unwrap(), 3_100);
+        assert_eq!(tax.at(100).unwrap(), 2_100);
+        assert_eq!(tax.at(200).unwrap(), 1_100);
+        // 4_000 falls below one basis point after 12 halvings
+        assert_eq!(tax.at(1_199).unwrap(), 101);
+        assert_eq!(tax.at(1_200).unwrap(), 100);
+        assert_eq!(tax.at(u64::MAX).unwrap(), 100);
+        // Never increases
+        let mut previous = tax.at(0).unwrap();
+        for elapsed in 1..1_300 {
+            assert!(tax.at(elapsed).unwrap() <= previous);
+            previous = tax.at(elapsed).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_schedule_is_keyed_off_open_at() {
+        let schedule = TaxDecaySchedule::new(
+            1_000,
+            DecayingTax::new(5_000, 100, TaxDecay::Linear { duration: 100 }).unwrap(),
+            DecayingTax::flat(300),
+        )
+        .unwrap();
+
+        assert_eq!(schedule.taxes_at(0).unwrap(), (5_000, 300));
+        assert_eq!(schedule.taxes_at(1_000).unwrap(), (5_000, 300));
+        assert_eq!(schedule.taxes_at(1_050).unwrap(), (2_550, 300));
+        assert_eq!(schedule.taxes_at(1_100).unwrap(), (100, 300));
+    }
+
+    #[test]
+    fn test_decaying_tax_validation() {
+        assert!(DecayingTax::new(TEN_THOUSAND + 1, 0, TaxDecay::None).is_err());
+        assert!(DecayingTax::new(100, 200, TaxDecay::None).is_err());
+        assert!(DecayingTax::new(200, 100, TaxDecay::Linear { duration: 0 }).is_err());
+        assert!(DecayingTax::new(
+            200,
+            100,
+            TaxDecay::Stepwise {
+                step_duration: 10,
+                steps: 0
+            }
+        )
+        .is_err());
+        assert!(DecayingTax::new(200, 100, TaxDecay::Exponential { half_life: 0 }).is_err());
+    }
+
+    #[test]
+    fn test_zero_duration_is_an_error_rather_than_a_panic() {
+        for decay in [
+            TaxDecay::Linear { duration: 0 },
+            TaxDecay::Stepwise {
+                step_duration: 0,
+                steps: 4,
+            },
+            TaxDecay::Stepwise {
+                step_duration: 60,
+                steps: 0,
+            },
+            TaxDecay::Exponential { half_life: 0 },
+        ] {
+            let tax = DecayingTax {
+                start: 2_000,
+                floor: 100,
+                decay,
+            };
+            assert_eq!(
+                tax.at(30).unwrap_err().downcast_ref::<CalculatorError>(),
+                Some(&CalculatorError::InvalidTaxConfig)
+            );
+        }
+    }
+}
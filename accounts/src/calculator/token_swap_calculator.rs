@@ -0,0 +1,465 @@
+#[cfg(feature = "debug")]
+use solana_program::msg;
+
+use crate::TEN_THOUSAND;
+
+use super::{
+    error::CalculatorError,
+    fee_mode::FeeMode,
+    number::U128,
+    rounding::{CheckedMulDiv, RoundDirection},
+    swap_context::SwapContext,
+    swap_curve::SwapCurve,
+    swap_direction::SwapDirection,
+    taxation_mode::TaxationMode,
+    ProtocolSwapFeeDirection, SwapInCalculationResult, SwapOutCalculationResult,
+    TokenSwapCalculator,
+};
+
+use anyhow::Result;
+
+impl<Curve: SwapCurve> TokenSwapCalculator<Curve> {
+    /// Applies the swap fee, swap tax and protocol swap fee around `curve`
+    /// for an exact input amount, and splits the protocol's cut off the swap
+    /// tax.
+    pub fn swap_in_with_curve(
+        curve: &Curve,
+        amount_in: u64,
+        context: &SwapContext,
+    ) -> Result<SwapInCalculationResult> {
+        context.validate()?;
+        context.checked_taxes_resolved()?;
+        if context.fee_mode != FeeMode::Static {
+            let static_context = SwapContext {
+                fee_schedule: context
+                    .fee_mode
+                    .fee_schedule(&context.fee_schedule, amount_in)?,
+                fee_mode: FeeMode::Static,
+                ..*context
+            };
+            return Self::swap_in_with_curve(curve, amount_in, &static_context);
+        }
+        let SwapContext {
+            swap_direction,
+            protocol_swap_fee_direction,
+            taxation_mode,
+            reserves,
+            fee_schedule,
+            tax_schedule,
+            ..
+        } = context;
+
+        let swap_amount_in_before_fees = U128::from(amount_in);
+
+        // Every fee and tax is rounded up, in the pool's favor
+        let swap_fee = swap_amount_in_before_fees
+            .checked_mul_div(
+                fee_schedule.swap_fee_numerator.into(),
+                fee_schedule.swap_fee_denominator.into(),
+                RoundDirection::Ceiling,
+            )
+            .ok_or(CalculatorError::Overflow)?;
+        let mut swap_tax_on_input_amount = U128::zero();
+        let mut swap_tax_on_output_amount = U128::zero();
+        let mut protocol_swap_fee_on_input_amount = U128::zero();
+        let mut protocol_swap_fee_on_output_amount = U128::zero();
+
+        match (taxation_mode, swap_direction) {
+            // If `Base` is the stable/native coin and `Quote` is the custom coin,
+            // then swapping base for quote is considered buying
+            (TaxationMode::Base, SwapDirection::Base2Quote)
+            // If `Quote` is the stable/native coin and `Base` is the custom coin,
+            // then swapping `Quote` for `Base` is considered buying
+            | (TaxationMode::Quote, SwapDirection::Quote2Base) => {
+                swap_tax_on_input_amount = swap_amount_in_before_fees
+                    .checked_mul_div(
+                        tax_schedule.buy_tax.into(),
+                        TEN_THOUSAND.into(),
+                        RoundDirection::Ceiling,
+                    )
+                    .ok_or(CalculatorError::Overflow)?;
+            }
+            _ => {}
+        };
+
+        match (protocol_swap_fee_direction, swap_direction) {
+            (ProtocolSwapFeeDirection::Base, SwapDirection::Base2Quote)
+            | (ProtocolSwapFeeDirection::Quote, SwapDirection::Quote2Base) => {
+                protocol_swap_fee_on_input_amount = swap_amount_in_before_fees
+                    .checked_mul_div(
+                        fee_schedule.protocol_swap_fee_numerator.into(),
+                        fee_schedule.protocol_swap_fee_denominator.into(),
+                        RoundDirection::Ceiling,
+                    )
+                    .ok_or(CalculatorError::Overflow)?;
+            }
+            _ => {}
+        };
+
+        let protocol_tax_on_input_amount = tax_schedule.protocol_tax(swap_tax_on_input_amount)?;
+
+        let swap_amount_in_after_fees = swap_amount_in_before_fees
+            .checked_sub(swap_fee)
+            .ok_or(CalculatorError::FeeExceedsInput)?
+            .checked_sub(swap_tax_on_input_amount)
+            .ok_or(CalculatorError::FeeExceedsInput)?
+            .checked_sub(protocol_swap_fee_on_input_amount)
+            .ok_or(CalculatorError::FeeExceedsInput)?;
+
+        let base_reserve = U128::from(reserves.base);
+        let quote_reserve = U128::from(reserves.quote);
+        // The curve rounds the amount out down, in the pool's favor
+        let swap_amount_out_before_fees = curve.swap_amount_out(
+            swap_amount_in_after_fees,
+            base_reserve,
+            quote_reserve,
+            swap_direction,
+        )?;
+        Self::check_swap_invariant(
+            curve,
+            swap_direction,
+            base_reserve,
+            quote_reserve,
+            swap_amount_in_after_fees,
+            swap_amount_out_before_fees,
+        )?;
+
+        match (taxation_mode, swap_direction) {
+            // If `Base` is the stable/native coin and `Quote` is the custom coin,
+            // then swapping `Quote` for `Base` is considered selling
+            (TaxationMode::Base, SwapDirection::Quote2Base)
+            // If `Quote` is the stable/native coin and `Base` is the custom coin,
+            // then swapping `Base` for `Quote` is considered selling
+            | (TaxationMode::Quote, SwapDirection::Base2Quote) => {
+                swap_tax_on_output_amount = swap_amount_out_before_fees
+                    .checked_mul_div(
+                        tax_schedule.sell_tax.into(),
+                        TEN_THOUSAND.into(),
+                        RoundDirection::Ceiling,
+                    )
+                    .ok_or(CalculatorError::Overflow)?;
+            }
+            _ => {}
+        };
+
+        match (protocol_swap_fee_direction, swap_direction) {
+            (ProtocolSwapFeeDirection::Base, SwapDirection::Quote2Base)
+            | (ProtocolSwapFeeDirection::Quote, SwapDirection::Base2Quote) => {
+                protocol_swap_fee_on_output_amount = swap_amount_out_before_fees
+                    .checked_mul_div(
+                        fee_schedule.protocol_swap_fee_numerator.into(),
+                        fee_schedule.protocol_swap_fee_denominator.into(),
+                        RoundDirection::Ceiling,
+                    )
+                    .ok_or(CalculatorError::Overflow)?;
+            }
+            _ => {}
+        };
+
+        let protocol_tax_on_output_amount = tax_schedule.protocol_tax(swap_tax_on_output_amount)?;
+
+        let swap_amount_out_after_fees = swap_amount_out_before_fees
+            .checked_sub(swap_tax_on_output_amount)
+            .ok_or(CalculatorError::FeeExceedsInput)?
+            .checked_sub(protocol_swap_fee_on_output_amount)
+            .ok_or(CalculatorError::FeeExceedsInput)?;
+        if swap_amount_out_after_fees.is_zero() {
+            return Err(CalculatorError::ZeroOutput.into());
+        }
+
+        Ok(SwapInCalculationResult {
+            swap_amount_in_before_fees,
+            swap_amount_in_after_fees,
+            swap_amount_out_after_fees,
+            swap_amount_out_before_fees,
+            swap_fee,
+            swap_tax_on_input_amount,
+            swap_tax_on_output_amount,
+            protocol_swap_fee_on_input_amount,
+            protocol_swap_fee_on_output_amount,
+            protocol_tax_on_input_amount,
+            protocol_tax_on_output_amount,
+        })
+    }
+
+    /// Exact output swap under a fee mode whose fee depends on the input
+    /// amount. Each of its fee schedules is tried, and the one that applies
+    /// to the input amount it leads to is charged. Where a tier boundary
+    /// leaves no such schedule, or several, the largest candidate input
+    /// amount is charged.
+    fn swap_out_with_fee_mode(
+        curve: &Curve,
+        amount_out: u64,
+        context: &SwapContext,
+    ) -> Result<SwapOutCalculationResult> {
+        let mut consistent: Option<SwapOutCalculationResult> = None;
+        let mut inconsistent: Option<SwapOutCalculationResult> = None;
+        let mut last_error = None;
+        for (amounts_in, fee_schedule) in context.fee_mode.fee_schedules(&context.fee_schedule)? {
+            let static_context = SwapContext {
+                fee_schedule,
+                fee_mode: FeeMode::Static,
+                ..*context
+            };
+            let result = match Self::swap_out_with_curve(curve, amount_out, &static_context) {
+                Ok(result) => result,
+                Err(error) => {
+                    last_error = Some(error);
+                    continue;
+                }
+            };
+            let is_consistent = result.swap_amount_in_after_fees <= U128::from(u64::MAX)
+                && amounts_in.contains(&result.swap_amount_in_after_fees.as_u64());
+            let best = if is_consistent {
+                &mut consistent
+            } else {
+                &mut inconsistent
+            };
+            let is_larger = match best {
+                Some(best) => result.swap_amount_in_after_fees > best.swap_amount_in_after_fees,
+                None => true,
+            };
+            if is_larger {
+                *best = Some(result);
+            }
+        }
+        consistent
+            .or(inconsistent)
+            .ok_or_else(|| last_error.unwrap_or_else(|| CalculatorError::InvalidFeeMode.into()))
+    }
+
+    /// Applies the swap fee, swap tax and protocol swap fee around `curve`
+    /// for an exact output amount, and splits the protocol's cut off the swap
+    /// tax.
+    pub fn swap_out_with_curve(
+        curve: &Curve,
+        amount_out: u64,
+        context: &SwapContext,
+    ) -> Result<SwapOutCalculationResult> {
+        context.validate()?;
+        context.checked_taxes_resolved()?;
+        if context.fee_mode != FeeMode::Static {
+            return Self::swap_out_with_fee_mode(curve, amount_out, context);
+        }
+        let SwapContext {
+            swap_direction,
+            protocol_swap_fee_direction,
+            taxation_mode,
+            reserves,
+            fee_schedule,
+            tax_schedule,
+            ..
+        } = context;
+
+        let swap_amount_out_before_fees = U128::from(amount_out);
+        let total_base_token_amount = U128::from(reserves.base);
+        let total_quote_token_amount = U128::from(reserves.quote);
+
+        #[cfg(feature = "debug")]
+        msg!(
+            "swap_amount_out_before_fees: {}, total_base_token_amount: {}, total_quote_token_amount: {}",
+            swap_amount_out_before_fees,
+            total_base_token_amount,
+            total_quote_token_amount
+        );
+
+        // Base on the directions apply external fee on the amount out.
+        // Every fee and tax is rounded up, in the pool's favor
+        let protocol_swap_fee_on_output_amount = match (protocol_swap_fee_direction, swap_direction)
+        {
+            (ProtocolSwapFeeDirection::Quote, SwapDirection::Base2Quote)
+            | (ProtocolSwapFeeDirection::Base, SwapDirection::Quote2Base) => {
+                swap_amount_out_before_fees
+                    .checked_mul_div(
+                        fee_schedule.protocol_swap_fee_numerator.into(),
+                        fee_schedule.protocol_swap_fee_denominator.into(),
+                        RoundDirection::Ceiling,
+                    )
+                    .ok_or(CalculatorError::Overflow)?
+            }
+            _ => U128::zero(),
+        };
+
+        #[cfg(feature = "debug")]
+        msg!(
+            "protocol_swap_fee_on_output_amount: {}",
+            protocol_swap_fee_on_output_amount
+        );
+
+        let swap_tax_on_output_amount = match (taxation_mode, swap_direction) {
+            // If `Base` is the stable/native coin and `Quote` is the custom coin,
+            // then swapping `Quote` for `Base` is considered selling
+            (TaxationMode::Base, SwapDirection::Quote2Base)
+            // If `Quote` is the stable/native coin and `Base` is the custom coin,
+            // then swapping `Base` for `Quote` is considered selling
+            | (TaxationMode::Quote, SwapDirection::Base2Quote) => {
+                swap_amount_out_before_fees
+                    .checked_mul_div(
+                        tax_schedule.sell_tax.into(),
+                        TEN_THOUSAND.into(),
+                        RoundDirection::Ceiling,
+                    )
+                    .ok_or(CalculatorError::Overflow)?
+            }
+            _ => U128::zero(),
+        };
+
+        #[cfg(feature = "debug")]
+        msg!("swap_tax_on_output_amount: {}", swap_tax_on_output_amount);
+
+        let protocol_tax_on_output_amount = tax_schedule.protocol_tax(swap_tax_on_output_amount)?;
+
+        #[cfg(feature = "debug")]
+        msg!(
+            "protocol_tax_on_output_amount: {}",
+            protocol_tax_on_output_amount
+        );
+
+        let swap_amount_out_after_fees = swap_amount_out_before_fees
+            .checked_add(protocol_swap_fee_on_output_amount)
+            .ok_or(CalculatorError::Overflow)?
+            .checked_add(swap_tax_on_output_amount)
+            .ok_or(CalculatorError::Overflow)?;
+
+        #[cfg(feature = "debug")]
+        msg!("swap_amount_out_after_fees: {}", swap_amount_out_after_fees);
+
+        // The curve rounds the amount in up, in the pool's favor
+        let swap_amount_in_before_fees = curve.swap_amount_in(
+            swap_amount_out_after_fees,
+            total_base_token_amount,
+            total_quote_token_amount,
+            swap_direction,
+        )?;
+        Self::check_swap_invariant(
+            curve,
+            swap_direction,
+            total_base_token_amount,
+            total_quote_token_amount,
+            swap_amount_in_before_fees,
+            swap_amount_out_after_fees,
+        )?;
+
+        #[cfg(feature = "debug")]
+        msg!("swap_amount_in_before_fees: {}", swap_amount_in_before_fees);
+
+        let swap_fee = swap_amount_in_before_fees
+            .checked_mul_div(
+                fee_schedule.swap_fee_numerator.into(),
+                fee_schedule.swap_fee_denominator.into(),
+                RoundDirection::Ceiling,
+            )
+            .ok_or(CalculatorError::Overflow)?;
+
+        #[cfg(feature = "debug")]
+        msg!("swap_fee: {}", swap_fee);
+
+        // Base on the directions apply external fee on the amount in
+        let protocol_swap_fee_on_input_amount = match (protocol_swap_fee_direction, swap_direction)
+        {
+            (ProtocolSwapFeeDirection::Base, SwapDirection::Base2Quote)
+            | (ProtocolSwapFeeDirection::Quote, SwapDirection::Quote2Base) => {
+                swap_amount_in_before_fees
+                    .checked_mul_div(
+                        fee_schedule.protocol_swap_fee_numerator.into(),
+                        fee_schedule.protocol_swap_fee_denominator.into(),
+                        RoundDirection::Ceiling,
+                    )
+                    .ok_or(CalculatorError::Overflow)?
+            }
+            _ => U128::zero(),
+        };
+
+        #[cfg(feature = "debug")]
+        msg!(
+            "protocol_swap_fee_on_input_amount: {}",
+            protocol_swap_fee_on_input_amount
+        );
+
+        let swap_tax_on_input_amount = match (taxation_mode, swap_direction) {
+            // If `Quote` is the stable/native coin and `Base` is the custom coin,
+            // then swapping `Quote` for `Base` is considered buying
+            (TaxationMode::Quote, SwapDirection::Quote2Base)
+            // If `Base` is the stable/native coin and `Quote` is the custom coin,
+            // then swapping `Base` for `Quote` is considered buying
+            | (TaxationMode::Base, SwapDirection::Base2Quote) => {
+                swap_amount_in_before_fees
+                    .checked_mul_div(
+                        tax_schedule.buy_tax.into(),
+                        TEN_THOUSAND.into(),
+                        RoundDirection::Ceiling,
+                    )
+                    .ok_or(CalculatorError::Overflow)?
+            }
+            _ => U128::zero(),
+        };
+
+        #[cfg(feature = "debug")]
+        msg!("swap_tax_on_input_amount: {}", swap_tax_on_input_amount);
+
+        let protocol_tax_on_input_amount = tax_schedule.protocol_tax(swap_tax_on_input_amount)?;
+
+        #[cfg(feature = "debug")]
+        msg!(
+            "protocol_tax_on_input_amount: {}",
+            protocol_tax_on_input_amount
+        );
+
+        let swap_amount_in_after_fees = swap_amount_in_before_fees
+            .checked_add(protocol_swap_fee_on_input_amount)
+            .ok_or(CalculatorError::Overflow)?
+            .checked_add(swap_tax_on_input_amount)
+            .ok_or(CalculatorError::Overflow)?
+            .checked_add(swap_fee)
+            .ok_or(CalculatorError::Overflow)?;
+
+        #[cfg(feature = "debug")]
+        msg!("swap_amount_in_after_fees: {}", swap_amount_in_after_fees);
+
+        Ok(SwapOutCalculationResult {
+            swap_amount_in_after_fees,
+            swap_amount_out_after_fees,
+            swap_amount_in_before_fees,
+            swap_amount_out_before_fees,
+            swap_fee,
+            swap_tax_on_input_amount,
+            swap_tax_on_output_amount,
+            protocol_swap_fee_on_input_amount,
+            protocol_swap_fee_on_output_amount,
+            protocol_tax_on_input_amount,
+            protocol_tax_on_output_amount,
+        })
+    }
+
+    /// Checks the curve invariant for reserves moving by `curve_amount_in`
+    /// on the source side and `curve_amount_out` on the destination side.
+    fn check_swap_invariant(
+        curve: &Curve,
+        swap_direction: &SwapDirection,
+        base_reserve: U128,
+        quote_reserve: U128,
+        curve_amount_in: U128,
+        curve_amount_out: U128,
+    ) -> Result<()> {
+        let (source_reserve, destination_reserve) = match swap_direction {
+            SwapDirection::Base2Quote => (base_reserve, quote_reserve),
+            SwapDirection::Quote2Base => (quote_reserve, base_reserve),
+        };
+        let source_reserve_after = source_reserve
+            .checked_add(curve_amount_in)
+            .ok_or(CalculatorError::Overflow)?;
+        let destination_reserve_after = destination_reserve
+            .checked_sub(curve_amount_out)
+            .ok_or(CalculatorError::InsufficientLiquidity)?;
+        let (base_reserve_after, quote_reserve_after) = match swap_direction {
+            SwapDirection::Base2Quote => (source_reserve_after, destination_reserve_after),
+            SwapDirection::Quote2Base => (destination_reserve_after, source_reserve_after),
+        };
+        curve.check_invariant(
+            base_reserve,
+            quote_reserve,
+            base_reserve_after,
+            quote_reserve_after,
+        )
+    }
+}
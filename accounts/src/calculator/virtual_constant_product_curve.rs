@@ -0,0 +1,191 @@
+use super::{
+    error::CalculatorError,
+    number::{U128, U256},
+    swap_curve::SwapCurve,
+    swap_direction::SwapDirection,
+    ConstantProduct, VirtualConstantProduct,
+};
+
+use anyhow::Result;
+
+impl VirtualConstantProduct {
+    pub fn new(
+        virtual_base_reserve: u64,
+        virtual_quote_reserve: u64,
+        completion_quote_reserve: u64,
+    ) -> Self {
+        Self {
+            virtual_base_reserve,
+            virtual_quote_reserve,
+            completion_quote_reserve,
+        }
+    }
+
+    /// Reserves the curve prices against, given the real reserves.
+    pub fn effective_reserves(
+        &self,
+        base_reserve: U128,
+        quote_reserve: U128,
+    ) -> Result<(U128, U128)> {
+        let effective_base_reserve = base_reserve
+            .checked_add(self.virtual_base_reserve.into())
+            .ok_or(CalculatorError::Overflow)?;
+        let effective_quote_reserve = quote_reserve
+            .checked_add(self.virtual_quote_reserve.into())
+            .ok_or(CalculatorError::Overflow)?;
+        Ok((effective_base_reserve, effective_quote_reserve))
+    }
+
+    /// Real quote liquidity still missing before the curve completes.
+    pub fn quote_liquidity_to_completion(&self, quote_reserve: u64) -> u64 {
+        self.completion_quote_reserve.saturating_sub(quote_reserve)
+    }
+
+    /// Share of the completion threshold reached, between `0.0` and `1.0`.
+    pub fn completion_progress(&self, quote_reserve: u64) -> f64 {
+        if self.is_completed(quote_reserve) {
+            return 1.0;
+        }
+        quote_reserve as f64 / self.completion_quote_reserve as f64
+    }
+
+    pub fn is_completed(&self, quote_reserve: u64) -> bool {
+        quote_reserve >= self.completion_quote_reserve
+    }
+
+    fn checked_real_reserve(
+        amount_out: U128,
+        base_reserve: U128,
+        quote_reserve: U128,
+        swap_direction: &SwapDirection,
+    ) -> Result<()> {
+        let real_destination_reserve = match swap_direction {
+            SwapDirection::Base2Quote => quote_reserve,
+            SwapDirection::Quote2Base => base_reserve,
+        };
+        if amount_out >= real_destination_reserve {
+            return Err(CalculatorError::InsufficientLiquidity.into());
+        }
+        Ok(())
+    }
+}
+
+impl SwapCurve for VirtualConstantProduct {
+    fn swap_amount_out(
+        &self,
+        amount_in: U128,
+        base_reserve: U128,
+        quote_reserve: U128,
+        swap_direction: &SwapDirection,
+    ) -> Result<U128> {
+        let (effective_base_reserve, effective_quote_reserve) =
+            self.effective_reserves(base_reserve, quote_reserve)?;
+        let amount_out = ConstantProduct.swap_amount_out(
+            amount_in,
+            effective_base_reserve,
+            effective_quote_reserve,
+            swap_direction,
+        )?;
+        Self::checked_real_reserve(amount_out, base_reserve, quote_reserve, swap_direction)?;
+        Ok(amount_out)
+    }
+
+    fn swap_amount_in(
+        &self,
+        amount_out: U128,
+        base_reserve: U128,
+        quote_reserve: U128,
+        swap_direction: &SwapDirection,
+    ) -> Result<U128> {
+        Self::checked_real_reserve(amount_out, base_reserve, quote_reserve, swap_direction)?;
+        let (effective_base_reserve, effective_quote_reserve) =
+            self.effective_reserves(base_reserve, quote_reserve)?;
+        ConstantProduct.swap_amount_in(
+            amount_out,
+            effective_base_reserve,
+            effective_quote_reserve,
+            swap_direction,
+        )
+    }
+
+    fn spot_price(&self, base_reserve: U128, quote_reserve: U128) -> Result<f64> {
+        let (effective_base_reserve, effective_quote_reserve) =
+            self.effective_reserves(base_reserve, quote_reserve)?;
+        ConstantProduct.spot_price(effective_base_reserve, effective_quote_reserve)
+    }
+
+    fn invariant(&self, base_reserve: U128, quote_reserve: U128) -> Result<U256> {
+        let (effective_base_reserve, effective_quote_reserve) =
+            self.effective_reserves(base_reserve, quote_reserve)?;
+        ConstantProduct.invariant(effective_base_reserve, effective_quote_reserve)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calculator::{
+        swap_context::{FeeSchedule, Reserves, SwapContext, TaxSchedule},
+        taxation_mode::TaxationMode,
+        ProtocolSwapFeeDirection, TokenSwapCalculator,
+    };
+
+    // 1B base tokens launched against 30 virtual SOL, completing at 85 real SOL
+    const BASE_RESERVE: u64 = 1_000_000_000_000_000;
+    const VIRTUAL_QUOTE_RESERVE: u64 = 30_000_000_000;
+    const COMPLETION_QUOTE_RESERVE: u64 = 85_000_000_000;
+
+    fn curve() -> VirtualConstantProduct {
+        VirtualConstantProduct::new(0, VIRTUAL_QUOTE_RESERVE, COMPLETION_QUOTE_RESERVE)
+    }
+
+    fn context(swap_direction: SwapDirection, quote_reserve: u64) -> SwapContext {
+        SwapContext {
+            swap_direction,
+            protocol_swap_fee_direction: ProtocolSwapFeeDirection::Quote,
+            taxation_mode: TaxationMode::Quote,
+            reserves: Reserves::new(BASE_RESERVE, quote_reserve),
+            fee_schedule: FeeSchedule::new(25, 10_000, 10, 10_000).unwrap(),
+            tax_schedule: TaxSchedule::new(300, 300).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_buy_without_real_quote_liquidity() {
+        let result = TokenSwapCalculator::<VirtualConstantProduct>::swap_in_with_curve(
+            &curve(),
+            1_000_000_000,
+            &context(SwapDirection::Quote2Base, 0),
+        )
+        .unwrap();
+
+        assert!(result.swap_amount_out_after_fees > U128::zero());
+        assert!(result.swap_tax_on_input_amount > U128::zero());
+    }
+
+    #[test]
+    fn test_sell_is_capped_by_real_quote_reserve() {
+        let result = TokenSwapCalculator::<VirtualConstantProduct>::swap_in_with_curve(
+            &curve(),
+            100_000_000_000_000,
+            &context(SwapDirection::Base2Quote, 1_000_000_000),
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_completion_threshold() {
+        let curve = curve();
+
+        assert!(!curve.is_completed(0));
+        assert_eq!(curve.completion_progress(42_500_000_000), 0.5);
+        assert_eq!(
+            curve.quote_liquidity_to_completion(42_500_000_000),
+            42_500_000_000
+        );
+        assert!(curve.is_completed(COMPLETION_QUOTE_RESERVE));
+        assert_eq!(curve.completion_progress(COMPLETION_QUOTE_RESERVE + 1), 1.0);
+    }
+}
@@ -0,0 +1,247 @@
+//! Market depth of a pool: quotes at many sizes at once, and a synthetic
+//! order book for depth charts.
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    calculator::{
+        error::CalculatorError,
+        price::ui_price,
+        swap_context::{SwapContext, TransferFees},
+    },
+    instructions::LiquidityPoolState,
+    quote::{quote_exact_in, QuoteResult},
+    TEN_THOUSAND,
+};
+
+/// Exact input quotes for each of `amounts`, in order.
+///
+/// Every amount is quoted against the same `context`, whose decaying taxes
+/// must have been resolved with [`SwapContext::at`]. Each amount has its own
+/// result, since a pool too shallow for the largest sizes can still fill the
+/// smaller ones.
+pub fn quote_ladder(
+    context: &SwapContext,
+    transfer_fees: &TransferFees,
+    amounts: &[u64],
+) -> Result<Vec<Result<QuoteResult>>> {
+    context.validate()?;
+    context.checked_taxes_resolved()?;
+    transfer_fees.validate()?;
+    Ok(amounts
+        .iter()
+        .map(|amount| quote_exact_in(*amount, context, transfer_fees, 0))
+        .collect())
+}
+
+/// `count` amounts starting at `first`, each `ratio` times the previous one.
+/// Stops early once the amounts no longer fit in a `u64`.
+pub fn geometric_ladder(first: u64, ratio: f64, count: usize) -> Result<Vec<u64>> {
+    if first == 0 || ratio.is_nan() || ratio <= 1.0 {
+        return Err(anyhow!(
+            "Ladder needs a positive first amount and a ratio above 1"
+        ));
+    }
+    Ok((0..count)
+        .map(|step| (first as f64 * ratio.powi(step as i32)).round())
+        .take_while(|amount| *amount < u64::MAX as f64)
+        .map(|amount| amount as u64)
+        .collect())
+}
+
+/// One price level of a synthetic order book
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OrderBookLevel {
+    /// Quote tokens per base token, in UI units
+    pub price: f64,
+    /// Base tokens, in raw units, filled between the previous level and this one
+    pub size: u64,
+    /// Base tokens, in raw units, filled from the spot price up to this level
+    pub cumulative_size: u64,
+}
+
+/// Constant-product liquidity of a pool laid out as price levels. Bids buy
+/// base from a seller, asks sell base to a buyer; both move away from the
+/// spot price.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OrderBook {
+    /// Spot price, in UI units
+    pub spot_price: f64,
+    pub bids: Vec<OrderBookLevel>,
+    pub asks: Vec<OrderBookLevel>,
+}
+
+/// Builds `levels` bid and ask levels, each `price_step_bps` away from the
+/// previous one, from the reserves of `state`.
+///
+/// Sizes come from the reserves alone: fees and taxes are not part of the
+/// book. [`quote_ladder`] gives the amounts a swap actually fills.
+pub fn order_book(
+    state: &LiquidityPoolState,
+    price_step_bps: u64,
+    levels: usize,
+) -> Result<OrderBook> {
+    let base_reserve = state.base_token_vault_balance as f64;
+    let quote_reserve = state.quote_token_vault_balance as f64;
+    if state.base_token_vault_balance == 0 || state.quote_token_vault_balance == 0 {
+        return Err(CalculatorError::InsufficientLiquidity.into());
+    }
+    if price_step_bps == 0 {
+        return Err(anyhow!("Order book needs a positive price step"));
+    }
+    let step = 1.0 + price_step_bps as f64 / TEN_THOUSAND as f64;
+    let spot_price = quote_reserve / base_reserve;
+    let to_ui = |price: f64| {
+        ui_price(
+            price,
+            state.base_token_mint_decimals,
+            state.quote_token_mint_decimals,
+        )
+    };
+
+    // Moving the price of `x * y = k` by a factor `m` leaves `x / sqrt(m)`
+    // base tokens in the pool
+    let side = |price_factor: fn(f64) -> f64| {
+        let mut cumulative_size = 0u64;
+        (1..=levels)
+            .map(|level| {
+                let multiplier = price_factor(step.powi(level as i32));
+                let base_reserve_after = base_reserve / multiplier.sqrt();
+                let level_cumulative_size =
+                    ((base_reserve_after - base_reserve).abs().floor() as u64).max(cumulative_size);
+                let size = level_cumulative_size - cumulative_size;
+                cumulative_size = level_cumulative_size;
+                OrderBookLevel {
+                    price: to_ui(spot_price * multiplier),
+                    size,
+                    cumulative_size,
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+
+    Ok(OrderBook {
+        spot_price: to_ui(spot_price),
+        bids: side(|factor| 1.0 / factor),
+        asks: side(|factor| factor),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calculator::{
+        swap_direction::SwapDirection,
+        tax_decay::{DecayingTax, TaxDecay, TaxDecaySchedule},
+        taxation_mode::TaxationMode,
+    };
+
+    fn state() -> LiquidityPoolState {
+        LiquidityPoolState {
+            base_token_mint_decimals: 9,
+            quote_token_mint_decimals: 6,
+            base_token_vault_balance: 1_000_000_000_000,
+            quote_token_vault_balance: 2_000_000_000,
+            swap_fee_numerator: 25,
+            swap_fee_denominator: 10_000,
+            protocol_swap_fee_numerator: 0,
+            protocol_swap_fee_denominator: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ladder_matches_individual_quotes() {
+        let amounts = geometric_ladder(1_000, 10.0, 12).unwrap();
+        assert_eq!(amounts.len(), 12);
+        assert_eq!(amounts[3], 1_000_000);
+
+        let context = SwapContext::from_pool_state(&state(), SwapDirection::Quote2Base).unwrap();
+        let ladder = quote_ladder(&context, &TransferFees::default(), &amounts).unwrap();
+
+        for (amount, quote) in amounts.iter().zip(ladder) {
+            let expected = quote_exact_in(*amount, &context, &TransferFees::default(), 0);
+            match (quote, expected) {
+                (Ok(quote), Ok(expected)) => assert_eq!(quote, expected),
+                (Err(error), Err(expected)) => {
+                    assert_eq!(error.to_string(), expected.to_string())
+                }
+                _ => panic!("ladder and quote disagree for {}", amount),
+            }
+        }
+    }
+
+    #[test]
+    fn test_ladder_quotes_decaying_taxes_at_their_timestamp() {
+        let tax_decay = TaxDecaySchedule {
+            buy_tax: DecayingTax {
+                start: 5_000,
+                floor: 100,
+                decay: TaxDecay::Linear { duration: 100 },
+            },
+            sell_tax: DecayingTax::flat(100),
+            open_at: 1_000,
+        };
+        let state = LiquidityPoolState {
+            taxation_mode: TaxationMode::Quote,
+            ..state()
+        };
+        let context = SwapContext::from_pool_state(&state, SwapDirection::Quote2Base)
+            .unwrap()
+            .with_tax_decay(tax_decay)
+            .unwrap();
+        let amounts = [1_000_000, 10_000_000];
+
+        assert!(quote_ladder(&context, &TransferFees::default(), &amounts).is_err());
+        let launch = quote_ladder(
+            &context.at(1_000).unwrap(),
+            &TransferFees::default(),
+            &amounts,
+        )
+        .unwrap();
+        let later = quote_ladder(
+            &context.at(2_000).unwrap(),
+            &TransferFees::default(),
+            &amounts,
+        )
+        .unwrap();
+        for (launch, later) in launch.into_iter().zip(later) {
+            assert!(launch.unwrap().amount_out < later.unwrap().amount_out);
+        }
+    }
+
+    #[test]
+    fn test_geometric_ladder_stops_at_u64_max() {
+        let amounts = geometric_ladder(u64::MAX / 4, 2.0, 10).unwrap();
+        assert_eq!(amounts.len(), 2);
+        assert!(geometric_ladder(0, 2.0, 10).is_err());
+        assert!(geometric_ladder(1, 1.0, 10).is_err());
+    }
+
+    #[test]
+    fn test_order_book_moves_away_from_spot() {
+        let book = order_book(&state(), 100, 5).unwrap();
+
+        // 0.002 raw quote units per raw base unit, 1000x in UI units
+        assert!((book.spot_price - 2.0).abs() < 1e-9);
+        assert_eq!(book.bids.len(), 5);
+        assert_eq!(book.asks.len(), 5);
+        for levels in [&book.bids, &book.asks] {
+            let sizes: u64 = levels.iter().map(|level| level.size).sum();
+            assert_eq!(sizes, levels.last().unwrap().cumulative_size);
+        }
+        assert!(book
+            .bids
+            .windows(2)
+            .all(|pair| pair[1].price < pair[0].price));
+        assert!(book
+            .asks
+            .windows(2)
+            .all(|pair| pair[1].price > pair[0].price));
+        assert!(book.bids[0].price < book.spot_price && book.spot_price < book.asks[0].price);
+
+        // Moving the price up 1% takes about 0.5% of the base reserve
+        let ask = book.asks[0];
+        assert!((ask.cumulative_size as f64 / 1e12 - 0.00496).abs() < 1e-5);
+    }
+}
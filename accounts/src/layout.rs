@@ -0,0 +1,500 @@
+//! Versioned decoding of `LiquidityPoolState` accounts.
+//!
+//! The `pool-price-stats` feature adds six `f64` fields in the middle of the
+//! packed state, so the compiled struct only matches the accounts of one
+//! layout, and `repr(packed)` leaves the compiler free to reorder its fields
+//! in memory. The decoder reads the layout off the account itself and its
+//! fields one by one, in account order, whichever features are enabled.
+//!
+//! Pools created before the fields after the price stats were added have
+//! shorter accounts, which decode with those fields left at their defaults.
+
+use std::{fmt, mem::size_of};
+
+use anchor_lang::{AnchorDeserialize, AnchorSerialize, Discriminator};
+use anyhow::Result;
+
+use crate::instructions::LiquidityPoolState;
+
+const DISCRIMINATOR_LEN: usize = 8;
+const PRICE_STATS_LEN: usize = 6 * size_of::<f64>();
+
+/// Failures to decode a `LiquidityPoolState` account
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    /// The account does not start with the `LiquidityPoolState` discriminator
+    DiscriminatorMismatch,
+    /// No known layout has the account's length
+    UnknownLayout { len: usize },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::DiscriminatorMismatch => {
+                write!(f, "Account is not a liquidity pool state")
+            }
+            LayoutError::UnknownLayout { len } => {
+                write!(f, "Unknown liquidity pool state layout of {} bytes", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+/// On-chain layouts of `LiquidityPoolState`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolStateLayout {
+    /// Program built without `pool-price-stats`
+    WithoutPriceStats,
+    /// Program built with `pool-price-stats`, the prices and market caps
+    /// follow the vault balances
+    WithPriceStats,
+    /// Account of a pool created before the last fields after the price
+    /// stats were added: only the first `fields` of them, fewer than
+    /// [`AFTER_PRICE_STATS_FIELDS`], are there
+    Historical { price_stats: bool, fields: usize },
+}
+
+impl PoolStateLayout {
+    /// Every layout, historical ones first
+    pub const ALL: [PoolStateLayout; 2 * (AFTER_PRICE_STATS_FIELDS + 1)] = {
+        let mut layouts = [PoolStateLayout::WithoutPriceStats; 2 * (AFTER_PRICE_STATS_FIELDS + 1)];
+        let mut fields = 0;
+        while fields < AFTER_PRICE_STATS_FIELDS {
+            layouts[2 * fields] = PoolStateLayout::Historical {
+                price_stats: false,
+                fields,
+            };
+            layouts[2 * fields + 1] = PoolStateLayout::Historical {
+                price_stats: true,
+                fields,
+            };
+            fields += 1;
+        }
+        layouts[2 * AFTER_PRICE_STATS_FIELDS] = PoolStateLayout::WithoutPriceStats;
+        layouts[2 * AFTER_PRICE_STATS_FIELDS + 1] = PoolStateLayout::WithPriceStats;
+        layouts
+    };
+
+    /// Account length of the layout, discriminator included
+    pub fn account_len(&self) -> usize {
+        let price_stats_len = if self.has_price_stats() {
+            PRICE_STATS_LEN
+        } else {
+            0
+        };
+        DISCRIMINATOR_LEN
+            + before_price_stats_len()
+            + price_stats_len
+            + after_price_stats_len(self.after_price_stats_fields())
+    }
+
+    pub fn has_price_stats(&self) -> bool {
+        match self {
+            PoolStateLayout::WithoutPriceStats => false,
+            PoolStateLayout::WithPriceStats => true,
+            PoolStateLayout::Historical { price_stats, .. } => *price_stats,
+        }
+    }
+
+    /// Number of the fields after the price stats the account has
+    pub fn after_price_stats_fields(&self) -> usize {
+        match self {
+            PoolStateLayout::WithoutPriceStats | PoolStateLayout::WithPriceStats => {
+                AFTER_PRICE_STATS_FIELDS
+            }
+            PoolStateLayout::Historical { fields, .. } => (*fields).min(AFTER_PRICE_STATS_FIELDS),
+        }
+    }
+
+    /// Layout of the account `data`, from its discriminator and length
+    pub fn detect(data: &[u8]) -> Result<Self> {
+        if !data.starts_with(&LiquidityPoolState::DISCRIMINATOR) {
+            return Err(LayoutError::DiscriminatorMismatch.into());
+        }
+        Self::ALL
+            .into_iter()
+            .find(|layout| layout.account_len() == data.len())
+            .ok_or_else(|| LayoutError::UnknownLayout { len: data.len() }.into())
+    }
+}
+
+/// Prices and market caps a pool keeps under `pool-price-stats`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PoolPriceStats {
+    pub min_price: f64,
+    pub max_price: f64,
+    pub curr_price: f64,
+    pub curr_mc: f64,
+    pub min_mc: f64,
+    pub max_mc: f64,
+}
+
+impl PoolPriceStats {
+    /// Reads the stats raw rather than through borsh, which rejects NaN.
+    /// `data` is known to hold them from its layout.
+    fn read(data: &mut &[u8]) -> Self {
+        let mut value = || {
+            let (bytes, rest) = data.split_at(size_of::<f64>());
+            *data = rest;
+            let mut value = [0u8; size_of::<f64>()];
+            value.copy_from_slice(bytes);
+            f64::from_le_bytes(value)
+        };
+        Self {
+            min_price: value(),
+            max_price: value(),
+            curr_price: value(),
+            curr_mc: value(),
+            min_mc: value(),
+            max_mc: value(),
+        }
+    }
+
+    fn write(&self, data: &mut Vec<u8>) {
+        for value in [
+            self.min_price,
+            self.max_price,
+            self.curr_price,
+            self.curr_mc,
+            self.min_mc,
+            self.max_mc,
+        ] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+/// Reads and writes the fields around the price stats in account order.
+/// Every field is little-endian without padding, as borsh encodes it.
+macro_rules! pool_state_fields {
+    (before_price_stats: [$($before:ident),* $(,)?], after_price_stats: [$($after:ident),* $(,)?] $(,)?) => {
+        /// Fields after the price stats of the current layouts
+        pub const AFTER_PRICE_STATS_FIELDS: usize = [$(stringify!($after)),*].len();
+
+        /// Length of the fields every layout has
+        fn before_price_stats_len() -> usize {
+            let state = LiquidityPoolState::default();
+            0 $(+ std::mem::size_of_val(&{ state.$before }))*
+        }
+
+        /// Length of the first `fields` fields after the price stats
+        fn after_price_stats_len(fields: usize) -> usize {
+            let state = LiquidityPoolState::default();
+            [$(std::mem::size_of_val(&{ state.$after })),*][..fields]
+                .iter()
+                .sum()
+        }
+
+        fn read_fields(
+            data: &mut &[u8],
+            layout: PoolStateLayout,
+        ) -> Result<(LiquidityPoolState, Option<PoolPriceStats>)> {
+            let mut state = LiquidityPoolState::default();
+            $(state.$before = AnchorDeserialize::deserialize(data)?;)*
+            let price_stats = layout
+                .has_price_stats()
+                .then(|| PoolPriceStats::read(data));
+            // Fields the account predates keep their defaults
+            let mut fields = layout.after_price_stats_fields();
+            $(
+                if fields > 0 {
+                    state.$after = AnchorDeserialize::deserialize(data)?;
+                    fields -= 1;
+                }
+            )*
+            Ok((state, price_stats))
+        }
+
+        fn write_fields(
+            state: &LiquidityPoolState,
+            layout: PoolStateLayout,
+            price_stats: &PoolPriceStats,
+            data: &mut Vec<u8>,
+        ) -> Result<()> {
+            $(AnchorSerialize::serialize(&{ state.$before }, data)?;)*
+            if layout.has_price_stats() {
+                price_stats.write(data);
+            }
+            let mut fields = layout.after_price_stats_fields();
+            $(
+                if fields > 0 {
+                    AnchorSerialize::serialize(&{ state.$after }, data)?;
+                    fields -= 1;
+                }
+            )*
+            Ok(())
+        }
+    };
+}
+
+pool_state_fields! {
+    before_price_stats: [
+        base_token_mint,
+        base_token_mint_decimals,
+        base_token_vault,
+        base_token_swap_tax_vault,
+        quote_token_mint,
+        quote_token_mint_decimals,
+        quote_token_vault,
+        quote_token_swap_tax_vault,
+        protocol_base_token_swap_fee_vault,
+        protocol_quote_token_swap_fee_vault,
+        lp_token_mint,
+        lp_token_mint_decimals,
+        lp_token_current_supply,
+        swap_fee_numerator,
+        swap_fee_denominator,
+        protocol_swap_fee_numerator,
+        protocol_swap_fee_denominator,
+        buy_tax,
+        sell_tax,
+        protocol_tax_numerator,
+        protocol_tax_denominator,
+        creator,
+        authority_bump,
+        allow_swap,
+        allow_remove_liquidity,
+        allow_add_liquidity,
+        open_at,
+        created_at,
+        lock_until,
+        protocol_config_version,
+        taxation_mode,
+        swap_base_in_amount,
+        swap_quote_in_amount,
+        swap_base_out_amount,
+        swap_quote_out_amount,
+        swap_base_fee,
+        swap_quote_fee,
+        swap_base_to_quote_count,
+        swap_quote_to_base_count,
+        swap_in_count,
+        swap_out_count,
+        base_swap_tax_amount,
+        quote_swap_tax_amount,
+        first_swap_out_timestamp,
+        latest_swap_out_timestamp,
+        first_swap_in_timestamp,
+        latest_swap_in_timestamp,
+        first_base_to_quote_amount,
+        latest_base_to_quote_amount,
+        first_quote_to_base_amount,
+        latest_quote_to_base_amount,
+        locked_lp,
+        initial_lp,
+        liquidity_added,
+        liquidity_removed,
+        is_initial_lp_burned,
+        base_token_added,
+        base_token_removed,
+        quote_token_added,
+        quote_token_removed,
+        base_protocol_tax,
+        quote_protocol_tax,
+        base_protocol_fee,
+        quote_protocol_fee,
+        base_token_vault_balance,
+        quote_token_vault_balance,
+    ],
+    after_price_stats: [
+        locked_taxation,
+        disable_non_creator_add_liquidity,
+        allow_creator_claim_swap_fee,
+        extras,
+        base_token_program,
+        quote_token_program,
+    ],
+}
+
+/// Decodes a `LiquidityPoolState` account of any known layout.
+///
+/// Price stats of the account are dropped when the crate is built without
+/// `pool-price-stats`, and left at zero when the account has none; read them
+/// with [`decode_price_stats`] either way.
+pub fn decode_liquidity_pool_state(data: &[u8]) -> Result<LiquidityPoolState> {
+    let layout = PoolStateLayout::detect(data)?;
+    #[allow(unused_mut, unused_variables)]
+    let (mut state, price_stats) = read_fields(&mut &data[DISCRIMINATOR_LEN..], layout)?;
+    #[cfg(feature = "pool-price-stats")]
+    if let Some(price_stats) = price_stats {
+        state.min_price = price_stats.min_price;
+        state.max_price = price_stats.max_price;
+        state.curr_price = price_stats.curr_price;
+        state.curr_mc = price_stats.curr_mc;
+        state.min_mc = price_stats.min_mc;
+        state.max_mc = price_stats.max_mc;
+    }
+    Ok(state)
+}
+
+/// Price stats of a `LiquidityPoolState` account, `None` for layouts without
+pub fn decode_price_stats(data: &[u8]) -> Result<Option<PoolPriceStats>> {
+    let layout = PoolStateLayout::detect(data)?;
+    let (_, price_stats) = read_fields(&mut &data[DISCRIMINATOR_LEN..], layout)?;
+    Ok(price_stats)
+}
+
+/// Encodes `state` as an account of `layout`, with `price_stats` if the
+/// layout has them and without the fields it predates, e.g. to simulate a
+/// pool
+pub fn encode_liquidity_pool_state(
+    state: &LiquidityPoolState,
+    layout: PoolStateLayout,
+    price_stats: &PoolPriceStats,
+) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(layout.account_len());
+    data.extend_from_slice(&LiquidityPoolState::DISCRIMINATOR);
+    write_fields(state, layout, price_stats, &mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calculator::taxation_mode::TaxationMode;
+    use anchor_lang::prelude::Pubkey;
+
+    fn state() -> LiquidityPoolState {
+        LiquidityPoolState {
+            base_token_mint: Pubkey::new_unique(),
+            quote_token_mint: Pubkey::new_unique(),
+            swap_fee_numerator: 25,
+            swap_fee_denominator: 10_000,
+            allow_swap: true,
+            taxation_mode: TaxationMode::Quote,
+            swap_in_count: u128::MAX,
+            base_token_vault_balance: 1_000_000,
+            quote_token_vault_balance: 2_000_000,
+            allow_creator_claim_swap_fee: true,
+            extras: [7; 8],
+            quote_token_program: Pubkey::new_unique(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_decode_every_layout() {
+        let state = state();
+        let price_stats = PoolPriceStats {
+            min_price: 0.5,
+            curr_price: 2.0,
+            max_mc: 1e9,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            PoolStateLayout::WithPriceStats.account_len()
+                - PoolStateLayout::WithoutPriceStats.account_len(),
+            PRICE_STATS_LEN
+        );
+        for layout in PoolStateLayout::ALL {
+            let data = encode_liquidity_pool_state(&state, layout, &price_stats).unwrap();
+            assert_eq!(data.len(), layout.account_len());
+            assert_eq!(PoolStateLayout::detect(&data).unwrap(), layout);
+
+            let decoded = decode_liquidity_pool_state(&data).unwrap();
+            assert_eq!(decoded.base_token_mint, state.base_token_mint);
+            assert_eq!({ decoded.swap_fee_numerator }, 25);
+            assert!(decoded.allow_swap);
+            assert_eq!(decoded.taxation_mode, TaxationMode::Quote);
+            assert_eq!({ decoded.swap_in_count }, u128::MAX);
+            assert_eq!({ decoded.quote_token_vault_balance }, 2_000_000);
+            if layout.after_price_stats_fields() == AFTER_PRICE_STATS_FIELDS {
+                assert!(decoded.allow_creator_claim_swap_fee);
+                assert_eq!(decoded.extras, [7; 8]);
+                assert_eq!(decoded.quote_token_program, state.quote_token_program);
+            }
+
+            let decoded_price_stats = decode_price_stats(&data).unwrap();
+            if layout.has_price_stats() {
+                assert_eq!(decoded_price_stats, Some(price_stats));
+                #[cfg(feature = "pool-price-stats")]
+                assert_eq!({ decoded.curr_price }, 2.0);
+            } else {
+                assert_eq!(decoded_price_stats, None);
+            }
+        }
+
+        // Every layout is told apart by its length alone
+        for (index, layout) in PoolStateLayout::ALL.iter().enumerate() {
+            assert!(PoolStateLayout::ALL[index + 1..]
+                .iter()
+                .all(|other| other.account_len() != layout.account_len()));
+        }
+    }
+
+    #[test]
+    fn test_decode_account_predating_trailing_fields() {
+        let state = state();
+        let price_stats = PoolPriceStats {
+            curr_price: 2.0,
+            ..Default::default()
+        };
+
+        // Created before `extras` and the token programs were added
+        for (full_layout, price_stats_len) in [
+            (PoolStateLayout::WithoutPriceStats, 0),
+            (PoolStateLayout::WithPriceStats, PRICE_STATS_LEN),
+        ] {
+            let full = encode_liquidity_pool_state(&state, full_layout, &price_stats).unwrap();
+            let data = &full[..full.len() - 8 - 2 * 32];
+            assert_eq!(
+                PoolStateLayout::detect(data).unwrap(),
+                PoolStateLayout::Historical {
+                    price_stats: price_stats_len > 0,
+                    fields: 3,
+                }
+            );
+
+            let decoded = decode_liquidity_pool_state(data).unwrap();
+            assert_eq!(decoded.base_token_mint, state.base_token_mint);
+            assert_eq!({ decoded.quote_token_vault_balance }, 2_000_000);
+            assert!(decoded.allow_creator_claim_swap_fee);
+            assert_eq!(decoded.extras, [0; 8]);
+            assert_eq!(decoded.base_token_program, Pubkey::default());
+            assert_eq!(decoded.quote_token_program, Pubkey::default());
+            assert_eq!(
+                decode_price_stats(data).unwrap(),
+                (price_stats_len > 0).then_some(price_stats)
+            );
+        }
+    }
+
+    #[test]
+    fn test_reject_unknown_layouts() {
+        let data = encode_liquidity_pool_state(
+            &state(),
+            PoolStateLayout::WithPriceStats,
+            &PoolPriceStats::default(),
+        )
+        .unwrap();
+
+        // Longer than any layout, used to underflow the zero padding
+        let mut longer = data.clone();
+        longer.push(0);
+        let error = decode_liquidity_pool_state(&longer).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LayoutError>(),
+            Some(&LayoutError::UnknownLayout {
+                len: data.len() + 1
+            })
+        );
+
+        let error = decode_liquidity_pool_state(&data[..data.len() - 1]).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LayoutError>(),
+            Some(LayoutError::UnknownLayout { .. })
+        ));
+
+        let mut other_account = data;
+        other_account[0] ^= 1;
+        let error = decode_liquidity_pool_state(&other_account).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LayoutError>(),
+            Some(&LayoutError::DiscriminatorMismatch)
+        );
+    }
+}
@@ -1,9 +1,15 @@
 use anchor_lang::prelude::*;
 
 pub mod calculator;
+pub mod depth;
 pub mod instructions;
+pub mod layout;
+pub mod position;
 pub mod quote;
+pub mod solver;
+pub mod swap_instruction;
 pub mod utils;
+pub mod zap;
 use anchor_spl::{
     token::Token,
     token_2022::spl_token_2022::{
@@ -11,6 +17,7 @@ use anchor_spl::{
         extension::{transfer_fee::{TransferFee, TransferFeeConfig}, BaseStateWithExtensions, StateWithExtensions},
     },
 };
+use calculator::swap_context::EpochTransferFee;
 use instructions::*;
 use protocol_account_config;
 
@@ -30,13 +37,19 @@ pub mod heaven_anchor_amm {
 }
 
 pub fn get_transfer_fee_config(mint_info: &(Vec<u8>, Pubkey), epoch: u64) -> Result<TransferFee> {
+    Ok(get_epoch_transfer_fee(mint_info)?.at(epoch))
+}
+
+/// Older and newer transfer fees of a mint, to resolve the fee of any epoch
+/// without reloading the mint account.
+pub fn get_epoch_transfer_fee(mint_info: &(Vec<u8>, Pubkey)) -> Result<EpochTransferFee> {
     if mint_info.1 == Token::id() {
-        return Ok(TransferFee::default());
+        return Ok(EpochTransferFee::default());
     }
 
     let mint_data = &mint_info.0;
     let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
 
     let transfer_fee_config = mint.get_extension::<TransferFeeConfig>()?;
-    Ok(*transfer_fee_config.get_epoch_fee(epoch))
+    Ok(EpochTransferFee::from(transfer_fee_config))
 }
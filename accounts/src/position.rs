@@ -0,0 +1,279 @@
+//! Analytics of an LP position: what the LP tokens redeem for, what they are
+//! worth in the quote token, the share of swap fees they earned and how they
+//! compare with holding the deposited tokens instead.
+
+use anchor_lang::error::Error;
+use anyhow::Result;
+
+use crate::{
+    calculator::{
+        error::CalculatorError, liquidity::WithdrawResult, number::U256,
+        swap_context::TransferFees, LiquidityCalculator,
+    },
+    instructions::{LiquidityPoolState, LiquidityPoolUserStats},
+    utils::error::AmmErrorCode,
+};
+
+/// Worth of `lp_amount` LP tokens at the current reserves
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PositionValue {
+    pub lp_amount: u64,
+    /// Fraction of the LP supply the position holds
+    pub pool_share: f64,
+    /// Tokens received for burning the position, net of transfer fees
+    pub redeemable: WithdrawResult,
+    /// Redeemable tokens valued at the spot price, in raw quote units
+    pub value_in_quote: u128,
+}
+
+/// Swap fees credited to a position, in raw units
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeeEarnings {
+    pub base_fees: u64,
+    pub quote_fees: u64,
+    /// Both fees valued at the spot price, in raw quote units
+    pub value_in_quote: u128,
+}
+
+/// Position value, fee earnings and impermanent loss of an LP
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PositionAnalytics {
+    pub position: PositionValue,
+    pub fee_earnings: FeeEarnings,
+    /// Tokens deposited minus tokens withdrawn, from the LP's stats
+    pub net_base_deposited: u128,
+    pub net_quote_deposited: u128,
+    /// Net deposits valued at the spot price, in raw quote units
+    pub hold_value_in_quote: u128,
+    /// Position value, fee earnings excluded, relative to holding the net
+    /// deposits: `-0.05` is a 5% loss. `None` without net deposits.
+    pub impermanent_loss: Option<f64>,
+    /// Position value, fee earnings included, relative to holding the net
+    /// deposits. `None` without net deposits.
+    pub return_vs_hold: Option<f64>,
+}
+
+/// `base_amount` base tokens and `quote_amount` quote tokens valued at the
+/// spot price of `state`, in raw quote units, rounded down
+pub fn value_in_quote(
+    state: &LiquidityPoolState,
+    base_amount: u128,
+    quote_amount: u128,
+) -> Result<u128> {
+    if state.base_token_vault_balance == 0 {
+        return Err(CalculatorError::InsufficientLiquidity.into());
+    }
+    let base_value = U256::from(base_amount) * U256::from(state.quote_token_vault_balance)
+        / U256::from(state.base_token_vault_balance);
+    u128::try_from(base_value)
+        .ok()
+        .and_then(|base_value| base_value.checked_add(quote_amount))
+        .ok_or(CalculatorError::Overflow.into())
+}
+
+/// Redeemable tokens and value of `lp_amount` LP tokens. Pool switches and LP
+/// locks are ignored: the position is valued as if it could be withdrawn now.
+pub fn position_value(
+    state: &LiquidityPoolState,
+    lp_amount: u64,
+    transfer_fees: &TransferFees,
+) -> Result<PositionValue> {
+    let redeemable = LiquidityCalculator::redeemable(state, lp_amount, transfer_fees)?;
+    Ok(PositionValue {
+        lp_amount,
+        pool_share: lp_amount as f64 / state.lp_token_current_supply as f64,
+        redeemable,
+        value_in_quote: value_in_quote(
+            state,
+            redeemable.base_amount_out.into(),
+            redeemable.quote_amount_out.into(),
+        )?,
+    })
+}
+
+/// Estimated share of the pool's accumulated `swap_base_fee` and
+/// `swap_quote_fee` earned by `lp_amount` LP tokens, rounded down.
+///
+/// The pool only keeps lifetime totals, so fees are assumed to accrue evenly
+/// over time: the position earns its share of the supply for the part of the
+/// pool's life since the LP's first deposit. `now` is a unix timestamp.
+pub fn estimate_fee_earnings(
+    state: &LiquidityPoolState,
+    user_stats: &LiquidityPoolUserStats,
+    lp_amount: u64,
+    now: u64,
+) -> Result<FeeEarnings> {
+    let lp_supply = state.lp_token_current_supply;
+    if lp_amount > lp_supply {
+        return Err(Error::from(AmmErrorCode::InvalidRemoveLiquidityInput).into());
+    }
+    let pool_age = now.saturating_sub(state.created_at);
+    let position_age = match user_stats.initial_liquidity_added_timestamp {
+        0 => pool_age,
+        timestamp => now.saturating_sub(timestamp).min(pool_age),
+    };
+    // A pool created this very second has all of its fees to share
+    let (position_age, pool_age) = if pool_age == 0 {
+        (1, 1)
+    } else {
+        (position_age, pool_age)
+    };
+
+    let fee_share = |pool_fees: u128| -> Result<u64> {
+        if lp_supply == 0 {
+            return Ok(0);
+        }
+        (U256::from(pool_fees) * U256::from(lp_amount) * U256::from(position_age)
+            / (U256::from(lp_supply) * U256::from(pool_age)))
+        .try_into()
+        .map_err(|_| CalculatorError::Overflow.into())
+    };
+    let base_fees = fee_share(state.swap_base_fee)?;
+    let quote_fees = fee_share(state.swap_quote_fee)?;
+
+    Ok(FeeEarnings {
+        base_fees,
+        quote_fees,
+        value_in_quote: value_in_quote(state, base_fees.into(), quote_fees.into())?,
+    })
+}
+
+/// Position value and fee earnings of `lp_amount` LP tokens, compared with
+/// holding the tokens the LP deposited net of what they withdrew.
+pub fn position_analytics(
+    state: &LiquidityPoolState,
+    user_stats: &LiquidityPoolUserStats,
+    lp_amount: u64,
+    transfer_fees: &TransferFees,
+    now: u64,
+) -> Result<PositionAnalytics> {
+    let position = position_value(state, lp_amount, transfer_fees)?;
+    let fee_earnings = estimate_fee_earnings(state, user_stats, lp_amount, now)?;
+
+    let net_base_deposited = user_stats
+        .base_token_added
+        .saturating_sub(user_stats.base_token_removed);
+    let net_quote_deposited = user_stats
+        .quote_token_added
+        .saturating_sub(user_stats.quote_token_removed);
+    let hold_value_in_quote = value_in_quote(state, net_base_deposited, net_quote_deposited)?;
+
+    let relative_to_hold = |value: u128| {
+        (hold_value_in_quote > 0).then(|| value as f64 / hold_value_in_quote as f64 - 1.0)
+    };
+
+    Ok(PositionAnalytics {
+        position,
+        fee_earnings,
+        net_base_deposited,
+        net_quote_deposited,
+        hold_value_in_quote,
+        impermanent_loss: relative_to_hold(
+            position
+                .value_in_quote
+                .saturating_sub(fee_earnings.value_in_quote),
+        ),
+        return_vs_hold: relative_to_hold(position.value_in_quote),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::TransferFee;
+
+    fn state() -> LiquidityPoolState {
+        LiquidityPoolState {
+            base_token_vault_balance: 1_000_000_000,
+            quote_token_vault_balance: 4_000_000_000,
+            lp_token_current_supply: 2_000_000_000,
+            swap_base_fee: 10_000_000,
+            swap_quote_fee: 40_000_000,
+            created_at: 1_000,
+            // Withdrawals are off, positions are still valued
+            allow_remove_liquidity: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_position_value_after_transfer_fees() {
+        let transfer_fees = TransferFees {
+            base: TransferFee {
+                epoch: 0.into(),
+                maximum_fee: u64::MAX.into(),
+                transfer_fee_basis_points: 100.into(),
+            },
+            ..Default::default()
+        };
+
+        let position = position_value(&state(), 200_000_000, &transfer_fees).unwrap();
+
+        assert_eq!(position.pool_share, 0.1);
+        assert_eq!(position.redeemable.base_amount_out, 99_000_000);
+        assert_eq!(position.redeemable.base_transfer_fee, 1_000_000);
+        assert_eq!(position.redeemable.quote_amount_out, 400_000_000);
+        // 99M base at 4 quote per base, plus 400M quote
+        assert_eq!(position.value_in_quote, 796_000_000);
+    }
+
+    #[test]
+    fn test_fee_earnings_prorated_by_share_and_time() {
+        let user_stats = LiquidityPoolUserStats {
+            initial_liquidity_added_timestamp: 1_500,
+            ..Default::default()
+        };
+
+        // A tenth of the supply for half of the pool's life
+        let fees = estimate_fee_earnings(&state(), &user_stats, 200_000_000, 2_000).unwrap();
+
+        assert_eq!(fees.base_fees, 500_000);
+        assert_eq!(fees.quote_fees, 2_000_000);
+        assert_eq!(fees.value_in_quote, 4_000_000);
+    }
+
+    #[test]
+    fn test_impermanent_loss_after_price_move() {
+        // Deposited 100M base and 400M quote at a price of 4, the price has
+        // since quadrupled: the pool holds half the base and twice the quote
+        let state = LiquidityPoolState {
+            base_token_vault_balance: 500_000_000,
+            quote_token_vault_balance: 8_000_000_000,
+            swap_base_fee: 0,
+            swap_quote_fee: 0,
+            ..state()
+        };
+        let user_stats = LiquidityPoolUserStats {
+            base_token_added: 100_000_000,
+            quote_token_added: 400_000_000,
+            initial_liquidity_added_timestamp: 1_000,
+            ..Default::default()
+        };
+
+        let analytics = position_analytics(
+            &state,
+            &user_stats,
+            200_000_000,
+            &TransferFees::default(),
+            2_000,
+        )
+        .unwrap();
+
+        // Holding is worth 100M * 16 + 400M, the position 50M * 16 + 800M
+        assert_eq!(analytics.hold_value_in_quote, 2_000_000_000);
+        assert_eq!(analytics.position.value_in_quote, 1_600_000_000);
+        // 2 * sqrt(4) / (1 + 4) - 1
+        assert!((analytics.impermanent_loss.unwrap() + 0.2).abs() < 1e-12);
+        assert_eq!(analytics.return_vs_hold, analytics.impermanent_loss);
+
+        let without_deposits = position_analytics(
+            &state,
+            &LiquidityPoolUserStats::default(),
+            200_000_000,
+            &TransferFees::default(),
+            2_000,
+        )
+        .unwrap();
+        assert_eq!(without_deposits.impermanent_loss, None);
+    }
+}
@@ -1,206 +1,1027 @@
-use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::TransferFee;
+use anchor_lang::prelude::Pubkey;
 
 use crate::calculator::{
-    number::{CheckedCeilDiv, U128},
-    swap_direction::SwapDirection,
-    taxation_mode::TaxationMode,
-    ConstantProduct, ProtocolSwapFeeDirection, TokenSwapCalculator,
+    error::CalculatorError,
+    number::{CheckedAsU64, U128},
+    price::SwapPrices,
+    rounding::{CheckedMulDiv, RoundDirection},
+    swap_context::{EpochTransferFees, Reserves, SwapContext, TransferFees},
+    swap_curve::SwapCurve,
+    ConstantProduct, TokenSwapCalculator,
 };
 use anyhow::Result;
 
+/// An amount of a given token
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TokenAmount {
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+impl TokenAmount {
+    fn new(mint: Pubkey, amount: U128) -> Result<Self> {
+        Ok(Self {
+            mint,
+            amount: amount.checked_as_u64().ok_or(CalculatorError::Overflow)?,
+        })
+    }
+}
+
+/// Quote of a swap, for an exact input or an exact output amount
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QuoteResult {
+    pub input_mint: Pubkey,
+    pub output_mint: Pubkey,
+
+    /// Amount sent by the user, including the input transfer fee
+    pub amount_in: u64,
+    /// Amount received by the pool
+    pub amount_in_after_transfer_fee: u64,
+    /// Amount sent by the pool
+    pub amount_out_before_transfer_fee: u64,
+    /// Amount received by the user, net of the output transfer fee
+    pub amount_out: u64,
+
+    pub input_transfer_fee: TokenAmount,
+    pub output_transfer_fee: TokenAmount,
+    /// LP swap fee, always charged on the input
+    pub swap_fee: TokenAmount,
+    /// Buy or sell tax, `protocol_tax` included
+    pub swap_tax: TokenAmount,
+    /// Protocol's cut of `swap_tax`, paid out of it rather than on top
+    pub protocol_tax: TokenAmount,
+    pub protocol_swap_fee: TokenAmount,
+
+    /// Slippage-bounded limits. For an exact input quote
+    /// `maximum_amount_in == amount_in`, for an exact output quote
+    /// `minimum_amount_out == amount_out`.
+    pub minimum_amount_out: u64,
+    pub maximum_amount_in: u64,
+
+    /// Pool reserves once the swap is executed
+    pub reserves_after: Reserves,
+    /// Spot, execution and post-trade prices in UI units, with the price
+    /// impact of the curve alone
+    pub prices: SwapPrices,
+}
+
+impl QuoteResult {
+    /// Sum of the swap fee, swap tax and protocol swap fee charged in `mint`,
+    /// transfer fees excluded. The protocol tax is part of the swap tax.
+    pub fn fees_in_mint(&self, mint: &Pubkey) -> u64 {
+        [self.swap_fee, self.swap_tax, self.protocol_swap_fee]
+            .iter()
+            .filter(|fee| fee.mint == *mint)
+            .fold(0u64, |total, fee| total.saturating_add(fee.amount))
+    }
+
+    /// Fees charged in the input mint, transfer fees excluded
+    pub fn input_fees(&self) -> TokenAmount {
+        TokenAmount {
+            mint: self.input_mint,
+            amount: self.fees_in_mint(&self.input_mint),
+        }
+    }
+
+    /// Fees charged in the output mint, transfer fees excluded
+    pub fn output_fees(&self) -> TokenAmount {
+        TokenAmount {
+            mint: self.output_mint,
+            amount: self.fees_in_mint(&self.output_mint),
+        }
+    }
+
+    /// All fees expressed in the input mint. Output fees are converted at the
+    /// rate of the swap itself, i.e. the amount received by the pool over the
+    /// amount released by the curve, and rounded up.
+    pub fn fees_in_input_mint(&self) -> Result<u64> {
+        let output_fees = self.output_fees().amount;
+        let curve_amount_out = U128::from(self.amount_out_before_transfer_fee)
+            .checked_add(output_fees.into())
+            .ok_or(CalculatorError::Overflow)?;
+        let converted_output_fees = if curve_amount_out.is_zero() {
+            U128::zero()
+        } else {
+            U128::from(output_fees)
+                .checked_mul_div(
+                    self.amount_in_after_transfer_fee.into(),
+                    curve_amount_out,
+                    RoundDirection::Ceiling,
+                )
+                .ok_or(CalculatorError::Overflow)?
+        };
+        Ok(converted_output_fees
+            .checked_add(self.input_fees().amount.into())
+            .ok_or(CalculatorError::Overflow)?
+            .checked_as_u64()
+            .ok_or(CalculatorError::Overflow)?)
+    }
+}
+
+/// Reserves after the curve step, and its prices. The LP swap fee stays in
+/// the pool, while taxes and protocol fees leave it.
+fn reserves_and_prices<Curve: SwapCurve>(
+    curve: &Curve,
+    context: &SwapContext,
+    curve_amount_in: U128,
+    curve_amount_out: U128,
+    swap_fee: U128,
+) -> Result<(Reserves, SwapPrices)> {
+    let reserves_after = context.reserves.after_swap(
+        context.swap_direction,
+        curve_amount_in
+            .checked_add(swap_fee)
+            .ok_or(CalculatorError::Overflow)?,
+        curve_amount_out,
+    )?;
+    let prices = SwapPrices::new(
+        curve,
+        context.swap_direction,
+        &context.reserves,
+        &reserves_after,
+        curve_amount_in,
+        curve_amount_out,
+    )?
+    .to_ui(context.base_decimals, context.quote_decimals);
+    Ok((reserves_after, prices))
+}
+
 pub fn quote_exact_out(
     amount_out: u64,
-    swap_direction: SwapDirection,
-    external_fee_direction: ProtocolSwapFeeDirection,
-    taxation_mode: TaxationMode,
-    base_token_amount: u64,
-    quote_token_amount: u64,
-    swap_fee_numerator: u64,
-    swap_fee_denominator: u64,
-    protocol_swap_fee_numerator: u64,
-    protocol_swap_fee_denominator: u64,
-    buy_tax: u64,
-    sell_tax: u64,
-    base_token_transfer_fee: TransferFee,
-    quote_token_transfer_fee: TransferFee,
+    context: &SwapContext,
+    transfer_fees: &TransferFees,
     slippage_numerator: u64,
-) -> Result<(u64, u64, u64)> {
-    let (input_transfer_fee, output_transfer_fee) = match swap_direction {
-        SwapDirection::Base2Quote => (base_token_transfer_fee, quote_token_transfer_fee),
-        SwapDirection::Quote2Base => (quote_token_transfer_fee, base_token_transfer_fee),
-    };
+) -> Result<QuoteResult> {
+    quote_exact_out_with_curve(
+        &ConstantProduct,
+        amount_out,
+        context,
+        transfer_fees,
+        slippage_numerator,
+    )
+}
+
+/// Decaying taxes of `context` must have been resolved with
+/// [`SwapContext::at`].
+pub fn quote_exact_out_with_curve<Curve: SwapCurve>(
+    curve: &Curve,
+    amount_out: u64,
+    context: &SwapContext,
+    transfer_fees: &TransferFees,
+    slippage_numerator: u64,
+) -> Result<QuoteResult> {
+    transfer_fees.validate()?;
+    let (input_transfer_fee, output_transfer_fee) =
+        transfer_fees.for_direction(&context.swap_direction);
 
     let amount_out_transfer_fee = output_transfer_fee
         .calculate_inverse_fee(amount_out)
-        .ok_or_else(|| {
-            anyhow::anyhow!(
-                "Failed to calculate transfer fee for amount_out: {}",
-                amount_out
-            )
-        })?;
+        .ok_or(CalculatorError::Overflow)?;
 
     let amount_out_after_transfer_fee = amount_out
         .checked_add(amount_out_transfer_fee)
-        .ok_or_else(|| {
-            anyhow::anyhow!("Failed to add transfer fee to amount_out: {}", amount_out)
-        })?;
+        .ok_or(CalculatorError::Overflow)?;
 
-    let result = TokenSwapCalculator::<ConstantProduct>::swap_out(
+    let result = TokenSwapCalculator::<Curve>::swap_out_with_curve(
+        curve,
         amount_out_after_transfer_fee,
-        &swap_direction,
-        &external_fee_direction,
-        &taxation_mode,
-        base_token_amount,
-        quote_token_amount,
-        swap_fee_numerator,
-        swap_fee_denominator,
-        protocol_swap_fee_numerator,
-        protocol_swap_fee_denominator,
-        buy_tax,
-        sell_tax,
+        context,
     )?;
 
     let amount_in_transfer_fee = input_transfer_fee
-        .calculate_inverse_fee(result.swap_amount_in_after_fees.as_u64())
-        .ok_or_else(|| {
-            anyhow::anyhow!(
-                "Failed to calculate transfer fee for amount_in: {}",
-                result.swap_amount_in_after_fees.as_u64()
-            )
-        })?;
+        .calculate_inverse_fee(
+            result
+                .swap_amount_in_after_fees
+                .checked_as_u64()
+                .ok_or(CalculatorError::Overflow)?,
+        )
+        .ok_or(CalculatorError::Overflow)?;
 
     let amount_in_after_transfer_fee = result
         .swap_amount_in_after_fees
         .checked_add(amount_in_transfer_fee.into())
-        .ok_or_else(|| anyhow::anyhow!("Failed to add transfer fee to amount_in"))?;
+        .ok_or(CalculatorError::Overflow)?;
 
+    // Round the tolerance up so it is never narrower than requested
     let slippage_amount = amount_in_after_transfer_fee
-        .checked_mul(slippage_numerator.into())
-        .ok_or_else(|| anyhow::anyhow!("Failed to calculate slippage amount"))?
-        .checked_ceil_div(10000.into())
-        .ok_or_else(|| anyhow::anyhow!("Failed to calculate slippage amount"))?
-        .0;
+        .checked_mul_div(
+            slippage_numerator.into(),
+            10000.into(),
+            RoundDirection::Ceiling,
+        )
+        .ok_or(CalculatorError::Overflow)?;
 
     let maximum_amount_in = amount_in_after_transfer_fee
         .checked_add(slippage_amount)
-        .ok_or_else(|| anyhow::anyhow!("Failed to calculate maximum amount in"))?
-        .as_u64();
-
-    let total_fees = match (taxation_mode, swap_direction) {
-        (TaxationMode::Base, SwapDirection::Base2Quote)
-        | (TaxationMode::Quote, SwapDirection::Quote2Base) => {
-            let total = result.protocol_swap_fee_on_input_amount
-                + result.swap_tax_on_input_amount
-                + result.swap_fee;
-            total
-        }
-        (TaxationMode::Quote, SwapDirection::Base2Quote)
-        | (TaxationMode::Base, SwapDirection::Quote2Base) => {
-            let total =
-                result.protocol_swap_fee_on_output_amount + result.swap_tax_on_output_amount;
-            total
-        }
-        _ => result.swap_fee,
-    };
+        .ok_or(CalculatorError::Overflow)?
+        .checked_as_u64()
+        .ok_or(CalculatorError::Overflow)?;
+
+    let amount_in = amount_in_after_transfer_fee
+        .checked_as_u64()
+        .ok_or(CalculatorError::Overflow)?;
+
+    let (reserves_after, prices) = reserves_and_prices(
+        curve,
+        context,
+        result.swap_amount_in_before_fees,
+        result.swap_amount_out_after_fees,
+        result.swap_fee,
+    )?;
 
-    Ok((
+    Ok(QuoteResult {
+        input_mint: context.input_mint(),
+        output_mint: context.output_mint(),
+        amount_in,
+        amount_in_after_transfer_fee: amount_in
+            .checked_sub(amount_in_transfer_fee)
+            .ok_or(CalculatorError::Overflow)?,
+        amount_out_before_transfer_fee: amount_out_after_transfer_fee,
+        amount_out,
+        input_transfer_fee: TokenAmount::new(context.input_mint(), amount_in_transfer_fee.into())?,
+        output_transfer_fee: TokenAmount::new(
+            context.output_mint(),
+            amount_out_transfer_fee.into(),
+        )?,
+        swap_fee: TokenAmount::new(context.input_mint(), result.swap_fee)?,
+        swap_tax: TokenAmount::new(
+            context.tax_mint(),
+            result
+                .swap_tax_on_input_amount
+                .checked_add(result.swap_tax_on_output_amount)
+                .ok_or(CalculatorError::Overflow)?,
+        )?,
+        protocol_tax: TokenAmount::new(
+            context.tax_mint(),
+            result
+                .protocol_tax_on_input_amount
+                .checked_add(result.protocol_tax_on_output_amount)
+                .ok_or(CalculatorError::Overflow)?,
+        )?,
+        protocol_swap_fee: TokenAmount::new(
+            context.protocol_swap_fee_mint(),
+            result
+                .protocol_swap_fee_on_input_amount
+                .checked_add(result.protocol_swap_fee_on_output_amount)
+                .ok_or(CalculatorError::Overflow)?,
+        )?,
+        minimum_amount_out: amount_out,
         maximum_amount_in,
-        amount_in_after_transfer_fee.as_u64(),
-        total_fees.as_u64(),
-    ))
+        reserves_after,
+        prices,
+    })
+}
+
+/// [`quote_exact_out`] with the transfer fees in force at `epoch`, which may
+/// be past an upcoming transfer fee change
+pub fn quote_exact_out_at_epoch(
+    amount_out: u64,
+    context: &SwapContext,
+    transfer_fees: &EpochTransferFees,
+    epoch: u64,
+    slippage_numerator: u64,
+) -> Result<QuoteResult> {
+    quote_exact_out(
+        amount_out,
+        context,
+        &transfer_fees.at(epoch)?,
+        slippage_numerator,
+    )
 }
 
 pub fn quote_exact_in(
     amount_in: u64,
-    swap_direction: SwapDirection,
-    protocol_swap_fee_direction: ProtocolSwapFeeDirection,
-    taxation_mode: TaxationMode,
-    base_token_amount: u64,
-    quote_token_amount: u64,
-    swap_fee_numerator: u64,
-    swap_fee_denominator: u64,
-    protocol_swap_fee_numerator: u64,
-    protocol_swap_fee_denominator: u64,
-    buy_tax: u64,
-    sell_tax: u64,
-    base_token_transfer_fee: TransferFee,
-    quote_token_transfer_fee: TransferFee,
+    context: &SwapContext,
+    transfer_fees: &TransferFees,
     slippage_numerator: u64,
-) -> Result<(u64, u64, u64)> {
-    let (input_transfer_fee, output_transfer_fee) = match swap_direction {
-        SwapDirection::Base2Quote => (base_token_transfer_fee, quote_token_transfer_fee),
-        SwapDirection::Quote2Base => (quote_token_transfer_fee, base_token_transfer_fee),
-    };
-    let amount_in_transfer_fee = input_transfer_fee.calculate_fee(amount_in).ok_or_else(|| {
-        anyhow::anyhow!(
-            "Failed to calculate transfer fee for amount_in: {}",
-            amount_in
-        )
-    })?;
+) -> Result<QuoteResult> {
+    quote_exact_in_with_curve(
+        &ConstantProduct,
+        amount_in,
+        context,
+        transfer_fees,
+        slippage_numerator,
+    )
+}
+
+/// [`quote_exact_in`] with the transfer fees in force at `epoch`, which may
+/// be past an upcoming transfer fee change
+pub fn quote_exact_in_at_epoch(
+    amount_in: u64,
+    context: &SwapContext,
+    transfer_fees: &EpochTransferFees,
+    epoch: u64,
+    slippage_numerator: u64,
+) -> Result<QuoteResult> {
+    quote_exact_in(
+        amount_in,
+        context,
+        &transfer_fees.at(epoch)?,
+        slippage_numerator,
+    )
+}
+
+/// Decaying taxes of `context` must have been resolved with
+/// [`SwapContext::at`].
+pub fn quote_exact_in_with_curve<Curve: SwapCurve>(
+    curve: &Curve,
+    amount_in: u64,
+    context: &SwapContext,
+    transfer_fees: &TransferFees,
+    slippage_numerator: u64,
+) -> Result<QuoteResult> {
+    transfer_fees.validate()?;
+    let (input_transfer_fee, output_transfer_fee) =
+        transfer_fees.for_direction(&context.swap_direction);
+    let amount_in_transfer_fee = input_transfer_fee
+        .calculate_fee(amount_in)
+        .ok_or(CalculatorError::Overflow)?;
 
     let amount_in_after_deduct_transfer_fee = amount_in.saturating_sub(amount_in_transfer_fee);
 
-    let result = TokenSwapCalculator::<ConstantProduct>::swap_in(
+    let result = TokenSwapCalculator::<Curve>::swap_in_with_curve(
+        curve,
         amount_in_after_deduct_transfer_fee,
-        &swap_direction,
-        &protocol_swap_fee_direction,
-        &taxation_mode,
-        base_token_amount,
-        quote_token_amount,
-        swap_fee_numerator,
-        swap_fee_denominator,
-        protocol_swap_fee_numerator,
-        protocol_swap_fee_denominator,
-        buy_tax,
-        sell_tax,
+        context,
     )?;
 
+    let amount_out_before_transfer_fee = result
+        .swap_amount_out_after_fees
+        .checked_as_u64()
+        .ok_or(CalculatorError::Overflow)?;
     let amount_out_transfer_fee = output_transfer_fee
-        .calculate_fee(result.swap_amount_out_after_fees.as_u64())
-        .ok_or_else(|| {
-            anyhow::anyhow!(
-                "Failed to calculate transfer fee for amount_out: {}",
-                result.swap_amount_out_after_fees.as_u64()
-            )
-        })?;
+        .calculate_fee(amount_out_before_transfer_fee)
+        .ok_or(CalculatorError::Overflow)?;
 
     let swap_amount_out_after_deduct_transfer_fees = result
         .swap_amount_out_after_fees
         .checked_sub(amount_out_transfer_fee.into())
-        .ok_or_else(|| anyhow::anyhow!("Failed to subtract transfer fee from amount_out"))?;
+        .ok_or(CalculatorError::FeeExceedsInput)?;
 
+    // Round the tolerance up so it is never narrower than requested
     let slippage_amount = swap_amount_out_after_deduct_transfer_fees
-        .checked_mul(slippage_numerator.into())
-        .ok_or_else(|| anyhow::anyhow!("Failed to calculate slippage amount"))?
-        .checked_ceil_div(10000.into())
-        .ok_or_else(|| anyhow::anyhow!("Failed to calculate slippage amount"))?
-        .0;
+        .checked_mul_div(
+            slippage_numerator.into(),
+            10000.into(),
+            RoundDirection::Ceiling,
+        )
+        .ok_or(CalculatorError::Overflow)?;
 
     let minimum_amount_out = swap_amount_out_after_deduct_transfer_fees
         .checked_sub(slippage_amount)
-        .ok_or_else(|| anyhow::anyhow!("Failed to calculate minimum amount out"))?
-        .as_u64();
-
-    let total_fees = match (taxation_mode, swap_direction) {
-        (TaxationMode::Base, SwapDirection::Base2Quote)
-        | (TaxationMode::Quote, SwapDirection::Quote2Base) => {
-            let total = result.protocol_swap_fee_on_input_amount
-                + result.swap_tax_on_input_amount
-                + result.swap_fee;
-            total
+        .ok_or(CalculatorError::Overflow)?
+        .checked_as_u64()
+        .ok_or(CalculatorError::Overflow)?;
+
+    let (reserves_after, prices) = reserves_and_prices(
+        curve,
+        context,
+        result.swap_amount_in_after_fees,
+        result.swap_amount_out_before_fees,
+        result.swap_fee,
+    )?;
+
+    Ok(QuoteResult {
+        input_mint: context.input_mint(),
+        output_mint: context.output_mint(),
+        amount_in,
+        amount_in_after_transfer_fee: amount_in_after_deduct_transfer_fee,
+        amount_out_before_transfer_fee,
+        amount_out: swap_amount_out_after_deduct_transfer_fees
+            .checked_as_u64()
+            .ok_or(CalculatorError::Overflow)?,
+        input_transfer_fee: TokenAmount::new(context.input_mint(), amount_in_transfer_fee.into())?,
+        output_transfer_fee: TokenAmount::new(
+            context.output_mint(),
+            amount_out_transfer_fee.into(),
+        )?,
+        swap_fee: TokenAmount::new(context.input_mint(), result.swap_fee)?,
+        swap_tax: TokenAmount::new(
+            context.tax_mint(),
+            result
+                .swap_tax_on_input_amount
+                .checked_add(result.swap_tax_on_output_amount)
+                .ok_or(CalculatorError::Overflow)?,
+        )?,
+        protocol_tax: TokenAmount::new(
+            context.tax_mint(),
+            result
+                .protocol_tax_on_input_amount
+                .checked_add(result.protocol_tax_on_output_amount)
+                .ok_or(CalculatorError::Overflow)?,
+        )?,
+        protocol_swap_fee: TokenAmount::new(
+            context.protocol_swap_fee_mint(),
+            result
+                .protocol_swap_fee_on_input_amount
+                .checked_add(result.protocol_swap_fee_on_output_amount)
+                .ok_or(CalculatorError::Overflow)?,
+        )?,
+        minimum_amount_out,
+        maximum_amount_in: amount_in,
+        reserves_after,
+        prices,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calculator::{
+        fee_mode::{AdaptiveFee, FeeMode, FeeTier, FeeTiers, MAX_FEE_TIERS},
+        swap_context::{EpochTransferFee, FeeSchedule, Reserves, TaxSchedule},
+        swap_direction::SwapDirection,
+        tax_decay::{DecayingTax, TaxDecay, TaxDecaySchedule},
+        taxation_mode::TaxationMode,
+        ProtocolSwapFeeDirection, StableSwap, VirtualConstantProduct,
+    };
+    use crate::TEN_THOUSAND;
+    use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::TransferFee;
+    use proptest::prelude::*;
+
+    fn swap_direction() -> impl Strategy<Value = SwapDirection> {
+        prop_oneof![
+            Just(SwapDirection::Base2Quote),
+            Just(SwapDirection::Quote2Base)
+        ]
+    }
+
+    fn taxation_mode() -> impl Strategy<Value = TaxationMode> {
+        prop_oneof![
+            Just(TaxationMode::None),
+            Just(TaxationMode::Base),
+            Just(TaxationMode::Quote)
+        ]
+    }
+
+    fn protocol_swap_fee_direction() -> impl Strategy<Value = ProtocolSwapFeeDirection> {
+        prop_oneof![
+            Just(ProtocolSwapFeeDirection::None),
+            Just(ProtocolSwapFeeDirection::Base),
+            Just(ProtocolSwapFeeDirection::Quote)
+        ]
+    }
+
+    /// Any `u64`, biased towards the edges where conversions overflow
+    fn amount() -> impl Strategy<Value = u64> + Clone {
+        prop_oneof![any::<u64>(), 0..=10_000u64, (u64::MAX - 10_000)..=u64::MAX]
+    }
+
+    /// Static, tiered or adaptive, with tier fees and adaptive parameters
+    /// drawn from `fee_numerator`
+    fn fee_mode(
+        fee_numerator: impl Strategy<Value = u64> + Clone,
+        volatility_bps: impl Strategy<Value = u64>,
+    ) -> impl Strategy<Value = FeeMode> {
+        let tiers = prop::collection::vec(
+            (1..=u64::MAX / 16, fee_numerator.clone()),
+            1..=MAX_FEE_TIERS,
+        )
+        .prop_map(|tiers| {
+            let mut minimum_amount_in = 0u64;
+            let tiers = tiers
+                .into_iter()
+                .enumerate()
+                .map(|(index, (gap, swap_fee_numerator))| {
+                    if index > 0 {
+                        minimum_amount_in += gap;
+                    }
+                    FeeTier {
+                        minimum_amount_in,
+                        swap_fee_numerator,
+                    }
+                })
+                .collect::<Vec<_>>();
+            FeeMode::Tiered(FeeTiers::new(&tiers).unwrap())
+        });
+        let adaptive = (
+            (fee_numerator.clone(), 1..=u64::MAX),
+            fee_numerator,
+            volatility_bps,
+        )
+            .prop_map(
+                |(
+                    (sensitivity_numerator, sensitivity_denominator),
+                    max_swap_fee_numerator,
+                    volatility_bps,
+                )| {
+                    FeeMode::Adaptive(AdaptiveFee {
+                        sensitivity_numerator,
+                        sensitivity_denominator,
+                        max_swap_fee_numerator,
+                        volatility_bps,
+                    })
+                },
+            );
+        prop_oneof![Just(FeeMode::Static), tiers, adaptive]
+    }
+
+    /// A decaying buy or sell tax, valid when `tax` and `duration` are
+    fn decaying_tax(
+        tax: impl Strategy<Value = u64> + Clone,
+        duration: impl Strategy<Value = u64> + Clone,
+    ) -> impl Strategy<Value = DecayingTax> {
+        let decay = prop_oneof![
+            Just(TaxDecay::None),
+            duration
+                .clone()
+                .prop_map(|duration| TaxDecay::Linear { duration }),
+            (duration.clone(), duration.clone()).prop_map(|(step_duration, steps)| {
+                TaxDecay::Stepwise {
+                    step_duration,
+                    steps,
+                }
+            }),
+            duration.prop_map(|half_life| TaxDecay::Exponential { half_life }),
+        ];
+        (tax.clone(), tax, decay).prop_map(|(start, floor, decay)| DecayingTax {
+            start: start.max(floor),
+            floor: start.min(floor),
+            decay,
+        })
+    }
+
+    fn tax_decay(
+        tax: impl Strategy<Value = u64> + Clone,
+        duration: impl Strategy<Value = u64> + Clone,
+    ) -> impl Strategy<Value = Option<TaxDecaySchedule>> {
+        prop::option::of(
+            (
+                amount(),
+                decaying_tax(tax.clone(), duration.clone()),
+                decaying_tax(tax, duration),
+            )
+                .prop_map(|(open_at, buy_tax, sell_tax)| TaxDecaySchedule {
+                    open_at,
+                    buy_tax,
+                    sell_tax,
+                }),
+        )
+    }
+
+    /// Resolves the tax decay of `context` at `timestamp`, or leaves a decay
+    /// `at` rejects in place for the quote to reject
+    fn resolved(context: SwapContext, timestamp: u64) -> SwapContext {
+        context.at(timestamp).unwrap_or(context)
+    }
+
+    fn context() -> impl Strategy<Value = SwapContext> {
+        (
+            (
+                swap_direction(),
+                protocol_swap_fee_direction(),
+                taxation_mode(),
+            ),
+            (amount(), amount()),
+            (amount(), amount(), amount(), amount()),
+            (amount(), amount(), amount(), amount()),
+            fee_mode(amount(), amount()),
+            (tax_decay(amount(), amount()), amount()),
+        )
+            .prop_map(
+                |(
+                    (swap_direction, protocol_swap_fee_direction, taxation_mode),
+                    (base, quote),
+                    (
+                        swap_fee_numerator,
+                        swap_fee_denominator,
+                        protocol_swap_fee_numerator,
+                        protocol_swap_fee_denominator,
+                    ),
+                    (buy_tax, sell_tax, protocol_tax_numerator, protocol_tax_denominator),
+                    fee_mode,
+                    (tax_decay, timestamp),
+                )| {
+                    let context = SwapContext {
+                        swap_direction,
+                        protocol_swap_fee_direction,
+                        taxation_mode,
+                        reserves: Reserves::new(base, quote),
+                        fee_schedule: FeeSchedule {
+                            swap_fee_numerator,
+                            swap_fee_denominator,
+                            protocol_swap_fee_numerator,
+                            protocol_swap_fee_denominator,
+                        },
+                        fee_mode,
+                        tax_schedule: TaxSchedule {
+                            buy_tax,
+                            sell_tax,
+                            protocol_tax_numerator,
+                            protocol_tax_denominator,
+                        },
+                        tax_decay,
+                        ..Default::default()
+                    };
+                    resolved(context, timestamp)
+                },
+            )
+    }
+
+    /// A context the calculator accepts: fees and taxes up to 100% and
+    /// reserves deep enough to quote against
+    fn valid_context() -> impl Strategy<Value = SwapContext> {
+        (
+            (
+                swap_direction(),
+                protocol_swap_fee_direction(),
+                taxation_mode(),
+            ),
+            (
+                1_000..=1_000_000_000_000_000u64,
+                1_000..=1_000_000_000_000_000u64,
+            ),
+            (0..=1_000u64, 0..=1_000u64),
+            (0..=TEN_THOUSAND, 0..=TEN_THOUSAND, 0..=100u64),
+            fee_mode(0..=1_000u64, 0..=10_000u64),
+            (tax_decay(0..=TEN_THOUSAND, 1..=1_000_000u64), amount()),
+        )
+            .prop_map(
+                |(
+                    (swap_direction, protocol_swap_fee_direction, taxation_mode),
+                    (base, quote),
+                    (swap_fee_numerator, protocol_swap_fee_numerator),
+                    (buy_tax, sell_tax, protocol_tax_numerator),
+                    fee_mode,
+                    (tax_decay, timestamp),
+                )| {
+                    let context = SwapContext {
+                        swap_direction,
+                        protocol_swap_fee_direction,
+                        taxation_mode,
+                        reserves: Reserves::new(base, quote),
+                        fee_schedule: FeeSchedule::new(
+                            swap_fee_numerator,
+                            10_000,
+                            protocol_swap_fee_numerator,
+                            10_000,
+                        )
+                        .unwrap(),
+                        fee_mode,
+                        tax_schedule: TaxSchedule::new(buy_tax, sell_tax)
+                            .unwrap()
+                            .with_protocol_tax(protocol_tax_numerator, 100)
+                            .unwrap(),
+                        tax_decay,
+                        ..Default::default()
+                    };
+                    context.at(timestamp).unwrap()
+                },
+            )
+    }
+
+    fn transfer_fee() -> impl Strategy<Value = TransferFee> {
+        (any::<u16>(), amount()).prop_map(|(transfer_fee_basis_points, maximum_fee)| TransferFee {
+            epoch: 0.into(),
+            maximum_fee: maximum_fee.into(),
+            transfer_fee_basis_points: transfer_fee_basis_points.into(),
+        })
+    }
+
+    fn transfer_fees() -> impl Strategy<Value = TransferFees> {
+        (transfer_fee(), transfer_fee()).prop_map(|(base, quote)| TransferFees { base, quote })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(2_000))]
+
+        #[test]
+        fn test_quote_never_panics(
+            amount in amount(),
+            context in context(),
+            transfer_fees in transfer_fees(),
+            slippage_numerator in amount(),
+        ) {
+            let _ = quote_exact_in(amount, &context, &transfer_fees, slippage_numerator);
+            let _ = quote_exact_out(amount, &context, &transfer_fees, slippage_numerator);
         }
-        (TaxationMode::Quote, SwapDirection::Base2Quote)
-        | (TaxationMode::Base, SwapDirection::Quote2Base) => {
-            let total =
-                result.protocol_swap_fee_on_output_amount + result.swap_tax_on_output_amount;
-            total
+
+        #[test]
+        fn test_quote_with_curves_never_panics(
+            amount in amount(),
+            context in context(),
+            transfer_fees in transfer_fees(),
+            slippage_numerator in 0..=10_000u64,
+            amplification_coefficient in amount(),
+            (virtual_base_reserve, virtual_quote_reserve) in (amount(), amount()),
+        ) {
+            let stable_swap = StableSwap::new(amplification_coefficient);
+            let virtual_constant_product =
+                VirtualConstantProduct::new(virtual_base_reserve, virtual_quote_reserve, u64::MAX);
+
+            let _ = quote_exact_in_with_curve(
+                &stable_swap, amount, &context, &transfer_fees, slippage_numerator,
+            );
+            let _ = quote_exact_out_with_curve(
+                &stable_swap, amount, &context, &transfer_fees, slippage_numerator,
+            );
+            let _ = quote_exact_in_with_curve(
+                &virtual_constant_product, amount, &context, &transfer_fees, slippage_numerator,
+            );
+            let _ = quote_exact_out_with_curve(
+                &virtual_constant_product, amount, &context, &transfer_fees, slippage_numerator,
+            );
         }
-        _ => U128::from(0),
-    };
 
-    Ok((
-        minimum_amount_out,
-        swap_amount_out_after_deduct_transfer_fees.as_u64(),
-        total_fees.as_u64(),
-    ))
+        #[test]
+        fn test_exact_in_and_exact_out_agree(
+            amount in 1..=1_000_000_000_000u64,
+            context in valid_context(),
+        ) {
+            let transfer_fees = TransferFees::default();
+            let Ok(quote) = quote_exact_in(amount, &context, &transfer_fees, 0) else {
+                return Ok(());
+            };
+            prop_assert!(quote.protocol_tax.amount <= quote.swap_tax.amount);
+
+            // Exact output fees and taxes are charged on the smaller net
+            // amounts, so the output of an exact input quote costs no more
+            // than its input. Only a fee tier boundary in between can make
+            // the exact output quote charge a dearer tier.
+            let exact_out = quote_exact_out(quote.amount_out, &context, &transfer_fees, 0);
+            prop_assert!(exact_out.is_ok(), "{:?} for {:?}", exact_out, quote);
+            let exact_out = exact_out.unwrap();
+            prop_assert_eq!(exact_out.amount_out, quote.amount_out);
+            prop_assert!(exact_out.protocol_tax.amount <= exact_out.swap_tax.amount);
+            if !matches!(context.fee_mode, FeeMode::Tiered(_)) {
+                prop_assert!(
+                    exact_out.amount_in <= amount,
+                    "{:?} for {:?}",
+                    exact_out,
+                    quote
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_maximum_amount_in_overflow_is_an_error() {
+        let context = SwapContext {
+            swap_direction: SwapDirection::Quote2Base,
+            reserves: Reserves::new(u64::MAX, u64::MAX),
+            fee_schedule: FeeSchedule::new(0, 1, 0, 1).unwrap(),
+            ..Default::default()
+        };
+
+        let error =
+            quote_exact_out(u64::MAX - 1, &context, &TransferFees::default(), 10_000).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CalculatorError>(),
+            Some(&CalculatorError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_quote_result_tags_fees_with_their_mint() {
+        let context = SwapContext {
+            swap_direction: SwapDirection::Base2Quote,
+            protocol_swap_fee_direction: ProtocolSwapFeeDirection::Quote,
+            taxation_mode: TaxationMode::Quote,
+            reserves: Reserves::new(1_000_000_000, 1_000_000_000),
+            fee_schedule: FeeSchedule::new(25, 10_000, 10, 10_000).unwrap(),
+            fee_mode: FeeMode::Static,
+            tax_schedule: TaxSchedule::new(0, 500)
+                .unwrap()
+                .with_protocol_tax(1, 10)
+                .unwrap(),
+            base_mint: Pubkey::new_unique(),
+            quote_mint: Pubkey::new_unique(),
+            base_decimals: 9,
+            quote_decimals: 6,
+            tax_decay: None,
+        };
+        let transfer_fees = TransferFees {
+            base: TransferFee {
+                epoch: 0.into(),
+                maximum_fee: u64::MAX.into(),
+                transfer_fee_basis_points: 100.into(),
+            },
+            ..Default::default()
+        };
+
+        let result = quote_exact_in(1_000_000, &context, &transfer_fees, 100).unwrap();
+
+        assert_eq!(result.input_transfer_fee.mint, context.base_mint);
+        assert_eq!(result.input_transfer_fee.amount, 10_000);
+        assert_eq!(result.amount_in_after_transfer_fee, 990_000);
+        assert_eq!(result.swap_fee.mint, context.base_mint);
+        assert_eq!(result.swap_fee.amount, 2_475);
+        assert_eq!(result.swap_tax.mint, context.quote_mint);
+        assert_eq!(result.protocol_tax.mint, context.quote_mint);
+        assert_eq!(result.protocol_swap_fee.mint, context.quote_mint);
+        assert_eq!(
+            result.fees_in_mint(&context.quote_mint),
+            result.swap_tax.amount + result.protocol_swap_fee.amount
+        );
+        assert_eq!(result.fees_in_mint(&context.base_mint), 2_475);
+        assert_eq!(result.input_fees().amount, 2_475);
+        assert_eq!(
+            result.output_fees().amount,
+            result.fees_in_mint(&context.quote_mint)
+        );
+        // A 5% tax, the protocol's cut included, and a 0.1% protocol fee on
+        // top of the 0.25% swap fee, all converted to the input mint
+        let fees = result.fees_in_input_mint().unwrap() as f64;
+        let expected = 0.0025 + 0.05 + 0.001;
+        assert!((fees / result.amount_in_after_transfer_fee as f64 - expected).abs() < 0.000_01);
+        assert_eq!(result.amount_out, result.amount_out_before_transfer_fee);
+        assert!(result.minimum_amount_out < result.amount_out);
+        assert_eq!(result.maximum_amount_in, result.amount_in);
+        assert!(result.prices.price_impact_bps > 0.0 && result.prices.price_impact_bps < 10.0);
+        assert_eq!(result.prices.spot_price_before, 1_000.0);
+        assert!(result.prices.execution_price < result.prices.spot_price_before);
+        assert!(result.prices.spot_price_after < result.prices.execution_price);
+
+        let result = quote_exact_out(900_000, &context, &transfer_fees, 100).unwrap();
+        assert_eq!(result.amount_out, 900_000);
+        assert_eq!(
+            result.amount_in,
+            result.amount_in_after_transfer_fee + result.input_transfer_fee.amount
+        );
+        assert_eq!(result.minimum_amount_out, result.amount_out);
+        assert!(result.maximum_amount_in > result.amount_in);
+    }
+
+    #[test]
+    fn test_protocol_tax_is_paid_out_of_swap_tax() {
+        let untaxed_protocol = SwapContext {
+            swap_direction: SwapDirection::Quote2Base,
+            taxation_mode: TaxationMode::Quote,
+            reserves: Reserves::new(1_000_000_000, 1_000_000_000),
+            fee_schedule: FeeSchedule::new(25, 10_000, 0, 1).unwrap(),
+            tax_schedule: TaxSchedule::new(300, 500).unwrap(),
+            ..Default::default()
+        };
+        let context = SwapContext {
+            tax_schedule: untaxed_protocol
+                .tax_schedule
+                .with_protocol_tax(1, 10)
+                .unwrap(),
+            ..untaxed_protocol
+        };
+
+        let result = quote_exact_in(1_000_000, &context, &TransferFees::default(), 0).unwrap();
+        assert_eq!(result.swap_tax.amount, 30_000);
+        assert_eq!(result.protocol_tax.amount, 3_000);
+        // 0.25% swap fee and 3% buy tax, the protocol's cut included
+        assert_eq!(result.input_fees().amount, 2_500 + 30_000);
+        assert_eq!(
+            result.amount_out,
+            quote_exact_in(1_000_000, &untaxed_protocol, &TransferFees::default(), 0)
+                .unwrap()
+                .amount_out
+        );
+
+        let result = quote_exact_out(1_000_000, &context, &TransferFees::default(), 0).unwrap();
+        let untaxed_protocol_result =
+            quote_exact_out(1_000_000, &untaxed_protocol, &TransferFees::default(), 0).unwrap();
+        assert_eq!(result.swap_tax, untaxed_protocol_result.swap_tax);
+        assert_eq!(
+            result.protocol_tax.amount,
+            result.swap_tax.amount.div_ceil(10)
+        );
+        assert_eq!(result.amount_in, untaxed_protocol_result.amount_in);
+    }
+
+    #[test]
+    fn test_quote_charges_decayed_tax_at_timestamp() {
+        let flat_context = |buy_tax| SwapContext {
+            swap_direction: SwapDirection::Quote2Base,
+            protocol_swap_fee_direction: ProtocolSwapFeeDirection::Quote,
+            taxation_mode: TaxationMode::Quote,
+            reserves: Reserves::new(1_000_000_000, 1_000_000_000),
+            fee_schedule: FeeSchedule::new(25, 10_000, 10, 10_000).unwrap(),
+            tax_schedule: TaxSchedule::new(buy_tax, 500).unwrap(),
+            ..Default::default()
+        };
+        let context = flat_context(0)
+            .with_tax_decay(
+                TaxDecaySchedule::new(
+                    1_000,
+                    DecayingTax::new(5_000, 100, TaxDecay::Linear { duration: 100 }).unwrap(),
+                    DecayingTax::flat(500),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        // At open, mid-decay, at the floor and long after it
+        for (timestamp, buy_tax) in [(1_000, 5_000), (1_050, 2_550), (1_100, 100), (9_999, 100)] {
+            let context = context.at(timestamp).unwrap();
+            let expected = flat_context(buy_tax);
+            assert_eq!(
+                quote_exact_in(1_000_000, &context, &TransferFees::default(), 0).unwrap(),
+                quote_exact_in(1_000_000, &expected, &TransferFees::default(), 0).unwrap()
+            );
+            assert_eq!(
+                quote_exact_out(1_000_000, &context, &TransferFees::default(), 0).unwrap(),
+                quote_exact_out(1_000_000, &expected, &TransferFees::default(), 0).unwrap()
+            );
+        }
+        // A context whose decay was never resolved would charge no buy tax
+        assert!(quote_exact_in(1_000_000, &context, &TransferFees::default(), 0).is_err());
+    }
+
+    #[test]
+    fn test_quote_with_zero_tax_decay_duration_is_an_error() {
+        let context = SwapContext {
+            swap_direction: SwapDirection::Quote2Base,
+            reserves: Reserves::new(1_000_000_000, 1_000_000_000),
+            tax_decay: Some(TaxDecaySchedule {
+                open_at: 1_000,
+                buy_tax: DecayingTax {
+                    start: 5_000,
+                    floor: 100,
+                    decay: TaxDecay::Linear { duration: 0 },
+                },
+                sell_tax: DecayingTax::flat(500),
+            }),
+            ..Default::default()
+        };
+
+        assert!(context.at(1_050).is_err());
+        assert!(quote_exact_in(1_000_000, &context, &TransferFees::default(), 0).is_err());
+        assert!(quote_exact_out(1_000_000, &context, &TransferFees::default(), 0).is_err());
+    }
+
+    #[test]
+    fn test_quote_charges_fee_tier_of_input_amount() {
+        let context = SwapContext {
+            swap_direction: SwapDirection::Quote2Base,
+            reserves: Reserves::new(1_000_000_000, 1_000_000_000),
+            fee_schedule: FeeSchedule::new(100, 10_000, 0, 1).unwrap(),
+            fee_mode: FeeMode::Tiered(
+                FeeTiers::new(&[
+                    FeeTier {
+                        minimum_amount_in: 0,
+                        swap_fee_numerator: 100,
+                    },
+                    FeeTier {
+                        minimum_amount_in: 1_000_000,
+                        swap_fee_numerator: 10,
+                    },
+                ])
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+        let quote_in =
+            |amount_in| quote_exact_in(amount_in, &context, &TransferFees::default(), 0).unwrap();
+
+        assert_eq!(quote_in(999_999).swap_fee.amount, 10_000);
+        assert_eq!(quote_in(1_000_000).swap_fee.amount, 1_000);
+
+        // Exact output fees are charged on the amount reaching the curve
+        let quote_out = |amount_out| {
+            let quote = quote_exact_out(amount_out, &context, &TransferFees::default(), 0).unwrap();
+            let curve_amount_in = quote.amount_in - quote.swap_fee.amount;
+            (quote.amount_in, quote.swap_fee.amount, curve_amount_in)
+        };
+        let (amount_in, swap_fee, curve_amount_in) = quote_out(500_000);
+        assert!(amount_in < 1_000_000);
+        assert_eq!(swap_fee, curve_amount_in.div_ceil(100));
+        let (amount_in, swap_fee, curve_amount_in) = quote_out(2_000_000);
+        assert!(amount_in >= 1_000_000);
+        assert_eq!(swap_fee, curve_amount_in.div_ceil(1_000));
+        // Just past the boundary with the higher fee, but short of it with
+        // the lower one: no tier applies to its own input, the larger is paid
+        let (amount_in, swap_fee, curve_amount_in) = quote_out(995_000);
+        assert!(amount_in >= 1_000_000);
+        assert_eq!(swap_fee, curve_amount_in.div_ceil(100));
+    }
+
+    #[test]
+    fn test_quote_at_future_epoch() {
+        let context = SwapContext {
+            swap_direction: SwapDirection::Base2Quote,
+            reserves: Reserves::new(1_000_000_000, 1_000_000_000),
+            fee_schedule: FeeSchedule::new(25, 10_000, 0, 1).unwrap(),
+            ..Default::default()
+        };
+        let newer = TransferFee {
+            epoch: 700.into(),
+            maximum_fee: u64::MAX.into(),
+            transfer_fee_basis_points: 50.into(),
+        };
+        let transfer_fees = EpochTransferFees::new(
+            EpochTransferFee::new(TransferFee::default(), newer),
+            EpochTransferFee::default(),
+        );
+
+        for (epoch, transfer_fee) in [(699, 0), (700, 5_000), (10_000, 5_000)] {
+            let quote =
+                quote_exact_in_at_epoch(1_000_000, &context, &transfer_fees, epoch, 0).unwrap();
+            assert_eq!(quote.input_transfer_fee.amount, transfer_fee);
+            assert_eq!(
+                quote,
+                quote_exact_in(1_000_000, &context, &transfer_fees.at(epoch).unwrap(), 0).unwrap()
+            );
+            let quote =
+                quote_exact_out_at_epoch(900_000, &context, &transfer_fees, epoch, 0).unwrap();
+            assert_eq!(
+                quote,
+                quote_exact_out(900_000, &context, &transfer_fees.at(epoch).unwrap(), 0).unwrap()
+            );
+        }
+    }
 }
@@ -0,0 +1,262 @@
+//! Inverse quotes: the exact input amount that takes a swap as far as a
+//! target allows.
+//!
+//! The solver binary searches over [`quote_exact_in`], so every fee, tax and
+//! Token-2022 transfer fee the quote charges is accounted for, and the
+//! returned quote always satisfies the target.
+
+use anyhow::Result;
+
+use crate::{
+    calculator::{
+        error::CalculatorError,
+        swap_context::{SwapContext, TransferFees},
+        swap_direction::SwapDirection,
+    },
+    quote::{quote_exact_in, QuoteResult},
+};
+
+/// Limit on the outcome of an exact input swap
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwapTarget {
+    /// Post-trade spot price, in UI units like [`QuoteResult::prices`], that
+    /// the swap must not cross: buying base raises the price, selling base
+    /// lowers it
+    SpotPrice(f64),
+    /// Price impact, in basis points, that the swap must not exceed
+    PriceImpactBps(f64),
+}
+
+impl SwapTarget {
+    fn is_satisfied_by(&self, swap_direction: SwapDirection, quote: &QuoteResult) -> bool {
+        match (self, swap_direction) {
+            (SwapTarget::SpotPrice(target), SwapDirection::Quote2Base) => {
+                quote.prices.spot_price_after <= *target
+            }
+            (SwapTarget::SpotPrice(target), SwapDirection::Base2Quote) => {
+                quote.prices.spot_price_after >= *target
+            }
+            (SwapTarget::PriceImpactBps(target), _) => quote.prices.price_impact_bps <= *target,
+        }
+    }
+}
+
+/// Largest exact input amount, up to the rounding of fees and reserves, whose
+/// quote satisfies `target`, along with that quote.
+///
+/// Returns `None` when no swap satisfies the target, e.g. when the spot price
+/// is already past it. Decaying taxes of `context` must have been resolved
+/// with [`SwapContext::at`].
+pub fn solve_amount_in(
+    target: SwapTarget,
+    context: &SwapContext,
+    transfer_fees: &TransferFees,
+) -> Result<Option<QuoteResult>> {
+    context.validate()?;
+    transfer_fees.validate()?;
+
+    let quote = |amount_in: u64| -> Result<Result<QuoteResult, Miss>> {
+        match quote_exact_in(amount_in, context, transfer_fees, 0) {
+            Ok(quote) if target.is_satisfied_by(context.swap_direction, &quote) => Ok(Ok(quote)),
+            Ok(_) => Ok(Err(Miss::Past)),
+            Err(error) => match error.downcast_ref::<CalculatorError>() {
+                Some(CalculatorError::ZeroOutput | CalculatorError::FeeExceedsInput) => {
+                    Ok(Err(Miss::TooSmall))
+                }
+                Some(CalculatorError::InsufficientLiquidity | CalculatorError::Overflow) => {
+                    Ok(Err(Miss::Past))
+                }
+                _ => Err(error),
+            },
+        }
+    };
+
+    if let Ok(quote) = quote(u64::MAX)? {
+        return Ok(Some(quote));
+    }
+    // `low` always satisfies the target, or is too small to pay anything out;
+    // `high` never does. Every satisfying amount is above a too small one, so
+    // those move the search up as well.
+    let (mut low, mut high) = (0u64, u64::MAX);
+    let mut best = None;
+    while high - low > 1 {
+        let middle = low + (high - low) / 2;
+        match quote(middle)? {
+            Ok(quote) => {
+                low = middle;
+                best = Some(quote);
+            }
+            Err(Miss::TooSmall) => low = middle,
+            Err(Miss::Past) => high = middle,
+        }
+    }
+    Ok(best)
+}
+
+/// Why an exact input amount does not satisfy the target
+enum Miss {
+    /// Too small to pay anything out
+    TooSmall,
+    /// Past the target, or too large for the pool
+    Past,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        calculator::{
+            swap_context::{FeeSchedule, Reserves, TaxSchedule},
+            taxation_mode::TaxationMode,
+            ProtocolSwapFeeDirection,
+        },
+        quote::quote_exact_out,
+    };
+    use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::TransferFee;
+    use proptest::prelude::*;
+
+    fn context(swap_direction: SwapDirection, (base, quote): (u64, u64)) -> SwapContext {
+        SwapContext {
+            swap_direction,
+            protocol_swap_fee_direction: ProtocolSwapFeeDirection::Quote,
+            taxation_mode: TaxationMode::Quote,
+            reserves: Reserves::new(base, quote),
+            fee_schedule: FeeSchedule::new(25, 10_000, 10, 10_000).unwrap(),
+            tax_schedule: TaxSchedule::new(300, 500)
+                .unwrap()
+                .with_protocol_tax(1, 10)
+                .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn transfer_fees() -> TransferFees {
+        let transfer_fee = |transfer_fee_basis_points: u16| TransferFee {
+            epoch: 0.into(),
+            maximum_fee: u64::MAX.into(),
+            transfer_fee_basis_points: transfer_fee_basis_points.into(),
+        };
+        TransferFees::new(transfer_fee(100), transfer_fee(50)).unwrap()
+    }
+
+    fn swap_direction() -> impl Strategy<Value = SwapDirection> {
+        prop_oneof![
+            Just(SwapDirection::Base2Quote),
+            Just(SwapDirection::Quote2Base)
+        ]
+    }
+
+    #[test]
+    fn test_price_impact_target() {
+        let context = context(
+            SwapDirection::Quote2Base,
+            (1_000_000_000_000, 1_000_000_000_000),
+        );
+
+        let quote = solve_amount_in(
+            SwapTarget::PriceImpactBps(100.0),
+            &context,
+            &transfer_fees(),
+        )
+        .unwrap()
+        .unwrap();
+
+        assert!(quote.prices.price_impact_bps <= 100.0);
+        assert!(quote.prices.price_impact_bps > 99.9);
+    }
+
+    #[test]
+    fn test_spot_price_target_already_crossed() {
+        let context = context(
+            SwapDirection::Quote2Base,
+            (1_000_000_000_000, 1_000_000_000_000),
+        );
+
+        let quote =
+            solve_amount_in(SwapTarget::SpotPrice(0.5), &context, &transfer_fees()).unwrap();
+
+        assert_eq!(quote, None);
+    }
+
+    #[test]
+    fn test_solution_behind_too_small_inputs() {
+        // A single base token costs about 1e15 quote, anything less pays out
+        // nothing. Binary searching from zero lands among those first.
+        let context = context(
+            SwapDirection::Quote2Base,
+            (1_000, 1_000_000_000_000_000_000),
+        );
+
+        let target = SwapTarget::PriceImpactBps(100.0);
+
+        let quote = solve_amount_in(target, &context, &TransferFees::default())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(quote.amount_out, 1);
+        assert!(quote.amount_in > 1_000_000_000_000_000);
+        let larger =
+            quote_exact_in(quote.amount_in + 1, &context, &TransferFees::default(), 0).unwrap();
+        assert!(!target.is_satisfied_by(SwapDirection::Quote2Base, &larger));
+    }
+
+    proptest! {
+        #[test]
+        fn test_solution_satisfies_target_and_is_largest(
+            swap_direction in swap_direction(),
+            reserves in prop_oneof![
+                (1_000..=u64::MAX / 4, 1_000..=u64::MAX / 4),
+                // One side so shallow that small swaps pay out nothing
+                (1..=10_000u64, 1_000_000_000_000..=u64::MAX / 4),
+                (1_000_000_000_000..=u64::MAX / 4, 1..=10_000u64),
+            ],
+            price_move in 0.0..1.0f64,
+            price_impact_bps in 0.0..10_000.0f64,
+        ) {
+            let context = context(swap_direction, reserves);
+            let spot_price = reserves.1 as f64 / reserves.0 as f64;
+            let target_price = match swap_direction {
+                SwapDirection::Quote2Base => spot_price * (1.0 + price_move),
+                SwapDirection::Base2Quote => spot_price * (1.0 - price_move),
+            };
+
+            for target in [
+                SwapTarget::SpotPrice(target_price),
+                SwapTarget::PriceImpactBps(price_impact_bps),
+            ] {
+                let solution = solve_amount_in(target, &context, &transfer_fees()).unwrap();
+                // Smaller inputs than a single unit fill pay out nothing, and
+                // when that fill satisfies the target, the solution is at
+                // least as large
+                let probe = quote_exact_out(1, &context, &transfer_fees(), 0)
+                    .and_then(|probe| quote_exact_in(probe.amount_in, &context, &transfer_fees(), 0));
+                if let Some(probe) = probe
+                    .ok()
+                    .filter(|probe| target.is_satisfied_by(swap_direction, probe))
+                {
+                    prop_assert!(
+                        solution.is_some_and(|solution| solution.amount_in >= probe.amount_in),
+                        "{:?} below {:?}",
+                        solution,
+                        probe
+                    );
+                }
+                let Some(quote) = solution else {
+                    continue;
+                };
+
+                // Re-checking the answer with a fresh quote
+                let recheck = quote_exact_in(quote.amount_in, &context, &transfer_fees(), 0).unwrap();
+                prop_assert_eq!(recheck, quote);
+                prop_assert!(target.is_satisfied_by(swap_direction, &recheck));
+
+                if quote.amount_in < u64::MAX {
+                    let larger = quote_exact_in(quote.amount_in + 1, &context, &transfer_fees(), 0);
+                    prop_assert!(
+                        larger.map_or(true, |larger| !target.is_satisfied_by(swap_direction, &larger))
+                    );
+                }
+            }
+        }
+    }
+}
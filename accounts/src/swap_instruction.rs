@@ -0,0 +1,261 @@
+//! Complete `swap_in` and `swap_out` instructions, to swap against a pool
+//! directly rather than through an aggregator.
+
+use anchor_lang::{
+    prelude::*, solana_program::instruction::Instruction, system_program::System, InstructionData,
+};
+use anchor_spl::{associated_token::AssociatedToken, token::Token};
+
+use crate::instructions::{
+    chainlink_feed_account, chainlink_program, seeds, LiquidityPoolState, SwapInParams,
+    SwapOutParams,
+};
+
+pub fn derive_authority() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[seeds::AUTHORITY.as_bytes()], &crate::ID)
+}
+
+pub fn derive_user_global_stats(user: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[seeds::USER_GLOBAL_STATS.as_bytes(), user.as_ref()],
+        &crate::ID,
+    )
+}
+
+pub fn derive_user_amm_stats(user: &Pubkey, pool_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            seeds::USER_AMM_STATS.as_bytes(),
+            user.as_ref(),
+            pool_id.as_ref(),
+        ],
+        &crate::ID,
+    )
+}
+
+pub fn derive_extras_account(
+    creator: &Pubkey,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            seeds::EXTRAS_ACCOUNT.as_bytes(),
+            creator.as_ref(),
+            base_mint.as_ref(),
+            quote_mint.as_ref(),
+        ],
+        &crate::ID,
+    )
+}
+
+/// Token program of a mint. Pools created before Token-2022 support leave it
+/// unset, their mints are all SPL Token mints.
+pub fn token_program_or_default(token_program: Pubkey) -> Pubkey {
+    if token_program == Pubkey::default() {
+        Token::id()
+    } else {
+        token_program
+    }
+}
+
+/// Builds the swap instructions of a user on a pool.
+///
+/// `swap_in` and `swap_out` take the same accounts; the direction of a swap
+/// is in its params, the user's base and quote token accounts are passed
+/// the same way either way.
+#[derive(Clone, Copy, Debug)]
+pub struct SwapInstructionBuilder {
+    pub pool_id: Pubkey,
+    pub state: LiquidityPoolState,
+    /// Signer owning the token accounts and paying for the user stats
+    pub user: Pubkey,
+    pub user_base_token_vault: Pubkey,
+    pub user_quote_token_vault: Pubkey,
+}
+
+macro_rules! swap_accounts {
+    ($builder:expr, $accounts:ident) => {{
+        let builder = $builder;
+        let state = &builder.state;
+        crate::accounts::$accounts {
+            token_program: Token::id(),
+            base_token_program: token_program_or_default(state.base_token_program),
+            quote_token_program: token_program_or_default(state.quote_token_program),
+            associated_token_program: AssociatedToken::id(),
+            system_program: System::id(),
+            liquidity_pool_state: builder.pool_id,
+            authority: derive_authority().0,
+            base_token_mint: state.base_token_mint,
+            quote_token_mint: state.quote_token_mint,
+            base_token_vault: state.base_token_vault,
+            quote_token_vault: state.quote_token_vault,
+            base_token_swap_tax_vault: state.base_token_swap_tax_vault,
+            quote_token_swap_tax_vault: state.quote_token_swap_tax_vault,
+            protocol_base_token_swap_fee_vault: state.protocol_base_token_swap_fee_vault,
+            protocol_quote_token_swap_fee_vault: state.protocol_quote_token_swap_fee_vault,
+            user: builder.user,
+            user_base_token_vault: builder.user_base_token_vault,
+            user_quote_token_vault: builder.user_quote_token_vault,
+            user_amm_stats: derive_user_amm_stats(&builder.user, &builder.pool_id).0,
+            user_global_stats: derive_user_global_stats(&builder.user).0,
+        }
+        .to_account_metas(None)
+    }};
+}
+
+impl SwapInstructionBuilder {
+    pub fn new(
+        pool_id: Pubkey,
+        state: &LiquidityPoolState,
+        user: Pubkey,
+        user_base_token_vault: Pubkey,
+        user_quote_token_vault: Pubkey,
+    ) -> Self {
+        Self {
+            pool_id,
+            state: *state,
+            user,
+            user_base_token_vault,
+            user_quote_token_vault,
+        }
+    }
+
+    /// Accounts read from the remaining accounts: the chainlink price feed
+    /// and program, and the pool's extras account
+    fn remaining_accounts(&self) -> [AccountMeta; 3] {
+        let extras_account = derive_extras_account(
+            &self.state.creator,
+            &self.state.base_token_mint,
+            &self.state.quote_token_mint,
+        )
+        .0;
+        [
+            AccountMeta::new_readonly(chainlink_feed_account::ID, false),
+            AccountMeta::new_readonly(chainlink_program::ID, false),
+            AccountMeta::new_readonly(extras_account, false),
+        ]
+    }
+
+    /// Accounts of a `swap_in`, remaining accounts included
+    pub fn swap_in_accounts(&self) -> Vec<AccountMeta> {
+        let mut accounts = swap_accounts!(self, SwapInAccounts);
+        accounts.extend(self.remaining_accounts());
+        accounts
+    }
+
+    /// Accounts of a `swap_out`, remaining accounts included
+    pub fn swap_out_accounts(&self) -> Vec<AccountMeta> {
+        let mut accounts = swap_accounts!(self, SwapOutAccounts);
+        accounts.extend(self.remaining_accounts());
+        accounts
+    }
+
+    /// Swaps exactly `params.amount_in` for at least `params.minimum_amount_out`
+    pub fn swap_in(&self, params: SwapInParams) -> Instruction {
+        Instruction {
+            program_id: crate::ID,
+            accounts: self.swap_in_accounts(),
+            data: crate::instruction::SwapIn { params }.data(),
+        }
+    }
+
+    /// Swaps at most `params.max_amount_in` for exactly `params.amount_out`
+    pub fn swap_out(&self, params: SwapOutParams) -> Instruction {
+        Instruction {
+            program_id: crate::ID,
+            accounts: self.swap_out_accounts(),
+            data: crate::instruction::SwapOut { params }.data(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calculator::swap_direction::SwapDirection;
+    use anchor_lang::Discriminator;
+
+    fn builder() -> SwapInstructionBuilder {
+        let state = LiquidityPoolState {
+            creator: Pubkey::new_unique(),
+            base_token_mint: Pubkey::new_unique(),
+            quote_token_mint: Pubkey::new_unique(),
+            base_token_vault: Pubkey::new_unique(),
+            quote_token_vault: Pubkey::new_unique(),
+            quote_token_program: anchor_spl::token_2022::ID,
+            ..Default::default()
+        };
+        SwapInstructionBuilder::new(
+            Pubkey::new_unique(),
+            &state,
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        )
+    }
+
+    #[test]
+    fn test_swap_in_instruction() {
+        let builder = builder();
+        let params = SwapInParams {
+            amount_in: 1_000_000,
+            minimum_amount_out: 990_000,
+            swap_direction: SwapDirection::Base2Quote,
+            encoded_user_defined_event_data: "order-42".to_string(),
+        };
+
+        let instruction = builder.swap_in(params);
+
+        assert_eq!(instruction.program_id, crate::ID);
+        let (discriminator, data) = instruction.data.split_at(8);
+        assert_eq!(discriminator, crate::instruction::SwapIn::DISCRIMINATOR);
+        let decoded = SwapInParams::try_from_slice(data).unwrap();
+        assert_eq!(decoded.amount_in, 1_000_000);
+        assert_eq!(decoded.minimum_amount_out, 990_000);
+        assert_eq!(decoded.swap_direction, SwapDirection::Base2Quote);
+        assert_eq!(decoded.encoded_user_defined_event_data, "order-42");
+
+        let accounts = &instruction.accounts;
+        assert_eq!(accounts.len(), 23);
+        // Unset token programs fall back to SPL Token
+        assert_eq!(accounts[1].pubkey, Token::id());
+        assert_eq!(accounts[2].pubkey, anchor_spl::token_2022::ID);
+        let user = accounts
+            .iter()
+            .find(|account| account.pubkey == builder.user)
+            .unwrap();
+        assert!(user.is_signer && user.is_writable);
+        assert_eq!(accounts[22].pubkey, {
+            let state = &builder.state;
+            derive_extras_account(
+                &state.creator,
+                &state.base_token_mint,
+                &state.quote_token_mint,
+            )
+            .0
+        });
+    }
+
+    #[test]
+    fn test_swap_out_instruction() {
+        let builder = builder();
+        let params = SwapOutParams {
+            max_amount_in: 1_010_000,
+            amount_out: 1_000_000,
+            swap_direction: SwapDirection::Quote2Base,
+            encoded_user_defined_event_data: String::new(),
+        };
+
+        let instruction = builder.swap_out(params);
+
+        let (discriminator, data) = instruction.data.split_at(8);
+        assert_eq!(discriminator, crate::instruction::SwapOut::DISCRIMINATOR);
+        assert_ne!(discriminator, crate::instruction::SwapIn::DISCRIMINATOR);
+        let decoded = SwapOutParams::try_from_slice(data).unwrap();
+        assert_eq!(decoded.max_amount_in, 1_010_000);
+        assert_eq!(decoded.amount_out, 1_000_000);
+        assert_eq!(decoded.swap_direction, SwapDirection::Quote2Base);
+        assert_eq!(instruction.accounts, builder.swap_in_accounts());
+    }
+}
@@ -0,0 +1,272 @@
+//! Single-sided deposits: part of one token is swapped through the pool and
+//! the rest is deposited, together with the swap output, for LP tokens.
+
+use anyhow::Result;
+
+use crate::{
+    calculator::{
+        error::CalculatorError,
+        liquidity::DepositResult,
+        number::U256,
+        swap_context::{SwapContext, TransferFees},
+        swap_direction::SwapDirection,
+        LiquidityCalculator,
+    },
+    instructions::LiquidityPoolState,
+    quote::{quote_exact_in, QuoteResult},
+};
+
+/// Outcome of a zap of `amount_in` source tokens
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ZapResult {
+    pub amount_in: u64,
+    /// Internal swap of part of `amount_in`
+    pub swap: QuoteResult,
+    /// Proportional deposit of the rest and of the swap output
+    pub deposit: DepositResult,
+    pub lp_amount: u64,
+    /// Tokens left over once the deposit is made, in raw units
+    pub base_dust: u64,
+    pub quote_dust: u64,
+}
+
+/// Splits `amount_in` source tokens between an internal swap in the direction
+/// of `context` and a deposit into `state` so that the LP tokens received are
+/// maximal.
+///
+/// The swap pays the LP fee, buy or sell tax, protocol fees and transfer
+/// fees of [`quote_exact_in`]; the deposit is priced against the reserves the
+/// swap leaves behind. Decaying taxes of `context` must have been resolved
+/// with [`SwapContext::at`].
+pub fn zap_in(
+    state: &LiquidityPoolState,
+    context: &SwapContext,
+    transfer_fees: &TransferFees,
+    amount_in: u64,
+    slippage_numerator: u64,
+) -> Result<ZapResult> {
+    state.checked_allow_swap()?;
+    state.checked_allow_add_liquidity()?;
+    context.validate()?;
+    context.checked_taxes_resolved()?;
+    transfer_fees.validate()?;
+    let swap_direction = context.swap_direction;
+    let (source_transfer_fee, destination_transfer_fee) =
+        transfer_fees.for_direction(&swap_direction);
+
+    // Both sides of the deposit mint LP tokens in proportion to their share
+    // of the post-swap reserves. Swapping more shrinks the source side and
+    // grows the destination side, so the best split is where they cross.
+    let destination_side_is_enough = |swap_amount_in: u64| -> Result<bool> {
+        let swap = match quote_exact_in(swap_amount_in, context, transfer_fees, 0) {
+            Ok(swap) => swap,
+            Err(error) => {
+                return match error.downcast_ref::<CalculatorError>() {
+                    // Too small a swap to pay anything out
+                    Some(CalculatorError::ZeroOutput | CalculatorError::FeeExceedsInput) => {
+                        Ok(false)
+                    }
+                    // Too large a swap for the pool
+                    Some(CalculatorError::InsufficientLiquidity) => Ok(true),
+                    _ => Err(error),
+                };
+            }
+        };
+        let source_amount = source_transfer_fee
+            .calculate_fee(amount_in - swap_amount_in)
+            .map(|fee| amount_in - swap_amount_in - fee)
+            .ok_or(CalculatorError::Overflow)?;
+        let destination_amount = destination_transfer_fee
+            .calculate_fee(swap.amount_out)
+            .map(|fee| swap.amount_out - fee)
+            .ok_or(CalculatorError::Overflow)?;
+        let (source_reserve, destination_reserve) = match swap_direction {
+            SwapDirection::Base2Quote => (swap.reserves_after.base, swap.reserves_after.quote),
+            SwapDirection::Quote2Base => (swap.reserves_after.quote, swap.reserves_after.base),
+        };
+        Ok(U256::from(destination_amount) * U256::from(source_reserve)
+            >= U256::from(source_amount) * U256::from(destination_reserve))
+    };
+
+    // Smallest swap whose output is enough for the rest of the input
+    let (mut low, mut high) = (0u64, amount_in);
+    while high - low > 1 {
+        let middle = low + (high - low) / 2;
+        if destination_side_is_enough(middle)? {
+            high = middle;
+        } else {
+            low = middle;
+        }
+    }
+
+    let mut best: Option<ZapResult> = None;
+    for swap_amount_in in [low, high] {
+        let Ok(zap) = zap_with_swap(
+            state,
+            context,
+            transfer_fees,
+            amount_in,
+            swap_amount_in,
+            slippage_numerator,
+        ) else {
+            continue;
+        };
+        best = match best {
+            Some(best) if best.lp_amount >= zap.lp_amount => Some(best),
+            _ => Some(zap),
+        };
+    }
+    best.ok_or_else(|| CalculatorError::InsufficientLiquidity.into())
+}
+
+fn zap_with_swap(
+    state: &LiquidityPoolState,
+    context: &SwapContext,
+    transfer_fees: &TransferFees,
+    amount_in: u64,
+    swap_amount_in: u64,
+    slippage_numerator: u64,
+) -> Result<ZapResult> {
+    let swap = quote_exact_in(swap_amount_in, context, transfer_fees, slippage_numerator)?;
+    let state_after = LiquidityPoolState {
+        base_token_vault_balance: swap.reserves_after.base,
+        quote_token_vault_balance: swap.reserves_after.quote,
+        ..*state
+    };
+    let source_amount = amount_in - swap_amount_in;
+    let (base_amount, quote_amount) = match context.swap_direction {
+        SwapDirection::Base2Quote => (source_amount, swap.amount_out),
+        SwapDirection::Quote2Base => (swap.amount_out, source_amount),
+    };
+
+    let lp_amount = LiquidityCalculator::lp_amount_for_deposit(
+        &state_after,
+        base_amount,
+        quote_amount,
+        transfer_fees,
+    )?;
+    if lp_amount == 0 {
+        return Err(CalculatorError::ZeroOutput.into());
+    }
+    let deposit =
+        LiquidityCalculator::deposit(&state_after, lp_amount, transfer_fees, slippage_numerator)?;
+
+    Ok(ZapResult {
+        amount_in,
+        swap,
+        deposit,
+        lp_amount,
+        base_dust: base_amount - deposit.base_amount_in,
+        quote_dust: quote_amount - deposit.quote_amount_in,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calculator::{
+        tax_decay::{DecayingTax, TaxDecay, TaxDecaySchedule},
+        taxation_mode::TaxationMode,
+    };
+    use anchor_lang::prelude::Pubkey;
+    use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::TransferFee;
+
+    fn state() -> LiquidityPoolState {
+        LiquidityPoolState {
+            base_token_mint: Pubkey::new_unique(),
+            quote_token_mint: Pubkey::new_unique(),
+            base_token_vault_balance: 1_000_000_000_000,
+            quote_token_vault_balance: 50_000_000_000,
+            lp_token_current_supply: 223_606_797_749,
+            swap_fee_numerator: 25,
+            swap_fee_denominator: 10_000,
+            protocol_swap_fee_numerator: 1,
+            protocol_swap_fee_denominator: 100,
+            taxation_mode: TaxationMode::Quote,
+            buy_tax: 300,
+            sell_tax: 500,
+            allow_swap: true,
+            allow_add_liquidity: true,
+            ..Default::default()
+        }
+    }
+
+    fn transfer_fees() -> TransferFees {
+        TransferFees {
+            base: TransferFee {
+                epoch: 0.into(),
+                maximum_fee: u64::MAX.into(),
+                transfer_fee_basis_points: 100.into(),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_zap_leaves_little_dust() {
+        for swap_direction in [SwapDirection::Quote2Base, SwapDirection::Base2Quote] {
+            let amount_in = match swap_direction {
+                SwapDirection::Quote2Base => 1_000_000_000,
+                SwapDirection::Base2Quote => 20_000_000_000,
+            };
+
+            let state = state();
+            let context = SwapContext::from_pool_state(&state, swap_direction).unwrap();
+            let zap = zap_in(&state, &context, &transfer_fees(), amount_in, 50).unwrap();
+
+            assert_eq!(zap.lp_amount, zap.deposit.lp_amount);
+            // Roughly half of the input is swapped, a bit more to pay the fees
+            assert!(zap.swap.amount_in > amount_in / 2);
+            assert!(zap.swap.amount_in < amount_in / 2 + amount_in / 20);
+            let (source_dust, destination_dust) = match swap_direction {
+                SwapDirection::Quote2Base => (zap.quote_dust, zap.base_dust),
+                SwapDirection::Base2Quote => (zap.base_dust, zap.quote_dust),
+            };
+            assert!(source_dust < amount_in / 10_000, "{:?}", zap);
+            assert!(destination_dust < zap.swap.amount_out / 10_000, "{:?}", zap);
+        }
+    }
+
+    #[test]
+    fn test_zap_requires_swap_and_add_liquidity() {
+        let state = LiquidityPoolState {
+            allow_swap: false,
+            ..state()
+        };
+        let context = SwapContext::from_pool_state(&state, SwapDirection::Quote2Base).unwrap();
+        assert!(zap_in(&state, &context, &transfer_fees(), 1_000_000, 0).is_err());
+    }
+
+    #[test]
+    fn test_zap_pays_the_tax_at_the_resolved_timestamp() {
+        let state = state();
+        let tax_decay = TaxDecaySchedule {
+            buy_tax: DecayingTax::new(5_000, 300, TaxDecay::Linear { duration: 100 }).unwrap(),
+            sell_tax: DecayingTax::flat(500),
+            open_at: 1_000,
+        };
+        let context = SwapContext::from_pool_state(&state, SwapDirection::Quote2Base)
+            .unwrap()
+            .with_tax_decay(tax_decay)
+            .unwrap();
+
+        assert!(zap_in(&state, &context, &transfer_fees(), 1_000_000_000, 50).is_err());
+        let launch = zap_in(
+            &state,
+            &context.at(1_000).unwrap(),
+            &transfer_fees(),
+            1_000_000_000,
+            50,
+        )
+        .unwrap();
+        let later = zap_in(
+            &state,
+            &context.at(2_000).unwrap(),
+            &transfer_fees(),
+            1_000_000_000,
+            50,
+        )
+        .unwrap();
+        assert!(launch.lp_amount < later.lp_amount);
+    }
+}
@@ -1,72 +1,442 @@
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
 };
 
-use anchor_lang::{prelude::Pubkey, pubkey, system_program::System, AccountDeserialize, Id};
-use anchor_lang::{solana_program::instruction::AccountMeta, ToAccountMetas};
-use anchor_spl::{
-    associated_token::AssociatedToken, token::Token,
-    token_2022::spl_token_2022::extension::transfer_fee::TransferFee,
-};
+use anchor_lang::{prelude::Pubkey, pubkey};
 use anyhow::{Ok, Result};
+pub use heaven_exchange::swap_instruction::{
+    derive_authority, derive_extras_account, derive_user_amm_stats, derive_user_global_stats,
+};
 use heaven_exchange::{
     calculator::{
-        swap_direction::SwapDirection, taxation_mode::TaxationMode, ProtocolSwapFeeDirection,
+        error::CalculatorError,
+        fee_mode::{AdaptiveFee, FeeMode, FeeTier, FeeTiers, VolatilityTracker},
+        swap_context::{EpochTransferFee, EpochTransferFees, SwapContext},
+        swap_curve::SwapCurve,
+        swap_direction::SwapDirection,
+        tax_decay::{DecayingTax, TaxDecay, TaxDecaySchedule},
+        ConstantProduct, SharedConstantProduct,
     },
-    get_transfer_fee_config,
-    instructions::{chainlink_feed_account, chainlink_program, LiquidityPoolState},
-    quote::{quote_exact_in, quote_exact_out},
+    get_epoch_transfer_fee,
+    instructions::LiquidityPoolState,
+    layout::decode_liquidity_pool_state,
+    quote::{quote_exact_in_with_curve, quote_exact_out_with_curve, QuoteResult},
+    swap_instruction::SwapInstructionBuilder,
 };
 use jupiter_amm_interface::{
     Amm, KeyedAccount, Quote, QuoteParams, Swap, SwapAndAccountMetas, SwapMode, SwapParams,
 };
+use rust_decimal::Decimal;
 
 pub struct HeavenAmm {
     pub key: Pubkey,
     pub authority: Pubkey,
     pub state: LiquidityPoolState,
-    pub base_transfer_fee: TransferFee,
-    pub quote_transfer_fee: TransferFee,
+    /// Older and newer transfer fees of the mints, resolved at `epoch` for
+    /// each quote
+    pub base_transfer_fee: EpochTransferFee,
+    pub quote_transfer_fee: EpochTransferFee,
     pub epoch: Arc<AtomicU64>,
+    /// Cluster unix time, for the pool's `open_at`
+    pub unix_timestamp: Arc<AtomicI64>,
+    /// Other pools on the same pair that share their reserves with this one
+    pub shared_pools: Vec<Pubkey>,
+    /// Set once the shared pools have been loaded by `update`
+    pub shared_constant_product: Option<SharedConstantProduct>,
+    pub fee_mode: FeeMode,
+    /// Prices seen by `update`, for the volatility of an adaptive fee
+    pub volatility: VolatilityTracker,
+    /// Launch taxes replacing the flat ones of `state`, resolved at
+    /// `unix_timestamp` for each quote
+    pub tax_decay: Option<TaxDecaySchedule>,
 }
 
 pub const PROGRAM_ID: Pubkey = pubkey!("HEAVEnMX7RoaYCucpyFterLWzFJR8Ah26oNSnqBs5Jtn");
 pub const AUTHORITY: Pubkey = pubkey!("GBrN2zZCrhzn1ouVxT3RNkBmwFAjLNt2p2MSXwMLMX7");
+/// Accounts of a swap instruction: the `SwapInAccounts`, then the chainlink
+/// feed and program and the extras account
+pub const SWAP_ACCOUNTS_LEN: usize = 23;
+/// Swaps an adaptive fee measures volatility over, unless configured
+pub const DEFAULT_VOLATILITY_WINDOW: usize = 32;
+
+/// Decodes a pool state account of any known layout, see
+/// [`decode_liquidity_pool_state`]
+pub fn deserialize_liquidity_pool_state(data: &[u8]) -> Result<LiquidityPoolState> {
+    decode_liquidity_pool_state(data)
+}
+
+/// Whether a quote failed only because the pool cannot fill the swap, e.g.
+/// the requested amount out exceeds the reserves, rather than because of a
+/// broken pool or calculation error.
+pub fn is_insufficient_liquidity(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<CalculatorError>()
+        .is_some_and(CalculatorError::is_liquidity_error)
+}
+
+/// Reads the `shared_pools` list from the keyed account params, e.g.
+/// `{ "shared_pools": ["<pool id>", ...] }`.
+pub fn shared_pools_from_params(keyed_account: &KeyedAccount) -> Result<Vec<Pubkey>> {
+    let Some(shared_pools) = keyed_account
+        .params
+        .as_ref()
+        .and_then(|params| params.get("shared_pools"))
+    else {
+        return Ok(vec![]);
+    };
+
+    shared_pools
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("`shared_pools` must be an array of pool ids"))?
+        .iter()
+        .map(|pool| {
+            let pool = pool
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("`shared_pools` must be an array of pool ids"))?;
+            Pubkey::from_str(pool).map_err(|_| anyhow::anyhow!("Invalid shared pool id: {}", pool))
+        })
+        .collect()
+}
+
+/// Reads the `fee_mode` from the keyed account params, along with the window
+/// of its volatility tracker, e.g.
+/// `{ "fee_mode": { "tiered": [[0, 30], [1000000, 20]] } }` for fee tiers of
+/// `[minimum amount in, swap fee numerator]`, or
+/// `{ "fee_mode": { "adaptive": { "sensitivity": [1, 10], "max_swap_fee_numerator": 100, "window": 32 } } }`.
+/// Pools without one have a static fee.
+pub fn fee_mode_from_params(keyed_account: &KeyedAccount) -> Result<(FeeMode, usize)> {
+    let Some(fee_mode) = keyed_account
+        .params
+        .as_ref()
+        .and_then(|params| params.get("fee_mode"))
+    else {
+        return Ok((FeeMode::Static, DEFAULT_VOLATILITY_WINDOW));
+    };
+    let invalid = || anyhow::anyhow!("Invalid fee mode: {}", fee_mode);
+    let number = |value: &serde_json::Value| value.as_u64().ok_or_else(invalid);
+    let pair = |value: Option<&serde_json::Value>| match value
+        .and_then(|value| value.as_array())
+        .map(Vec::as_slice)
+    {
+        Some([first, second]) => Ok((number(first)?, number(second)?)),
+        _ => Err(invalid()),
+    };
+
+    if let Some(tiers) = fee_mode.get("tiered") {
+        let tiers = tiers
+            .as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|tier| {
+                let (minimum_amount_in, swap_fee_numerator) = pair(Some(tier))?;
+                Ok(FeeTier {
+                    minimum_amount_in,
+                    swap_fee_numerator,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        return Ok((
+            FeeMode::Tiered(FeeTiers::new(&tiers)?),
+            DEFAULT_VOLATILITY_WINDOW,
+        ));
+    }
+
+    if let Some(adaptive) = fee_mode.get("adaptive") {
+        let (sensitivity_numerator, sensitivity_denominator) = pair(adaptive.get("sensitivity"))?;
+        let fee_mode = FeeMode::Adaptive(AdaptiveFee {
+            sensitivity_numerator,
+            sensitivity_denominator,
+            max_swap_fee_numerator: number(
+                adaptive.get("max_swap_fee_numerator").ok_or_else(invalid)?,
+            )?,
+            volatility_bps: 0,
+        });
+        fee_mode.validate()?;
+        let window = match adaptive.get("window") {
+            Some(window) => number(window)? as usize,
+            None => DEFAULT_VOLATILITY_WINDOW,
+        };
+        return Ok((fee_mode, window));
+    }
+
+    match fee_mode.as_str() {
+        Some("static") => Ok((FeeMode::Static, DEFAULT_VOLATILITY_WINDOW)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Reads the `tax_decay` of launch taxes from the keyed account params, e.g.
+/// `{ "tax_decay": { "buy": { "start": 5000, "floor": 300, "linear": 600 } } }`.
+/// A side decays `"linear"` over a duration, `"stepwise"` over
+/// `[step duration, steps]` or `"exponential"` with a half-life, all in
+/// seconds from the pool's `open_at`. A missing side keeps the flat tax of
+/// `state`, and pools without one have flat taxes.
+pub fn tax_decay_from_params(
+    keyed_account: &KeyedAccount,
+    state: &LiquidityPoolState,
+) -> Result<Option<TaxDecaySchedule>> {
+    let Some(tax_decay) = keyed_account
+        .params
+        .as_ref()
+        .and_then(|params| params.get("tax_decay"))
+    else {
+        return Ok(None);
+    };
+    let invalid = || anyhow::anyhow!("Invalid tax decay: {}", tax_decay);
+    let number = |value: Option<&serde_json::Value>| {
+        value.and_then(|value| value.as_u64()).ok_or_else(invalid)
+    };
+    let decaying_tax = |side: &str, flat_tax: u64| {
+        let Some(tax) = tax_decay.get(side) else {
+            return Ok(DecayingTax::flat(flat_tax));
+        };
+        let decay = if let Some(duration) = tax.get("linear") {
+            TaxDecay::Linear {
+                duration: number(Some(duration))?,
+            }
+        } else if let Some(stepwise) = tax.get("stepwise") {
+            match stepwise.as_array().map(Vec::as_slice) {
+                Some([step_duration, steps]) => TaxDecay::Stepwise {
+                    step_duration: number(Some(step_duration))?,
+                    steps: number(Some(steps))?,
+                },
+                _ => return Err(invalid()),
+            }
+        } else if let Some(half_life) = tax.get("exponential") {
+            TaxDecay::Exponential {
+                half_life: number(Some(half_life))?,
+            }
+        } else {
+            TaxDecay::None
+        };
+        DecayingTax::new(number(tax.get("start"))?, number(tax.get("floor"))?, decay)
+    };
+
+    Ok(Some(TaxDecaySchedule::new(
+        state.open_at,
+        decaying_tax("buy", state.buy_tax)?,
+        decaying_tax("sell", state.sell_tax)?,
+    )?))
+}
 
-pub fn derive_user_global_stats(user: &Pubkey) -> (Pubkey, u8) {
+pub fn derive_liquidity_pool_state(
+    creator: &Pubkey,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> (Pubkey, u8) {
     Pubkey::find_program_address(
         &[
-            heaven_exchange::instructions::seeds::USER_GLOBAL_STATS.as_bytes(),
-            user.as_ref(),
+            heaven_exchange::instructions::seeds::LIQUIDITY_POOL_STATE.as_bytes(),
+            creator.as_ref(),
+            base_mint.as_ref(),
+            quote_mint.as_ref(),
         ],
         &PROGRAM_ID,
     )
 }
 
-pub fn derive_user_amm_stats(user: &Pubkey, pool_id: &Pubkey) -> (Pubkey, u8) {
+pub fn derive_lp_token_mint(pool_id: &Pubkey) -> (Pubkey, u8) {
     Pubkey::find_program_address(
         &[
-            heaven_exchange::instructions::seeds::USER_AMM_STATS.as_bytes(),
-            user.as_ref(),
+            heaven_exchange::instructions::seeds::LIQUIDITY_PROVIDER_TOKEN_MINT.as_bytes(),
             pool_id.as_ref(),
         ],
         &PROGRAM_ID,
     )
 }
 
-pub fn derive_extras_account(creator: &Pubkey, base_mint: &Pubkey, quote_mint: &Pubkey) -> (Pubkey, u8) {
+pub fn derive_token_vault(pool_id: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
     Pubkey::find_program_address(
         &[
-            heaven_exchange::instructions::seeds::EXTRAS_ACCOUNT.as_bytes(),
-            creator.as_ref(),
-            base_mint.as_ref(),
-            quote_mint.as_ref(),
+            heaven_exchange::instructions::seeds::LIQUIDITY_POOL_TOKEN_VAULT.as_bytes(),
+            pool_id.as_ref(),
+            mint.as_ref(),
+        ],
+        &PROGRAM_ID,
+    )
+}
+
+pub fn derive_swap_tax_vault(pool_id: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            heaven_exchange::instructions::seeds::LIQUIDITY_POOL_SWAP_TAX_TOKEN_VAULT.as_bytes(),
+            pool_id.as_ref(),
+            mint.as_ref(),
         ],
         &PROGRAM_ID,
     )
 }
 
+/// Why a pool account is not a genuine pool of the program
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PoolVerificationError {
+    /// The account is owned by another program
+    WrongOwner { owner: Pubkey },
+    /// An address in or of the pool is not the one its seeds derive
+    AddressMismatch {
+        account: &'static str,
+        expected: Pubkey,
+        found: Pubkey,
+    },
+    /// The pool's authority bump is not the bump of the program authority
+    AuthorityBumpMismatch { expected: u8, found: u8 },
+    /// An update changed an address the pool was verified with
+    PoolChanged { account: &'static str },
+}
+
+impl std::fmt::Display for PoolVerificationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PoolVerificationError::WrongOwner { owner } => {
+                write!(
+                    f,
+                    "Pool account is owned by {} instead of {}",
+                    owner, PROGRAM_ID
+                )
+            }
+            PoolVerificationError::AddressMismatch {
+                account,
+                expected,
+                found,
+            } => write!(f, "Pool {} is {} instead of {}", account, found, expected),
+            PoolVerificationError::AuthorityBumpMismatch { expected, found } => write!(
+                f,
+                "Pool authority bump is {} instead of {}",
+                found, expected
+            ),
+            PoolVerificationError::PoolChanged { account } => {
+                write!(f, "Pool {} changed since it was verified", account)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PoolVerificationError {}
+
+/// Addresses of a pool, in the order they are verified, named for errors
+fn pool_addresses(key: &Pubkey, state: &LiquidityPoolState) -> [(&'static str, Pubkey); 6] {
+    [
+        ("state", *key),
+        ("base token vault", state.base_token_vault),
+        ("quote token vault", state.quote_token_vault),
+        ("base token swap tax vault", state.base_token_swap_tax_vault),
+        (
+            "quote token swap tax vault",
+            state.quote_token_swap_tax_vault,
+        ),
+        ("LP token mint", state.lp_token_mint),
+    ]
+}
+
+/// Checks that the pool account `key` owned by `owner` is a genuine pool of
+/// the program: its address, vaults, swap tax vaults and LP mint are the PDAs
+/// derived from the seeds in `instructions::seeds`, and its authority bump is
+/// the program authority's. Guards against spoofed pools in account snapshots.
+pub fn verify_liquidity_pool(
+    key: &Pubkey,
+    owner: &Pubkey,
+    state: &LiquidityPoolState,
+) -> Result<()> {
+    if *owner != PROGRAM_ID {
+        return Err(PoolVerificationError::WrongOwner { owner: *owner }.into());
+    }
+
+    let expected = [
+        derive_liquidity_pool_state(
+            &state.creator,
+            &state.base_token_mint,
+            &state.quote_token_mint,
+        )
+        .0,
+        derive_token_vault(key, &state.base_token_mint).0,
+        derive_token_vault(key, &state.quote_token_mint).0,
+        derive_swap_tax_vault(key, &state.base_token_mint).0,
+        derive_swap_tax_vault(key, &state.quote_token_mint).0,
+        derive_lp_token_mint(key).0,
+    ];
+    for ((account, found), expected) in pool_addresses(key, state).into_iter().zip(expected) {
+        if found != expected {
+            return Err(PoolVerificationError::AddressMismatch {
+                account,
+                expected,
+                found,
+            }
+            .into());
+        }
+    }
+
+    let (_, authority_bump) = derive_authority();
+    if state.authority_bump != authority_bump {
+        return Err(PoolVerificationError::AuthorityBumpMismatch {
+            expected: authority_bump,
+            found: state.authority_bump,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Checks that an update of the verified pool `key` kept every address it
+/// was verified with, without deriving them again
+fn verify_pool_unchanged(
+    key: &Pubkey,
+    owner: &Pubkey,
+    verified: &LiquidityPoolState,
+    updated: &LiquidityPoolState,
+) -> Result<()> {
+    if *owner != PROGRAM_ID {
+        return Err(PoolVerificationError::WrongOwner { owner: *owner }.into());
+    }
+    let identity = |state: &LiquidityPoolState| {
+        [
+            ("creator", state.creator),
+            ("base token mint", state.base_token_mint),
+            ("quote token mint", state.quote_token_mint),
+        ]
+        .into_iter()
+        .chain(pool_addresses(key, state))
+    };
+    for ((account, verified), (_, updated)) in identity(verified).zip(identity(updated)) {
+        if verified != updated {
+            return Err(PoolVerificationError::PoolChanged { account }.into());
+        }
+    }
+    if verified.authority_bump != updated.authority_bump {
+        return Err(PoolVerificationError::PoolChanged {
+            account: "authority bump",
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Why a pool cannot be swapped against right now
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolInactive {
+    /// The pool has swaps turned off
+    SwapDisabled,
+    /// The pool opens for swaps at unix time `open_at`
+    NotOpenYet { open_at: u64 },
+    /// A vault of the pool is empty
+    EmptyVault,
+}
+
+impl std::fmt::Display for PoolInactive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PoolInactive::SwapDisabled => write!(f, "Pool has swaps disabled"),
+            PoolInactive::NotOpenYet { open_at } => write!(f, "Pool opens at {}", open_at),
+            PoolInactive::EmptyVault => write!(f, "Pool has an empty vault"),
+        }
+    }
+}
+
+impl std::error::Error for PoolInactive {}
+
 impl Amm for HeavenAmm {
     fn label(&self) -> String {
         return String::from("Heaven");
@@ -83,21 +453,27 @@ impl Amm for HeavenAmm {
     where
         Self: Sized,
     {
-        let mut info = keyed_account.account.data.clone();
-        let size = std::mem::size_of::<LiquidityPoolState>() + 8;
-        let extra = size - info.len();
-        if extra > 0 {
-            info.extend(vec![0u8; extra]);
-        }
-        let state = LiquidityPoolState::try_deserialize(&mut info.as_ref())?;
+        let state = deserialize_liquidity_pool_state(&keyed_account.account.data)?;
+        verify_liquidity_pool(&keyed_account.key, &keyed_account.account.owner, &state)?;
+        let shared_pools = shared_pools_from_params(keyed_account)?;
+        let (fee_mode, volatility_window) = fee_mode_from_params(keyed_account)?;
+        let tax_decay = tax_decay_from_params(keyed_account, &state)?;
+        let mut volatility = VolatilityTracker::new(volatility_window);
+        volatility.record(&state);
 
         Ok(HeavenAmm {
             key: keyed_account.key,
             state,
             authority: AUTHORITY,
-            base_transfer_fee: TransferFee::default(),
-            quote_transfer_fee: TransferFee::default(),
+            base_transfer_fee: EpochTransferFee::default(),
+            quote_transfer_fee: EpochTransferFee::default(),
             epoch: amm_context.clock_ref.epoch.clone(),
+            unix_timestamp: amm_context.clock_ref.unix_timestamp.clone(),
+            shared_pools,
+            shared_constant_product: None,
+            fee_mode,
+            volatility,
+            tax_decay,
         })
     }
 
@@ -110,24 +486,21 @@ impl Amm for HeavenAmm {
     }
 
     fn get_accounts_to_update(&self) -> Vec<Pubkey> {
-        vec![
+        let mut accounts = vec![
             self.key,
             self.state.base_token_mint,
             self.state.quote_token_mint,
-        ]
+        ];
+        accounts.extend(self.shared_pools.iter());
+        accounts
     }
 
     fn update(&mut self, account_map: &jupiter_amm_interface::AccountMap) -> Result<()> {
-        let mut info = account_map
+        let info = account_map
             .get(&self.key)
-            .ok_or_else(|| anyhow::anyhow!("Could not find liquidity pool state account"))?
-            .data.clone();
-        let size = std::mem::size_of::<LiquidityPoolState>() + 8;
-        let extra = size - info.len();
-        if extra > 0 {
-            info.extend(vec![0u8; extra]);
-        }
-        let new_state = LiquidityPoolState::try_deserialize(&mut info.as_ref())?;
+            .ok_or_else(|| anyhow::anyhow!("Could not find liquidity pool state account"))?;
+        let new_state = deserialize_liquidity_pool_state(&info.data)?;
+        verify_pool_unchanged(&self.key, &info.owner, &self.state, &new_state)?;
         let base_mint = account_map
             .get(&self.state.base_token_mint)
             .ok_or_else(|| anyhow::anyhow!("Could not find base token mint account"))?;
@@ -136,92 +509,70 @@ impl Amm for HeavenAmm {
             .get(&self.state.quote_token_mint)
             .ok_or_else(|| anyhow::anyhow!("Could not find quote token mint account"))?;
 
-        let epoch = self.epoch.load(Ordering::Relaxed);
         let base_transfer_fee =
-            get_transfer_fee_config(&(base_mint.data[0..].to_vec(), base_mint.owner), epoch)?;
+            get_epoch_transfer_fee(&(base_mint.data[0..].to_vec(), base_mint.owner))?;
         let quote_transfer_fee =
-            get_transfer_fee_config(&(quote_mint.data[0..].to_vec(), quote_mint.owner), epoch)?;
+            get_epoch_transfer_fee(&(quote_mint.data[0..].to_vec(), quote_mint.owner))?;
 
+        let shared_constant_product = if self.shared_pools.is_empty() {
+            None
+        } else {
+            let mut other_pools_base_reserve = 0u64;
+            let mut other_pools_quote_reserve = 0u64;
+            for shared_pool in self.shared_pools.iter() {
+                let info = account_map
+                    .get(shared_pool)
+                    .ok_or_else(|| anyhow::anyhow!("Could not find shared pool {}", shared_pool))?;
+                let shared_state = deserialize_liquidity_pool_state(&info.data)?;
+                verify_liquidity_pool(shared_pool, &info.owner, &shared_state)?;
+                if shared_state.base_token_mint != new_state.base_token_mint
+                    || shared_state.quote_token_mint != new_state.quote_token_mint
+                {
+                    return Err(anyhow::anyhow!(
+                        "Shared pool {} is not on the same pair",
+                        shared_pool
+                    ));
+                }
+                other_pools_base_reserve = other_pools_base_reserve
+                    .checked_add(shared_state.base_token_vault_balance)
+                    .ok_or_else(|| anyhow::anyhow!("Shared base reserve overflow"))?;
+                other_pools_quote_reserve = other_pools_quote_reserve
+                    .checked_add(shared_state.quote_token_vault_balance)
+                    .ok_or_else(|| anyhow::anyhow!("Shared quote reserve overflow"))?;
+            }
+            Some(SharedConstantProduct::new(
+                other_pools_base_reserve,
+                other_pools_quote_reserve,
+            ))
+        };
+
+        self.volatility.record(&new_state);
         self.state = new_state;
         self.base_transfer_fee = base_transfer_fee;
         self.quote_transfer_fee = quote_transfer_fee;
+        self.shared_constant_product = shared_constant_product;
         Ok(())
     }
 
     fn quote(&self, quote_params: &QuoteParams) -> Result<Quote> {
-        let protocol_swap_fee_direction = match self.state.taxation_mode {
-            TaxationMode::Base => ProtocolSwapFeeDirection::Base,
-            TaxationMode::Quote => ProtocolSwapFeeDirection::Quote,
-            TaxationMode::None => ProtocolSwapFeeDirection::None,
-        };
-
-        let swap_direction = if quote_params.input_mint.eq(&self.state.base_token_mint) {
-            SwapDirection::Base2Quote
+        let result = self.quote_result(quote_params)?;
+        // Fees are charged in both mints, e.g. the swap fee on the input and
+        // a sell tax on the output. Jupiter takes a single fee mint, so they
+        // are all reported in the input mint.
+        let fee_amount = result.fees_in_input_mint()?;
+        let fee_pct = if result.amount_in == 0 {
+            Decimal::ZERO
         } else {
-            SwapDirection::Quote2Base
+            Decimal::from(fee_amount) / Decimal::from(result.amount_in)
         };
-        match quote_params.swap_mode {
-            SwapMode::ExactIn => {
-                let (_minimum_out, amount_out, total_fee) = quote_exact_in(
-                    quote_params.amount,
-                    swap_direction,
-                    protocol_swap_fee_direction,
-                    self.state.taxation_mode,
-                    self.state.base_token_vault_balance,
-                    self.state.quote_token_vault_balance,
-                    self.state.swap_fee_numerator,
-                    self.state.swap_fee_denominator,
-                    self.state.protocol_swap_fee_numerator,
-                    self.state.protocol_swap_fee_denominator,
-                    self.state.buy_tax,
-                    self.state.sell_tax,
-                    self.base_transfer_fee,
-                    self.quote_transfer_fee,
-                    0,
-                )?;
-                Ok(Quote {
-                    fee_amount: total_fee,
-                    fee_mint: match self.state.taxation_mode {
-                        TaxationMode::Base => self.state.base_token_mint,
-                        TaxationMode::Quote => self.state.quote_token_mint,
-                        TaxationMode::None => quote_params.input_mint,
-                    },
-                    in_amount: quote_params.amount,
-                    out_amount: amount_out,
-                    ..Default::default()
-                })
-            }
-            SwapMode::ExactOut => {
-                let (_maximum_amount_in, amount_in, total_fee) = quote_exact_out(
-                    quote_params.amount,
-                    swap_direction,
-                    protocol_swap_fee_direction,
-                    self.state.taxation_mode,
-                    self.state.base_token_vault_balance,
-                    self.state.quote_token_vault_balance,
-                    self.state.swap_fee_numerator,
-                    self.state.swap_fee_denominator,
-                    self.state.protocol_swap_fee_numerator,
-                    self.state.protocol_swap_fee_denominator,
-                    self.state.buy_tax,
-                    self.state.sell_tax,
-                    self.base_transfer_fee,
-                    self.quote_transfer_fee,
-                    0,
-                )?;
-                Ok(Quote {
-                    fee_amount: total_fee,
-                    fee_mint: match self.state.taxation_mode {
-                        TaxationMode::Base => self.state.base_token_mint,
-                        TaxationMode::Quote => self.state.quote_token_mint,
-                        TaxationMode::None => quote_params.input_mint,
-                    },
-                    in_amount: amount_in,
-                    out_amount: quote_params.amount,
-                    ..Default::default()
-                })
-            }
-        }
+        Ok(Quote {
+            fee_amount,
+            fee_mint: result.input_mint,
+            fee_pct,
+            in_amount: result.amount_in,
+            out_amount: result.amount_out,
+            ..Default::default()
+        })
     }
 
     fn get_swap_and_account_metas(&self, swap_params: &SwapParams) -> Result<SwapAndAccountMetas> {
@@ -238,75 +589,779 @@ impl Amm for HeavenAmm {
                 )
             };
 
-        // SwapIn and SwapOut instructions have the same account input
-        let mut accounts = heaven_exchange::accounts::SwapInAccounts {
-            associated_token_program: AssociatedToken::id(),
-            authority: self.authority,
-            base_token_program: if self.state.base_token_program.eq(&Pubkey::default()) {
-                Token::id()
-            } else {
-                self.state.base_token_program
-            },
-            base_token_mint: self.state.base_token_mint,
-            base_token_swap_tax_vault: self.state.base_token_swap_tax_vault,
-            base_token_vault: self.state.base_token_vault,
-            liquidity_pool_state: self.key,
-            protocol_base_token_swap_fee_vault: self.state.protocol_base_token_swap_fee_vault,
-            protocol_quote_token_swap_fee_vault: self.state.protocol_quote_token_swap_fee_vault,
-            quote_token_mint: self.state.quote_token_mint,
-            quote_token_program: if self.state.quote_token_program.eq(&Pubkey::default()) {
-                Token::id()
-            } else {
-                self.state.quote_token_program
-            },
-            quote_token_swap_tax_vault: self.state.quote_token_swap_tax_vault,
-            quote_token_vault: self.state.quote_token_vault,
-            system_program: System::id(),
-            token_program: Token::id(),
-            user: swap_params.token_transfer_authority,
-            user_base_token_vault: user_base_token_account,
-            user_quote_token_vault: user_quote_token_account,
-            user_amm_stats: derive_user_amm_stats(&swap_params.token_transfer_authority, &self.key)
-                .0,
-            user_global_stats: derive_user_global_stats(&swap_params.token_transfer_authority).0,
-        }
-        .to_account_metas(None);
-
-        let remaining_accounts = vec![
-            AccountMeta {
-                pubkey: chainlink_feed_account::ID,
-                is_signer: false,
-                is_writable: false,
-            },
-            AccountMeta {
-                pubkey: chainlink_program::ID,
-                is_signer: false,
-                is_writable: false,
-            },
-            AccountMeta {
-                pubkey: derive_extras_account(&self.state.creator, &self.state.base_token_mint, &self.state.quote_token_mint).0,
-                is_signer: false,
-                is_writable: false,
-            },
-        ];
-
-        accounts.extend(remaining_accounts);
+        // `swap_in` and `swap_out` take the same accounts, Jupiter only needs
+        // them: it encodes the swap itself
+        let account_metas = SwapInstructionBuilder::new(
+            self.key,
+            &self.state,
+            swap_params.token_transfer_authority,
+            user_base_token_account,
+            user_quote_token_account,
+        )
+        .swap_in_accounts();
 
         Ok(SwapAndAccountMetas {
-            // Do we have to add `Heaven` to the Swap enum on the jupiter_amm_interface?
             swap: Swap::TokenSwap,
-            account_metas: accounts,
+            account_metas,
         })
     }
 
+    fn supports_exact_out(&self) -> bool {
+        true
+    }
+
+    fn get_accounts_len(&self) -> usize {
+        SWAP_ACCOUNTS_LEN
+    }
+
+    fn is_active(&self) -> bool {
+        self.inactive_reason().is_none()
+    }
+
     fn clone_amm(&self) -> Box<dyn Amm + Send + Sync> {
         Box::new(HeavenAmm {
             key: self.key,
             state: self.state.clone(),
             authority: self.authority,
-            base_transfer_fee: self.base_transfer_fee.clone(),
-            quote_transfer_fee: self.quote_transfer_fee.clone(),
+            base_transfer_fee: self.base_transfer_fee,
+            quote_transfer_fee: self.quote_transfer_fee,
             epoch: self.epoch.clone(),
+            unix_timestamp: self.unix_timestamp.clone(),
+            shared_pools: self.shared_pools.clone(),
+            shared_constant_product: self.shared_constant_product,
+            fee_mode: self.fee_mode,
+            volatility: self.volatility.clone(),
+            tax_decay: self.tax_decay,
         })
     }
 }
+
+impl HeavenAmm {
+    /// Why the pool cannot be swapped against at the current cluster time,
+    /// `None` when it is active
+    pub fn inactive_reason(&self) -> Option<PoolInactive> {
+        let open_at = self.state.open_at;
+        let now = self.unix_timestamp.load(Ordering::Relaxed);
+        if !self.state.allow_swap {
+            Some(PoolInactive::SwapDisabled)
+        } else if now < 0 || (now as u64) < open_at {
+            Some(PoolInactive::NotOpenYet { open_at })
+        } else if self.state.base_token_vault_balance == 0
+            || self.state.quote_token_vault_balance == 0
+        {
+            Some(PoolInactive::EmptyVault)
+        } else {
+            None
+        }
+    }
+
+    /// Full quote behind [`Amm::quote`], with the per-mint fee breakdown and
+    /// the spot, execution and post-trade prices that Jupiter's `Quote` has
+    /// no room for.
+    pub fn quote_result(&self, quote_params: &QuoteParams) -> Result<QuoteResult> {
+        self.quote_result_with_slippage(quote_params, 0)
+    }
+
+    /// [`Self::quote_result`] with `minimum_amount_out` and
+    /// `maximum_amount_in` bounded by a slippage of `slippage_numerator`
+    /// basis points
+    pub fn quote_result_with_slippage(
+        &self,
+        quote_params: &QuoteParams,
+        slippage_numerator: u64,
+    ) -> Result<QuoteResult> {
+        if let Some(inactive) = self.inactive_reason() {
+            return Err(inactive.into());
+        }
+        match (&self.shared_constant_product, self.shared_pools.is_empty()) {
+            (Some(shared_constant_product), _) => {
+                self.quote_with_curve(shared_constant_product, quote_params, slippage_numerator)
+            }
+            (None, true) => {
+                self.quote_with_curve(&ConstantProduct, quote_params, slippage_numerator)
+            }
+            (None, false) => Err(anyhow::anyhow!("Shared pools have not been loaded yet")),
+        }
+    }
+
+    fn quote_with_curve<Curve: SwapCurve>(
+        &self,
+        curve: &Curve,
+        quote_params: &QuoteParams,
+        slippage_numerator: u64,
+    ) -> Result<QuoteResult> {
+        let swap_direction = if quote_params.input_mint.eq(&self.state.base_token_mint) {
+            SwapDirection::Base2Quote
+        } else {
+            SwapDirection::Quote2Base
+        };
+        let mut context = SwapContext::from_pool_state(&self.state, swap_direction)?
+            .with_fee_mode(
+                self.fee_mode
+                    .with_volatility(self.volatility.volatility_bps()),
+            )?;
+        if let Some(tax_decay) = self.tax_decay {
+            context = context.with_tax_decay(tax_decay)?;
+        }
+        // Launch taxes at the current cluster time
+        let now = self.unix_timestamp.load(Ordering::Relaxed).max(0) as u64;
+        let context = context.at(now)?;
+        // The epoch may have moved on since the mints were last updated
+        let transfer_fees = EpochTransferFees::new(self.base_transfer_fee, self.quote_transfer_fee)
+            .at(self.epoch.load(Ordering::Relaxed))?;
+        match quote_params.swap_mode {
+            SwapMode::ExactIn => quote_exact_in_with_curve(
+                curve,
+                quote_params.amount,
+                &context,
+                &transfer_fees,
+                slippage_numerator,
+            ),
+            SwapMode::ExactOut => quote_exact_out_with_curve(
+                curve,
+                quote_params.amount,
+                &context,
+                &transfer_fees,
+                slippage_numerator,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::TransferFee;
+    use heaven_exchange::{
+        calculator::taxation_mode::TaxationMode,
+        layout::{encode_liquidity_pool_state, PoolPriceStats, PoolStateLayout},
+    };
+    use jupiter_amm_interface::{AccountMap, AmmContext, ClockRef};
+
+    fn amm(state: LiquidityPoolState) -> HeavenAmm {
+        HeavenAmm {
+            key: Pubkey::new_unique(),
+            authority: AUTHORITY,
+            state,
+            base_transfer_fee: EpochTransferFee::default(),
+            quote_transfer_fee: EpochTransferFee::default(),
+            epoch: Arc::new(AtomicU64::new(0)),
+            unix_timestamp: Arc::new(AtomicI64::new(0)),
+            shared_pools: vec![],
+            shared_constant_product: None,
+            fee_mode: FeeMode::Static,
+            volatility: VolatilityTracker::new(DEFAULT_VOLATILITY_WINDOW),
+            tax_decay: None,
+        }
+    }
+
+    #[test]
+    fn test_quote_reports_insufficient_liquidity() {
+        let amm = amm(LiquidityPoolState {
+            base_token_mint: Pubkey::new_unique(),
+            quote_token_mint: Pubkey::new_unique(),
+            base_token_vault_balance: 1_000_000,
+            quote_token_vault_balance: 1_000_000,
+            swap_fee_numerator: 25,
+            swap_fee_denominator: 10_000,
+            protocol_swap_fee_numerator: 0,
+            protocol_swap_fee_denominator: 1,
+            allow_swap: true,
+            ..Default::default()
+        });
+        let quote_params = QuoteParams {
+            amount: 1_000_000,
+            input_mint: amm.state.quote_token_mint,
+            output_mint: amm.state.base_token_mint,
+            swap_mode: SwapMode::ExactOut,
+        };
+
+        let error = amm.quote(&quote_params).unwrap_err();
+        assert!(is_insufficient_liquidity(&error));
+
+        let error = amm
+            .quote(&QuoteParams {
+                amount: 1,
+                swap_mode: SwapMode::ExactIn,
+                ..quote_params
+            })
+            .unwrap_err();
+        assert!(is_insufficient_liquidity(&error));
+    }
+
+    #[test]
+    fn test_quote_reports_fees_in_input_mint() {
+        for taxation_mode in [TaxationMode::None, TaxationMode::Base, TaxationMode::Quote] {
+            let amm = amm(LiquidityPoolState {
+                base_token_mint: Pubkey::new_unique(),
+                quote_token_mint: Pubkey::new_unique(),
+                base_token_vault_balance: 1_000_000_000_000,
+                quote_token_vault_balance: 1_000_000_000_000,
+                swap_fee_numerator: 25,
+                swap_fee_denominator: 10_000,
+                protocol_swap_fee_numerator: 0,
+                protocol_swap_fee_denominator: 1,
+                allow_swap: true,
+                taxation_mode,
+                buy_tax: 300,
+                sell_tax: 500,
+                protocol_tax_numerator: 1,
+                protocol_tax_denominator: 10,
+                ..Default::default()
+            });
+            for swap_direction in [SwapDirection::Base2Quote, SwapDirection::Quote2Base] {
+                let (input_mint, output_mint) = match swap_direction {
+                    SwapDirection::Base2Quote => {
+                        (amm.state.base_token_mint, amm.state.quote_token_mint)
+                    }
+                    SwapDirection::Quote2Base => {
+                        (amm.state.quote_token_mint, amm.state.base_token_mint)
+                    }
+                };
+                // Swap fee, plus the swap tax with its 10% protocol cut paid
+                // out of it: a tax charged on the input is a buy tax, on the
+                // output a sell tax
+                let (input_rate, output_rate) = match (taxation_mode, swap_direction) {
+                    (TaxationMode::None, _) => (0.0025, 0.0),
+                    (TaxationMode::Base, SwapDirection::Base2Quote)
+                    | (TaxationMode::Quote, SwapDirection::Quote2Base) => (0.0025 + 0.03, 0.0),
+                    (TaxationMode::Base, SwapDirection::Quote2Base)
+                    | (TaxationMode::Quote, SwapDirection::Base2Quote) => (0.0025, 0.05),
+                };
+
+                for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+                    let quote = amm
+                        .quote(&QuoteParams {
+                            amount: 1_000_000,
+                            input_mint,
+                            output_mint,
+                            swap_mode,
+                        })
+                        .unwrap();
+
+                    // Exact out quotes charge their rates on top of the amount
+                    let expected_fee_pct = match swap_mode {
+                        SwapMode::ExactIn => input_rate + output_rate,
+                        SwapMode::ExactOut => {
+                            input_rate / (1.0 + input_rate) + output_rate / (1.0 + output_rate)
+                        }
+                    };
+                    let case = (taxation_mode, swap_direction, swap_mode);
+                    assert_eq!(quote.fee_mint, input_mint, "{:?}", case);
+                    let fee_pct: f64 = quote.fee_pct.try_into().unwrap();
+                    assert!(
+                        (fee_pct - expected_fee_pct).abs() < 0.000_01,
+                        "{:?}: {} != {}",
+                        case,
+                        fee_pct,
+                        expected_fee_pct
+                    );
+                    assert_eq!(
+                        quote.fee_pct,
+                        Decimal::from(quote.fee_amount) / Decimal::from(quote.in_amount)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_quote_result_reports_ui_prices() {
+        let amm = amm(LiquidityPoolState {
+            base_token_mint: Pubkey::new_unique(),
+            base_token_mint_decimals: 6,
+            quote_token_mint: Pubkey::new_unique(),
+            quote_token_mint_decimals: 9,
+            // 1 base token for 0.5 quote token
+            base_token_vault_balance: 1_000_000_000_000,
+            quote_token_vault_balance: 500_000_000_000_000,
+            swap_fee_numerator: 25,
+            swap_fee_denominator: 10_000,
+            protocol_swap_fee_numerator: 0,
+            protocol_swap_fee_denominator: 1,
+            allow_swap: true,
+            ..Default::default()
+        });
+
+        let result = amm
+            .quote_result(&QuoteParams {
+                amount: 10_000_000_000_000,
+                input_mint: amm.state.quote_token_mint,
+                output_mint: amm.state.base_token_mint,
+                swap_mode: SwapMode::ExactIn,
+            })
+            .unwrap();
+
+        assert_eq!(result.prices.spot_price_before, 0.5);
+        assert!(result.prices.execution_price > 0.5);
+        assert!(result.prices.spot_price_after > result.prices.execution_price);
+        // 2% of the quote reserve, minus the swap fee
+        assert!((result.prices.price_impact_bps - 195.6).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_adaptive_fee_rises_with_volatility() {
+        let keyed_account = KeyedAccount {
+            key: Pubkey::new_unique(),
+            account: Default::default(),
+            params: Some(serde_json::json!({
+                "fee_mode": {
+                    "adaptive": {
+                        "sensitivity": [1, 10],
+                        "max_swap_fee_numerator": 100,
+                        "window": 8
+                    }
+                }
+            })),
+        };
+        let (fee_mode, window) = fee_mode_from_params(&keyed_account).unwrap();
+        assert_eq!(window, 8);
+
+        let state = LiquidityPoolState {
+            base_token_mint: Pubkey::new_unique(),
+            quote_token_mint: Pubkey::new_unique(),
+            base_token_vault_balance: 1_000_000_000,
+            quote_token_vault_balance: 1_000_000_000,
+            swap_fee_numerator: 25,
+            swap_fee_denominator: 10_000,
+            protocol_swap_fee_numerator: 0,
+            protocol_swap_fee_denominator: 1,
+            allow_swap: true,
+            ..Default::default()
+        };
+        let mut amm = HeavenAmm {
+            fee_mode,
+            volatility: VolatilityTracker::new(window),
+            ..amm(state)
+        };
+        let quote_params = QuoteParams {
+            amount: 1_000_000,
+            input_mint: state.quote_token_mint,
+            output_mint: state.base_token_mint,
+            swap_mode: SwapMode::ExactIn,
+        };
+        amm.volatility.record(&state);
+        assert_eq!(
+            amm.quote_result(&quote_params).unwrap().swap_fee.amount,
+            2_500
+        );
+
+        // A swap moved the price by 2%, the fee rises by 20 basis points
+        amm.volatility.record(&LiquidityPoolState {
+            quote_token_vault_balance: 1_020_000_000,
+            swap_in_count: 1,
+            ..state
+        });
+        assert_eq!(
+            amm.quote_result(&quote_params).unwrap().swap_fee.amount,
+            4_500
+        );
+    }
+
+    #[test]
+    fn test_fee_mode_from_params() {
+        let keyed_account = |params| KeyedAccount {
+            key: Pubkey::new_unique(),
+            account: Default::default(),
+            params,
+        };
+
+        let (fee_mode, _) = fee_mode_from_params(&keyed_account(None)).unwrap();
+        assert_eq!(fee_mode, FeeMode::Static);
+        let (fee_mode, _) = fee_mode_from_params(&keyed_account(Some(
+            serde_json::json!({ "fee_mode": { "tiered": [[0, 30], [1000000, 20]] } }),
+        )))
+        .unwrap();
+        assert_eq!(
+            fee_mode,
+            FeeMode::Tiered(
+                FeeTiers::new(&[
+                    FeeTier {
+                        minimum_amount_in: 0,
+                        swap_fee_numerator: 30
+                    },
+                    FeeTier {
+                        minimum_amount_in: 1_000_000,
+                        swap_fee_numerator: 20
+                    },
+                ])
+                .unwrap()
+            )
+        );
+        assert!(fee_mode_from_params(&keyed_account(Some(
+            serde_json::json!({ "fee_mode": { "tiered": [[1000000, 20], [0, 30]] } }),
+        )))
+        .is_err());
+        assert!(fee_mode_from_params(&keyed_account(Some(
+            serde_json::json!({ "fee_mode": "dynamic" }),
+        )))
+        .is_err());
+    }
+
+    #[test]
+    fn test_quote_resolves_transfer_fee_at_current_epoch() {
+        let transfer_fee = |epoch: u64, transfer_fee_basis_points: u16| TransferFee {
+            epoch: epoch.into(),
+            maximum_fee: u64::MAX.into(),
+            transfer_fee_basis_points: transfer_fee_basis_points.into(),
+        };
+        let amm = HeavenAmm {
+            base_transfer_fee: EpochTransferFee::new(transfer_fee(0, 0), transfer_fee(5, 100)),
+            ..amm(LiquidityPoolState {
+                base_token_mint: Pubkey::new_unique(),
+                quote_token_mint: Pubkey::new_unique(),
+                base_token_vault_balance: 1_000_000_000,
+                quote_token_vault_balance: 1_000_000_000,
+                swap_fee_numerator: 25,
+                swap_fee_denominator: 10_000,
+                protocol_swap_fee_numerator: 0,
+                protocol_swap_fee_denominator: 1,
+                allow_swap: true,
+                ..Default::default()
+            })
+        };
+        let quote_params = QuoteParams {
+            amount: 1_000_000,
+            input_mint: amm.state.base_token_mint,
+            output_mint: amm.state.quote_token_mint,
+            swap_mode: SwapMode::ExactIn,
+        };
+
+        amm.epoch.store(4, Ordering::Relaxed);
+        let before = amm.quote_result(&quote_params).unwrap();
+        assert_eq!(before.input_transfer_fee.amount, 0);
+
+        // No account update in between, the newer fee applies from its epoch
+        amm.epoch.store(5, Ordering::Relaxed);
+        let after = amm.quote_result(&quote_params).unwrap();
+        assert_eq!(after.input_transfer_fee.amount, 10_000);
+        assert!(after.amount_out < before.amount_out);
+    }
+
+    #[test]
+    fn test_quote_charges_decayed_tax_at_cluster_time() {
+        let state = LiquidityPoolState {
+            base_token_mint: Pubkey::new_unique(),
+            quote_token_mint: Pubkey::new_unique(),
+            base_token_vault_balance: 1_000_000_000,
+            quote_token_vault_balance: 1_000_000_000,
+            swap_fee_numerator: 25,
+            swap_fee_denominator: 10_000,
+            protocol_swap_fee_numerator: 0,
+            protocol_swap_fee_denominator: 1,
+            taxation_mode: TaxationMode::Quote,
+            buy_tax: 100,
+            sell_tax: 500,
+            open_at: 1_000,
+            allow_swap: true,
+            ..Default::default()
+        };
+        let keyed_account = KeyedAccount {
+            key: Pubkey::new_unique(),
+            account: Default::default(),
+            params: Some(serde_json::json!({
+                "tax_decay": { "buy": { "start": 5000, "floor": 100, "linear": 100 } }
+            })),
+        };
+        let tax_decay = tax_decay_from_params(&keyed_account, &state).unwrap();
+        assert_eq!(
+            tax_decay,
+            Some(TaxDecaySchedule {
+                open_at: 1_000,
+                buy_tax: DecayingTax::new(5_000, 100, TaxDecay::Linear { duration: 100 }).unwrap(),
+                sell_tax: DecayingTax::flat(500),
+            })
+        );
+        let amm = HeavenAmm {
+            tax_decay,
+            ..amm(state)
+        };
+        let quote_params = QuoteParams {
+            amount: 1_000_000,
+            input_mint: state.quote_token_mint,
+            output_mint: state.base_token_mint,
+            swap_mode: SwapMode::ExactIn,
+        };
+
+        // 50% at open, halfway down to the 1% floor 50 seconds later
+        amm.unix_timestamp.store(1_000, Ordering::Relaxed);
+        assert_eq!(
+            amm.quote_result(&quote_params).unwrap().swap_tax.amount,
+            500_000
+        );
+        amm.unix_timestamp.store(1_050, Ordering::Relaxed);
+        assert_eq!(
+            amm.quote_result(&quote_params).unwrap().swap_tax.amount,
+            255_000
+        );
+        amm.unix_timestamp.store(2_000, Ordering::Relaxed);
+        assert_eq!(
+            amm.quote_result(&quote_params).unwrap().swap_tax.amount,
+            10_000
+        );
+    }
+
+    #[test]
+    fn test_tax_decay_from_params() {
+        let keyed_account = |params| KeyedAccount {
+            key: Pubkey::new_unique(),
+            account: Default::default(),
+            params,
+        };
+        let state = LiquidityPoolState {
+            buy_tax: 300,
+            sell_tax: 500,
+            open_at: 1_000,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            tax_decay_from_params(&keyed_account(None), &state).unwrap(),
+            None
+        );
+        assert_eq!(
+            tax_decay_from_params(
+                &keyed_account(Some(serde_json::json!({
+                    "tax_decay": { "sell": { "start": 2000, "floor": 500, "stepwise": [60, 3] } }
+                }))),
+                &state
+            )
+            .unwrap(),
+            Some(TaxDecaySchedule {
+                open_at: 1_000,
+                buy_tax: DecayingTax::flat(300),
+                sell_tax: DecayingTax::new(
+                    2_000,
+                    500,
+                    TaxDecay::Stepwise {
+                        step_duration: 60,
+                        steps: 3
+                    }
+                )
+                .unwrap(),
+            })
+        );
+        // Zero durations and floors above the start are rejected up front
+        assert!(tax_decay_from_params(
+            &keyed_account(Some(serde_json::json!({
+                "tax_decay": { "buy": { "start": 5000, "floor": 100, "linear": 0 } }
+            }))),
+            &state
+        )
+        .is_err());
+        assert!(tax_decay_from_params(
+            &keyed_account(Some(serde_json::json!({
+                "tax_decay": { "buy": { "start": 100, "floor": 5000 } }
+            }))),
+            &state
+        )
+        .is_err());
+    }
+
+    /// A pool at the addresses the program derives, with its account
+    fn genuine_pool() -> (KeyedAccount, LiquidityPoolState) {
+        let creator = Pubkey::new_unique();
+        let base_token_mint = Pubkey::new_unique();
+        let quote_token_mint = Pubkey::new_unique();
+        let (key, _) = derive_liquidity_pool_state(&creator, &base_token_mint, &quote_token_mint);
+        let state = LiquidityPoolState {
+            creator,
+            base_token_mint,
+            quote_token_mint,
+            base_token_vault: derive_token_vault(&key, &base_token_mint).0,
+            quote_token_vault: derive_token_vault(&key, &quote_token_mint).0,
+            base_token_swap_tax_vault: derive_swap_tax_vault(&key, &base_token_mint).0,
+            quote_token_swap_tax_vault: derive_swap_tax_vault(&key, &quote_token_mint).0,
+            lp_token_mint: derive_lp_token_mint(&key).0,
+            authority_bump: derive_authority().1,
+            ..Default::default()
+        };
+        (pool_account(key, &state), state)
+    }
+
+    fn pool_account(key: Pubkey, state: &LiquidityPoolState) -> KeyedAccount {
+        let mut keyed_account = KeyedAccount {
+            key,
+            account: Default::default(),
+            params: None,
+        };
+        keyed_account.account.owner = PROGRAM_ID;
+        keyed_account.account.data = encode_liquidity_pool_state(
+            state,
+            PoolStateLayout::WithPriceStats,
+            &PoolPriceStats::default(),
+        )
+        .unwrap();
+        keyed_account
+    }
+
+    fn verification_error(error: anyhow::Error) -> PoolVerificationError {
+        error.downcast::<PoolVerificationError>().unwrap()
+    }
+
+    #[test]
+    fn test_authority_is_derived() {
+        assert_eq!(derive_authority().0, AUTHORITY);
+    }
+
+    #[test]
+    fn test_verify_liquidity_pool() {
+        let (pool, state) = genuine_pool();
+        verify_liquidity_pool(&pool.key, &PROGRAM_ID, &state).unwrap();
+
+        let error = verify_liquidity_pool(&pool.key, &Pubkey::new_unique(), &state).unwrap_err();
+        assert!(matches!(
+            verification_error(error),
+            PoolVerificationError::WrongOwner { .. }
+        ));
+
+        // Same creator and mints at another address
+        let spoofed = Pubkey::new_unique();
+        let error = verify_liquidity_pool(&spoofed, &PROGRAM_ID, &state).unwrap_err();
+        assert_eq!(
+            verification_error(error),
+            PoolVerificationError::AddressMismatch {
+                account: "state",
+                expected: pool.key,
+                found: spoofed,
+            }
+        );
+
+        let fake_vault = LiquidityPoolState {
+            quote_token_vault: Pubkey::new_unique(),
+            ..state
+        };
+        let error = verify_liquidity_pool(&pool.key, &PROGRAM_ID, &fake_vault).unwrap_err();
+        assert!(matches!(
+            verification_error(error),
+            PoolVerificationError::AddressMismatch {
+                account: "quote token vault",
+                ..
+            }
+        ));
+
+        let fake_lp_mint = LiquidityPoolState {
+            lp_token_mint: Pubkey::new_unique(),
+            ..state
+        };
+        let error = verify_liquidity_pool(&pool.key, &PROGRAM_ID, &fake_lp_mint).unwrap_err();
+        assert!(matches!(
+            verification_error(error),
+            PoolVerificationError::AddressMismatch {
+                account: "LP token mint",
+                ..
+            }
+        ));
+
+        let wrong_bump = LiquidityPoolState {
+            authority_bump: state.authority_bump.wrapping_sub(1),
+            ..state
+        };
+        let error = verify_liquidity_pool(&pool.key, &PROGRAM_ID, &wrong_bump).unwrap_err();
+        assert!(matches!(
+            verification_error(error),
+            PoolVerificationError::AuthorityBumpMismatch { .. }
+        ));
+    }
+
+    #[test]
+    fn test_reject_spoofed_pools() {
+        let amm_context = AmmContext {
+            clock_ref: ClockRef::default(),
+        };
+        let (pool, state) = genuine_pool();
+        let mut amm = HeavenAmm::from_keyed_account(&pool, &amm_context).unwrap();
+
+        let spoofed = pool_account(Pubkey::new_unique(), &state);
+        assert!(HeavenAmm::from_keyed_account(&spoofed, &amm_context).is_err());
+
+        // An update cannot swap the vaults of a verified pool
+        let fake_vault = pool_account(
+            pool.key,
+            &LiquidityPoolState {
+                base_token_vault: Pubkey::new_unique(),
+                ..state
+            },
+        );
+        let account_map = AccountMap::from([(pool.key, fake_vault.account)]);
+        let error = amm.update(&account_map).unwrap_err();
+        assert_eq!(
+            verification_error(error),
+            PoolVerificationError::PoolChanged {
+                account: "base token vault"
+            }
+        );
+    }
+
+    #[test]
+    fn test_inactive_pools_do_not_quote() {
+        let state = LiquidityPoolState {
+            base_token_mint: Pubkey::new_unique(),
+            quote_token_mint: Pubkey::new_unique(),
+            base_token_vault_balance: 1_000_000_000,
+            quote_token_vault_balance: 1_000_000_000,
+            swap_fee_numerator: 25,
+            swap_fee_denominator: 10_000,
+            protocol_swap_fee_numerator: 0,
+            protocol_swap_fee_denominator: 1,
+            allow_swap: true,
+            open_at: 1_000,
+            ..Default::default()
+        };
+        let quote_params = QuoteParams {
+            amount: 1_000_000,
+            input_mint: state.quote_token_mint,
+            output_mint: state.base_token_mint,
+            swap_mode: SwapMode::ExactIn,
+        };
+        let inactive = |amm: &HeavenAmm| {
+            assert!(!amm.is_active());
+            let error = amm.quote(&quote_params).unwrap_err();
+            assert!(!is_insufficient_liquidity(&error));
+            error.downcast::<PoolInactive>().unwrap()
+        };
+
+        let amm = amm(state);
+        amm.unix_timestamp.store(999, Ordering::Relaxed);
+        assert_eq!(inactive(&amm), PoolInactive::NotOpenYet { open_at: 1_000 });
+        amm.unix_timestamp.store(1_000, Ordering::Relaxed);
+        assert!(amm.is_active());
+        amm.quote(&quote_params).unwrap();
+
+        let swap_disabled = self::amm(LiquidityPoolState {
+            allow_swap: false,
+            ..state
+        });
+        swap_disabled.unix_timestamp.store(1_000, Ordering::Relaxed);
+        assert_eq!(inactive(&swap_disabled), PoolInactive::SwapDisabled);
+
+        let empty_vault = self::amm(LiquidityPoolState {
+            base_token_vault_balance: 0,
+            ..state
+        });
+        empty_vault.unix_timestamp.store(1_000, Ordering::Relaxed);
+        assert_eq!(inactive(&empty_vault), PoolInactive::EmptyVault);
+    }
+
+    #[test]
+    fn test_accounts_len_matches_swap_accounts() {
+        let amm = amm(LiquidityPoolState::default());
+        let swap_params = SwapParams {
+            in_amount: 1_000_000,
+            out_amount: 0,
+            source_mint: amm.state.base_token_mint,
+            destination_mint: amm.state.quote_token_mint,
+            source_token_account: Pubkey::new_unique(),
+            destination_token_account: Pubkey::new_unique(),
+            token_transfer_authority: Pubkey::new_unique(),
+            open_order_address: None,
+            quote_mint_to_referrer: None,
+            jupiter_program_id: &Pubkey::new_unique(),
+            missing_dynamic_accounts_as_default: false,
+        };
+
+        let swap = amm.get_swap_and_account_metas(&swap_params).unwrap();
+        assert_eq!(swap.account_metas.len(), amm.get_accounts_len());
+        assert!(amm.supports_exact_out());
+    }
+}
@@ -1,4 +1,5 @@
 pub mod amm;
+pub mod transaction;
 
 #[cfg(test)]
 pub mod test_harness;
@@ -0,0 +1,397 @@
+//! Instructions of a complete user swap on a pool, in execution order:
+//! compute budget, the user's token accounts, SOL wrapping, the swap itself
+//! and SOL unwrapping.
+
+use anchor_lang::{
+    prelude::Pubkey,
+    solana_program::{instruction::Instruction, system_instruction},
+};
+use anchor_spl::{
+    associated_token::{
+        get_associated_token_address_with_program_id,
+        spl_associated_token_account::instruction::create_associated_token_account_idempotent,
+    },
+    token::spl_token,
+    token_2022::spl_token_2022,
+};
+use anyhow::Result;
+use heaven_exchange::{
+    calculator::swap_direction::SwapDirection,
+    instructions::{SwapInParams, SwapOutParams},
+    quote::QuoteResult,
+    swap_instruction::{token_program_or_default, SwapInstructionBuilder},
+};
+use jupiter_amm_interface::{QuoteParams, SwapMode};
+use solana_sdk::compute_budget::ComputeBudgetInstruction;
+
+use crate::amm::HeavenAmm;
+
+/// Compute units of a swap, with room for Token-2022 transfer fees and the
+/// user's token account creation
+pub const DEFAULT_COMPUTE_UNIT_LIMIT: u32 = 300_000;
+/// Slippage of a swap in basis points, unless configured
+pub const DEFAULT_SLIPPAGE_BPS: u64 = 50;
+
+/// How to assemble the swap of `user`
+#[derive(Clone, Debug)]
+pub struct SwapTransactionConfig {
+    /// Signer and fee payer, owner of the token accounts
+    pub user: Pubkey,
+    /// Slippage bounding `minimum_amount_out` or `max_amount_in`, in basis
+    /// points
+    pub slippage_bps: u64,
+    pub compute_unit_limit: u32,
+    /// Priority fee in micro-lamports per compute unit, none if zero
+    pub compute_unit_price: u64,
+    /// Swap native SOL, wrapping it in and unwrapping it out of the user's
+    /// WSOL account. The account is closed afterwards, unwrapping any WSOL
+    /// it held before the swap too.
+    pub wrap_and_unwrap_sol: bool,
+    pub encoded_user_defined_event_data: String,
+}
+
+impl SwapTransactionConfig {
+    pub fn new(user: Pubkey) -> Self {
+        Self {
+            user,
+            slippage_bps: DEFAULT_SLIPPAGE_BPS,
+            compute_unit_limit: DEFAULT_COMPUTE_UNIT_LIMIT,
+            compute_unit_price: 0,
+            wrap_and_unwrap_sol: true,
+            encoded_user_defined_event_data: String::new(),
+        }
+    }
+}
+
+/// A quote and the instructions executing it
+#[derive(Clone, Debug)]
+pub struct SwapTransaction {
+    pub quote: QuoteResult,
+    pub instructions: Vec<Instruction>,
+}
+
+fn is_native_mint(mint: &Pubkey) -> bool {
+    *mint == spl_token::native_mint::ID || *mint == spl_token_2022::native_mint::ID
+}
+
+/// Quotes `quote_params` on `amm` and assembles the instructions swapping it
+/// for `config.user`:
+/// 1. compute unit limit, then price if any
+/// 2. idempotent creation of the user's base and quote token accounts, under
+///    the token program of each mint
+/// 3. for a native SOL input, a transfer of the maximum amount in to the WSOL
+///    account and its sync
+/// 4. `swap_in` for an exact input, `swap_out` for an exact output
+/// 5. for a native SOL input or output, the closing of the WSOL account
+pub fn swap_transaction(
+    amm: &HeavenAmm,
+    quote_params: &QuoteParams,
+    config: &SwapTransactionConfig,
+) -> Result<SwapTransaction> {
+    let state = &amm.state;
+    let swap_direction = SwapDirection::parse(
+        &quote_params.input_mint,
+        &quote_params.output_mint,
+        &state.base_token_mint,
+        &state.quote_token_mint,
+    )?;
+    let quote = amm.quote_result_with_slippage(quote_params, config.slippage_bps)?;
+
+    let user = &config.user;
+    let base_token_program = token_program_or_default(state.base_token_program);
+    let quote_token_program = token_program_or_default(state.quote_token_program);
+    let user_base_token_vault = get_associated_token_address_with_program_id(
+        user,
+        &state.base_token_mint,
+        &base_token_program,
+    );
+    let user_quote_token_vault = get_associated_token_address_with_program_id(
+        user,
+        &state.quote_token_mint,
+        &quote_token_program,
+    );
+    let (input_vault, input_token_program) = match swap_direction {
+        SwapDirection::Base2Quote => (user_base_token_vault, base_token_program),
+        SwapDirection::Quote2Base => (user_quote_token_vault, quote_token_program),
+    };
+    let (output_vault, output_token_program) = match swap_direction {
+        SwapDirection::Base2Quote => (user_quote_token_vault, quote_token_program),
+        SwapDirection::Quote2Base => (user_base_token_vault, base_token_program),
+    };
+
+    let mut instructions = vec![ComputeBudgetInstruction::set_compute_unit_limit(
+        config.compute_unit_limit,
+    )];
+    if config.compute_unit_price > 0 {
+        instructions.push(ComputeBudgetInstruction::set_compute_unit_price(
+            config.compute_unit_price,
+        ));
+    }
+
+    instructions.push(create_associated_token_account_idempotent(
+        user,
+        user,
+        &state.base_token_mint,
+        &base_token_program,
+    ));
+    instructions.push(create_associated_token_account_idempotent(
+        user,
+        user,
+        &state.quote_token_mint,
+        &quote_token_program,
+    ));
+
+    let wrap_sol = config.wrap_and_unwrap_sol && is_native_mint(&quote.input_mint);
+    if wrap_sol {
+        instructions.push(system_instruction::transfer(
+            user,
+            &input_vault,
+            quote.maximum_amount_in,
+        ));
+        instructions.push(spl_token_2022::instruction::sync_native(
+            &input_token_program,
+            &input_vault,
+        )?);
+    }
+
+    let builder = SwapInstructionBuilder::new(
+        amm.key,
+        state,
+        *user,
+        user_base_token_vault,
+        user_quote_token_vault,
+    );
+    instructions.push(match quote_params.swap_mode {
+        SwapMode::ExactIn => builder.swap_in(SwapInParams {
+            amount_in: quote.amount_in,
+            minimum_amount_out: quote.minimum_amount_out,
+            swap_direction,
+            encoded_user_defined_event_data: config.encoded_user_defined_event_data.clone(),
+        }),
+        SwapMode::ExactOut => builder.swap_out(SwapOutParams {
+            max_amount_in: quote.maximum_amount_in,
+            amount_out: quote.amount_out,
+            swap_direction,
+            encoded_user_defined_event_data: config.encoded_user_defined_event_data.clone(),
+        }),
+    });
+
+    // Unspent wrapped SOL of an exact output swap is unwrapped with it
+    let unwrap_sol = if wrap_sol {
+        Some((input_vault, input_token_program))
+    } else if config.wrap_and_unwrap_sol && is_native_mint(&quote.output_mint) {
+        Some((output_vault, output_token_program))
+    } else {
+        None
+    };
+    if let Some((wsol_vault, token_program)) = unwrap_sol {
+        instructions.push(spl_token_2022::instruction::close_account(
+            &token_program,
+            &wsol_vault,
+            user,
+            user,
+            &[],
+        )?);
+    }
+
+    Ok(SwapTransaction {
+        quote,
+        instructions,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::amm::{AUTHORITY, DEFAULT_VOLATILITY_WINDOW, SWAP_ACCOUNTS_LEN};
+    use anchor_lang::{
+        prelude::borsh::BorshDeserialize, solana_program::system_program, Discriminator, Id,
+    };
+    use anchor_spl::{associated_token::AssociatedToken, token::Token, token_2022::Token2022};
+    use heaven_exchange::{
+        calculator::{
+            fee_mode::{FeeMode, VolatilityTracker},
+            swap_context::EpochTransferFee,
+        },
+        instructions::LiquidityPoolState,
+    };
+    use std::sync::{
+        atomic::{AtomicI64, AtomicU64},
+        Arc,
+    };
+
+    /// A pool of a Token-2022 base token against WSOL
+    fn amm() -> HeavenAmm {
+        HeavenAmm {
+            key: Pubkey::new_unique(),
+            authority: AUTHORITY,
+            state: LiquidityPoolState {
+                base_token_mint: Pubkey::new_unique(),
+                quote_token_mint: spl_token::native_mint::ID,
+                base_token_vault_balance: 1_000_000_000,
+                quote_token_vault_balance: 1_000_000_000,
+                swap_fee_numerator: 25,
+                swap_fee_denominator: 10_000,
+                protocol_swap_fee_numerator: 0,
+                protocol_swap_fee_denominator: 1,
+                allow_swap: true,
+                base_token_program: Token2022::id(),
+                ..Default::default()
+            },
+            base_transfer_fee: EpochTransferFee::default(),
+            quote_transfer_fee: EpochTransferFee::default(),
+            epoch: Arc::new(AtomicU64::new(0)),
+            unix_timestamp: Arc::new(AtomicI64::new(0)),
+            shared_pools: vec![],
+            shared_constant_product: None,
+            fee_mode: FeeMode::Static,
+            volatility: VolatilityTracker::new(DEFAULT_VOLATILITY_WINDOW),
+            tax_decay: None,
+        }
+    }
+
+    fn program_ids(transaction: &SwapTransaction) -> Vec<Pubkey> {
+        transaction
+            .instructions
+            .iter()
+            .map(|instruction| instruction.program_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_buy_with_native_sol() {
+        let amm = amm();
+        let user = Pubkey::new_unique();
+        let config = SwapTransactionConfig {
+            compute_unit_price: 1_000,
+            ..SwapTransactionConfig::new(user)
+        };
+        let quote_params = QuoteParams {
+            amount: 1_000_000,
+            input_mint: spl_token::native_mint::ID,
+            output_mint: amm.state.base_token_mint,
+            swap_mode: SwapMode::ExactIn,
+        };
+
+        let transaction = swap_transaction(&amm, &quote_params, &config).unwrap();
+
+        let compute_budget = solana_sdk::compute_budget::ID;
+        assert_eq!(
+            program_ids(&transaction),
+            [
+                compute_budget,
+                compute_budget,
+                AssociatedToken::id(),
+                AssociatedToken::id(),
+                system_program::ID,
+                Token::id(),
+                heaven_exchange::ID,
+                Token::id(),
+            ]
+        );
+        let instructions = &transaction.instructions;
+        let base_vault = get_associated_token_address_with_program_id(
+            &user,
+            &amm.state.base_token_mint,
+            &Token2022::id(),
+        );
+        let wsol_vault = get_associated_token_address_with_program_id(
+            &user,
+            &spl_token::native_mint::ID,
+            &Token::id(),
+        );
+        // Each account under the token program of its mint
+        assert_eq!(instructions[2].accounts[1].pubkey, base_vault);
+        assert_eq!(instructions[2].accounts[5].pubkey, Token2022::id());
+        assert_eq!(instructions[3].accounts[1].pubkey, wsol_vault);
+        assert_eq!(instructions[3].accounts[5].pubkey, Token::id());
+        // The whole input is wrapped
+        assert_eq!(instructions[4].accounts[1].pubkey, wsol_vault);
+        assert_eq!(instructions[5].accounts[0].pubkey, wsol_vault);
+
+        let swap = &instructions[6];
+        assert_eq!(swap.accounts.len(), SWAP_ACCOUNTS_LEN);
+        assert_eq!(swap.accounts[7].pubkey, user);
+        assert!(swap.accounts[7].is_signer);
+        assert_eq!(swap.accounts[10].pubkey, base_vault);
+        assert_eq!(swap.accounts[11].pubkey, wsol_vault);
+        let (discriminator, params) = swap.data.split_at(8);
+        assert_eq!(
+            discriminator,
+            heaven_exchange::instruction::SwapIn::DISCRIMINATOR
+        );
+        let params = SwapInParams::try_from_slice(params).unwrap();
+        assert_eq!(params.amount_in, 1_000_000);
+        assert_eq!(params.swap_direction, SwapDirection::Quote2Base);
+        assert_eq!(
+            params.minimum_amount_out,
+            transaction.quote.minimum_amount_out
+        );
+        assert!(params.minimum_amount_out < transaction.quote.amount_out);
+
+        // The WSOL account is closed back to the user
+        assert_eq!(instructions[7].accounts[0].pubkey, wsol_vault);
+        assert_eq!(instructions[7].accounts[1].pubkey, user);
+    }
+
+    #[test]
+    fn test_sell_for_exact_native_sol() {
+        let amm = amm();
+        let user = Pubkey::new_unique();
+        let quote_params = QuoteParams {
+            amount: 1_000_000,
+            input_mint: amm.state.base_token_mint,
+            output_mint: spl_token::native_mint::ID,
+            swap_mode: SwapMode::ExactOut,
+        };
+
+        let transaction =
+            swap_transaction(&amm, &quote_params, &SwapTransactionConfig::new(user)).unwrap();
+
+        // Nothing to wrap, the output is unwrapped
+        assert_eq!(
+            program_ids(&transaction),
+            [
+                solana_sdk::compute_budget::ID,
+                AssociatedToken::id(),
+                AssociatedToken::id(),
+                heaven_exchange::ID,
+                Token::id(),
+            ]
+        );
+        let swap = &transaction.instructions[3];
+        let (discriminator, params) = swap.data.split_at(8);
+        assert_eq!(
+            discriminator,
+            heaven_exchange::instruction::SwapOut::DISCRIMINATOR
+        );
+        let params = SwapOutParams::try_from_slice(params).unwrap();
+        assert_eq!(params.amount_out, 1_000_000);
+        assert_eq!(params.swap_direction, SwapDirection::Base2Quote);
+        assert!(params.max_amount_in > transaction.quote.amount_in);
+
+        let without_wrapping = SwapTransactionConfig {
+            wrap_and_unwrap_sol: false,
+            ..SwapTransactionConfig::new(user)
+        };
+        let transaction = swap_transaction(&amm, &quote_params, &without_wrapping).unwrap();
+        assert_eq!(program_ids(&transaction).last(), Some(&heaven_exchange::ID));
+    }
+
+    #[test]
+    fn test_reject_mints_outside_the_pool() {
+        let amm = amm();
+        let quote_params = QuoteParams {
+            amount: 1_000_000,
+            input_mint: Pubkey::new_unique(),
+            output_mint: amm.state.base_token_mint,
+            swap_mode: SwapMode::ExactIn,
+        };
+        assert!(swap_transaction(
+            &amm,
+            &quote_params,
+            &SwapTransactionConfig::new(Pubkey::new_unique())
+        )
+        .is_err());
+    }
+}